@@ -0,0 +1,87 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::Duration,
+};
+use failure::format_err;
+
+use crate::prelude::*;
+
+/// Default size after which the access log is rotated: 10 MB.
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// A single request, written as one line of JSON.
+#[derive(Serialize, Debug)]
+pub struct Entry<'a> {
+    pub time: DateTime,
+    pub request_id: Uuid,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub duration_ms: u64,
+}
+
+impl<'a> Entry<'a> {
+    pub fn new(method: &'a str, path: &'a str, status: u16, duration: Duration) -> Self {
+        Entry {
+            time: now(),
+            request_id: uuid(),
+            method,
+            path,
+            status,
+            duration_ms: duration.as_secs() * 1000 + u64::from(duration.subsec_millis()),
+        }
+    }
+}
+
+struct State {
+    file: File,
+    size: u64,
+}
+
+/// Append-only access log.
+///
+/// Once the file grows beyond `max_size` it is moved to `{path}.1`, replacing
+/// an older rotated log, and a new file is started.
+pub struct AccessLog {
+    path: String,
+    max_size: u64,
+    state: Mutex<State>,
+}
+
+impl AccessLog {
+    pub fn open(path: &str, max_size: u64) -> Result<Self, Error> {
+        let file = Self::open_file(path)?;
+        let size = file.metadata()?.len();
+        Ok(AccessLog {
+            path: path.to_string(),
+            max_size,
+            state: Mutex::new(State { file, size }),
+        })
+    }
+
+    fn open_file(path: &str) -> Result<File, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(file)
+    }
+
+    pub fn write(&self, entry: &Entry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut state = self.state.lock().map_err(|_| format_err!("access log poisoned"))?;
+        if state.size > 0 && state.size + line.len() as u64 > self.max_size {
+            state.file.flush()?;
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            state.file = Self::open_file(&self.path)?;
+            state.size = 0;
+        }
+        state.file.write_all(&line)?;
+        state.size += line.len() as u64;
+        Ok(())
+    }
+}
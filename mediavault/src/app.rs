@@ -1,18 +1,222 @@
 use serde_derive::{Serialize, Deserialize};
+use failure::format_err;
 use mediavault_common::types::{
     self as t,
     File,
     FileMeta,
     FileFilter,
     FileQuery,
-
+    FileSort,
+    FileSortItem,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Seek},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    thread,
 };
-use crate::{db, prelude::*, storage};
+use crate::{access_log, db, fetcher, importer, prelude::*, storage, thumbs, watcher};
 
-#[derive(Clone, Debug)]
+/// Server configuration, usually loaded with `Config::load`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct Config {
     pub db_path: String,
-    pub storage_path: String,
+    /// Media directories. See `StorageRoot` for how paths map to roots.
+    pub storage_roots: Vec<storage::StorageRoot>,
+    /// Bearer token required for protected endpoints.
+    /// If unset, every request is treated as authenticated, so the server
+    /// may only listen on a loopback address, see `Config::validate`.
+    pub auth_token: Option<String>,
+    /// Maximum number of files read in parallel while indexing.
+    /// Defaults to the number of CPUs.
+    pub index_concurrency: Option<usize>,
+    /// Number of paths and loaded files buffered between the index threads.
+    /// Defaults to `index_concurrency`.
+    pub index_queue_size: Option<usize>,
+    /// Query for the landing page of the frontend.
+    /// Defaults to the newest files.
+    pub home_query: Option<FileQuery>,
+    /// Sort direction per field for queries that don't specify one,
+    /// `true` meaning ascending. Missing fields use `FileSort::default_ascending`.
+    pub sort_ascending: HashMap<FileSort, bool>,
+    /// File to write a JSON access log to, one line per request.
+    pub access_log_path: Option<String>,
+    /// Size in bytes after which the access log is rotated.
+    /// Defaults to `access_log::DEFAULT_MAX_SIZE`.
+    pub access_log_max_size: Option<u64>,
+    /// Deepest accepted nesting of query filters.
+    /// Defaults to `DEFAULT_MAX_FILTER_DEPTH`.
+    pub max_filter_depth: Option<usize>,
+    /// Number of metadata backups kept per file when metadata is
+    /// overwritten. Disabled by default.
+    pub meta_backups: Option<usize>,
+    /// Serve media through symlinks that point outside of their storage
+    /// root. Disabled by default.
+    pub follow_symlinks: bool,
+    /// Respond with 404 to unknown paths instead of serving the frontend.
+    pub disable_spa_fallback: bool,
+    /// Read size for hashing files during indexing.
+    /// Defaults to `storage::DEFAULT_HASH_BUFFER_SIZE`.
+    pub hash_buffer_size: Option<usize>,
+    /// Digest for content hashes. Defaults to md5.
+    pub hash_algo: storage::HashAlgo,
+    /// `ffprobe` binary for reading video and audio durations.
+    /// Defaults to `ffprobe` from the `PATH`. Without it, durations stay unset.
+    pub ffprobe_binary: Option<String>,
+    /// `ffmpeg` binary for video thumbnails.
+    /// Defaults to `ffmpeg` from the `PATH`.
+    pub ffmpeg_binary: Option<String>,
+    /// Seconds an importer script may run before it is killed.
+    /// Importers are trusted code, see `importer::NodeEngine`.
+    pub importer_timeout: Option<u64>,
+    /// Directory for cached thumbnails.
+    /// Defaults to `.thumbs` next to the database.
+    pub thumbs_path: Option<String>,
+    /// Largest thumbnail bound in pixels that may be requested.
+    /// Defaults to `thumbs::DEFAULT_MAX_SIZE`.
+    pub thumb_max_size: Option<u32>,
+    /// Watch the storage roots and index changes as they happen.
+    pub watch: bool,
+    /// Move deleted files to the trash directory of their root instead of
+    /// removing them. Enabled by default.
+    pub trash: bool,
+    /// Days a file stays in the trash before `trash_purge` removes it.
+    /// Without it, trashed files are kept until they are restored.
+    pub trash_retention_days: Option<u32>,
+    /// Address the server listens on.
+    pub bind_address: IpAddr,
+    pub port: u16,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            db_path: "db.sqlite3".into(),
+            storage_roots: vec![storage::StorageRoot {
+                id: String::new(),
+                path: "data".into(),
+            }],
+            auth_token: None,
+            index_concurrency: None,
+            index_queue_size: None,
+            home_query: None,
+            sort_ascending: Default::default(),
+            access_log_path: None,
+            access_log_max_size: None,
+            max_filter_depth: None,
+            meta_backups: None,
+            follow_symlinks: false,
+            disable_spa_fallback: false,
+            hash_buffer_size: None,
+            hash_algo: Default::default(),
+            ffprobe_binary: None,
+            ffmpeg_binary: None,
+            importer_timeout: None,
+            thumbs_path: None,
+            thumb_max_size: None,
+            watch: false,
+            trash: true,
+            trash_retention_days: None,
+            bind_address: IpAddr::from([127, 0, 0, 1]),
+            port: 8080,
+        }
+    }
+}
+
+impl Config {
+    /// Read by `load` if no path is given and the file exists.
+    pub const DEFAULT_PATH: &'static str = "mediavault.toml";
+
+    /// Load the config from a TOML file and apply overrides from the
+    /// environment. Settings missing from the file keep their defaults.
+    ///
+    /// Recognized environment variables:
+    /// * `MEDIAVAULT_DB_PATH`
+    /// * `MEDIAVAULT_STORAGE_PATH`: directory of the root without an id
+    /// * `MEDIAVAULT_AUTH_TOKEN`
+    /// * `MEDIAVAULT_ACCESS_LOG`
+    /// * `MEDIAVAULT_BIND_ADDRESS`
+    /// * `MEDIAVAULT_PORT`
+    pub fn load(path: Option<&str>) -> Result<Self, Error> {
+        let mut config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(Self::DEFAULT_PATH).exists() => Self::from_file(Self::DEFAULT_PATH)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Check settings that are valid on their own but not together.
+    ///
+    /// Without an auth token, anyone who can reach the server may see
+    /// private files and use the maintenance endpoints, so it refuses to
+    /// listen on other than loopback addresses.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.auth_token.is_none() && !self.bind_address.is_loopback() {
+            return Err(format_err!(
+                "an auth token is required to listen on {}, set auth_token or MEDIAVAULT_AUTH_TOKEN",
+                self.bind_address,
+            ));
+        }
+        Ok(())
+    }
+
+    fn from_file(path: &str) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| format_err!("invalid config file {}: {}", path, e))
+    }
+
+    fn apply_env(&mut self) -> Result<(), Error> {
+        let var = |name: &str| std::env::var(name).ok();
+
+        if let Some(db_path) = var("MEDIAVAULT_DB_PATH") {
+            self.db_path = db_path;
+        }
+        if let Some(path) = var("MEDIAVAULT_STORAGE_PATH") {
+            match self.storage_roots.iter_mut().find(|root| root.id.is_empty()) {
+                Some(root) => root.path = path,
+                None => self.storage_roots.push(storage::StorageRoot {
+                    id: String::new(),
+                    path,
+                }),
+            }
+        }
+        if let Some(token) = var("MEDIAVAULT_AUTH_TOKEN") {
+            self.auth_token = Some(token);
+        }
+        if let Some(access_log_path) = var("MEDIAVAULT_ACCESS_LOG") {
+            self.access_log_path = Some(access_log_path);
+        }
+        if let Some(address) = var("MEDIAVAULT_BIND_ADDRESS") {
+            self.bind_address = address
+                .parse()
+                .map_err(|_| format_err!("invalid MEDIAVAULT_BIND_ADDRESS: {}", address))?;
+        }
+        if let Some(port) = var("MEDIAVAULT_PORT") {
+            self.port = port
+                .parse()
+                .map_err(|_| format_err!("invalid MEDIAVAULT_PORT: {}", port))?;
+        }
+        Ok(())
+    }
+}
+
+pub const DEFAULT_MAX_FILTER_DEPTH: usize = 32;
+
+/// Result of an index run.
+#[derive(Serialize, Clone, Debug)]
+pub struct IndexReport {
+    pub run_id: Uuid,
+    /// Hashes of files that were not indexed before.
+    pub added: Vec<String>,
+    /// Paths that were removed from the index because they are gone from the
+    /// storage. Only filled by `reindex`.
+    pub removed: Vec<String>,
 }
 
 #[derive(Clone)]
@@ -20,77 +224,583 @@ pub struct App {
     pub config: Config,
     db: db::Pool,
     storage: storage::Storage,
+    importer_engine: Arc<dyn importer::Engine>,
+    thumbs: Arc<thumbs::Thumbnailer>,
+    access_log: Option<Arc<access_log::AccessLog>>,
 }
 
 impl App {
     pub fn new(config: Config) -> Result<Self, Error> {
+        config.validate()?;
         std::env::set_var("RUST_LOG", "mediavault=trace,warp=debug");
-        env_logger::init();
+        // Fails if a logger is already installed, e.g. by an earlier App.
+        let _ = env_logger::try_init();
 
         let db = db::build_pool(&config.db_path)?;
 
         let con = db.get()?;
         db::Db::new(&con).migrate()?;
 
-        let storage = storage::Storage::new(&config.storage_path)?;
+        let mut storage = storage::Storage::new(&config.storage_roots)?;
+        storage.set_meta_backups(config.meta_backups.unwrap_or(0));
+        storage.set_follow_symlinks(config.follow_symlinks);
+        storage.set_hash_buffer_size(config.hash_buffer_size.unwrap_or(storage::DEFAULT_HASH_BUFFER_SIZE));
+        storage.set_hash_algo(config.hash_algo);
+        if let Some(ref binary) = config.ffprobe_binary {
+            storage.set_ffprobe_binary(binary);
+        }
+
+        let thumbs_path = match config.thumbs_path {
+            Some(ref path) => PathBuf::from(path),
+            None => Path::new(&config.db_path)
+                .parent()
+                .unwrap_or_else(|| Path::new(""))
+                .join(".thumbs"),
+        };
+        let mut thumbs = thumbs::Thumbnailer::new(
+            thumbs_path,
+            config.thumb_max_size.unwrap_or(thumbs::DEFAULT_MAX_SIZE),
+        )?;
+        if let Some(ref binary) = config.ffmpeg_binary {
+            thumbs.set_ffmpeg_binary(binary);
+        }
+
+        let importer_engine = importer::NodeEngine {
+            timeout: config.importer_timeout
+                .map(std::time::Duration::from_secs)
+                .unwrap_or(importer::DEFAULT_TIMEOUT),
+            ..importer::NodeEngine::default()
+        };
+
+        // Opened here so a bad log path fails the startup with an error.
+        let access_log = match config.access_log_path {
+            Some(ref path) => {
+                let max_size = config.access_log_max_size.unwrap_or(access_log::DEFAULT_MAX_SIZE);
+                let log = access_log::AccessLog::open(path, max_size)
+                    .map_err(|e| format_err!("could not open access log {}: {}", path, e))?;
+                Some(Arc::new(log))
+            }
+            None => None,
+        };
 
         let app = App {
             config,
             db,
             storage,
+            importer_engine: Arc::new(importer_engine),
+            thumbs: Arc::new(thumbs),
+            access_log,
         };
+        if app.config.watch {
+            watcher::spawn(app.clone(), app.storage.root_dirs())?;
+        }
         Ok(app)
     }
 
-    pub fn index(&self) -> Result<(), Error> {
+    pub fn access_log(&self) -> Option<Arc<access_log::AccessLog>> {
+        self.access_log.clone()
+    }
+
+    pub fn client_config(&self) -> t::ClientConfig {
+        t::ClientConfig {
+            home_query: self.config.home_query.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Index all files in the storage.
+    ///
+    /// Items are loaded by `index_concurrency` worker threads and persisted
+    /// from the calling thread. Paths and loaded files are handed over
+    /// channels bounded by `index_queue_size`, so memory use doesn't grow with
+    /// the library size.
+    ///
+    /// Files that were not in the database before are marked with the id of
+    /// this run, see `files_last_indexed`.
+    pub fn index(&self) -> Result<IndexReport, Error> {
+        let concurrency = self.config.index_concurrency
+            .unwrap_or_else(num_cpus::get)
+            .max(1);
+        let queue_size = self.config.index_queue_size.unwrap_or(concurrency).max(1);
+
+        let (path_sender, path_receiver) = mpsc::sync_channel::<Result<String, Error>>(queue_size);
+        let path_receiver = Arc::new(Mutex::new(path_receiver));
+        let (item_sender, item_receiver) = mpsc::sync_channel(queue_size);
+
+        let storage = self.storage.clone();
+        thread::spawn(move || {
+            for path in storage.paths(None) {
+                // Fails only if all workers are gone.
+                if path_sender.send(path).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for _ in 0..concurrency {
+            let path_receiver = path_receiver.clone();
+            let item_sender = item_sender.clone();
+            let storage = self.storage.clone();
+
+            thread::spawn(move || loop {
+                let path = match path_receiver.lock().unwrap().recv() {
+                    Ok(path) => path,
+                    Err(_) => break,
+                };
+                let item = path.and_then(|p| {
+                    log::debug!("Indexing {}", p);
+                    storage.item(&p)
+                });
+                if item_sender.send(item).is_err() {
+                    break;
+                }
+            });
+        }
+        // Only the workers may keep the item channel open.
+        drop(item_sender);
+
         let con = self.db.get()?;
         let db = db::Db::new(&con);
 
-        self.storage
-            .items(None)
-            .for_each(|entry| {
-                let entry = entry.unwrap();
-                println!("{:?}", entry);
-                match entry {
-                    storage::StorageItem::File(f) => {
-                        db.file_persist(&f).unwrap();
+        let mut report = IndexReport {
+            run_id: uuid(),
+            added: Vec::new(),
+            removed: Vec::new(),
+        };
+        db.index_run_create(&report.run_id, &now())?;
+
+        // Keep going after errors, but report the first one.
+        let mut result = Ok(());
+        // Galleries reference files, so they are persisted once all files are.
+        let mut galleries = Vec::new();
+        for item in item_receiver {
+            let res = item.and_then(|item| match item {
+                storage::StorageItem::File(f) => {
+                    let is_new = !db.file_exists(&f.info.hash)?;
+                    db.file_persist(&f)?;
+                    db.file_subtitles_persist(&f.info.hash, &f.info.subtitles)?;
+                    if is_new {
+                        db.file_index_run_set(&f.info.hash, &report.run_id)?;
+                        report.added.push(f.info.hash.clone());
                     }
-                    _ => {}
+                    Ok(())
                 }
+                storage::StorageItem::Gallery(g) => {
+                    galleries.push(g);
+                    Ok(())
+                }
+                _ => Ok(()),
+            });
+            if let (Err(e), true) = (res, result.is_ok()) {
+                result = Err(e);
             }
-        );
+        }
+        for gallery in galleries {
+            let res = db.gallery_persist(&gallery).map_err(Error::from);
+            if let (Err(e), true) = (res, result.is_ok()) {
+                result = Err(e);
+            }
+        }
+        result.map(|_| report)
+    }
 
-        Ok(())
+    /// Files with GPS coordinates.
+    pub fn files_geo(&self, authed: bool) -> Result<Vec<File>, Error> {
+        let con = self.db.get()?;
+        let files = db::Db::new(&con).files_geo(!authed)?;
+        Ok(Self::files_redact(files, authed))
     }
 
-    pub fn file(&self, hash: &str) -> Result<File, Error> {
+    /// Media files without dimensions or length, e.g. to retry extracting them.
+    pub fn files_missing_media_info(&self, authed: bool) -> Result<Vec<File>, Error> {
         let con = self.db.get()?;
-        db::Db::new(&con).file(hash)
+        let files = db::Db::new(&con).files_missing_media_info(!authed)?;
+        Ok(Self::files_redact(files, authed))
     }
 
-    pub fn files(&self, query: FileQuery) -> Result<t::FilesPage, Error> {
+    /// Images and videos without a cached thumbnail of `size`, e.g. to
+    /// generate them ahead of time. Files missing from storage are skipped.
+    pub fn files_missing_thumbnails(&self, size: Option<u32>) -> Result<Vec<File>, Error> {
         let con = self.db.get()?;
-        let files = db::Db::new(&con)
-            .files(query)?;
-        Ok(files)
+        let files = db::Db::new(&con).files_with_thumbnails()?;
+        let size = size.unwrap_or(thumbs::DEFAULT_SIZE);
+
+        let mut missing = Vec::new();
+        for file in files {
+            if file.info.is_svg() {
+                continue;
+            }
+            let source = match self.storage.media_source(&file.path)? {
+                Some(source) => source,
+                None => continue,
+            };
+            if !self.thumbs.is_cached(&source, &file.info.hash, size)? {
+                missing.push(file);
+            }
+        }
+        Ok(missing)
     }
 
+    /// Load files in the order of the given hashes, e.g. for a custom order
+    /// picked by the user. Hashes without a file, or of private files for
+    /// unauthenticated requests, are reported as missing.
+    pub fn files_by_hashes(&self, data: t::FilesByHashes, authed: bool) -> Result<t::FilesByHashesResult, Error> {
+        let con = self.db.get()?;
+        let mut files = db::Db::new(&con)
+            .files_by_hashes(&data.hashes, !authed)?
+            .into_iter()
+            .map(|file| (file.info.hash.clone(), file))
+            .collect::<HashMap<_, _>>();
+
+        let mut result = t::FilesByHashesResult::default();
+        let mut seen = HashSet::new();
+        for hash in data.hashes {
+            if !seen.insert(hash.clone()) {
+                continue;
+            }
+            match files.remove(&hash) {
+                Some(file) => result.items.push(file),
+                None => result.missing.push(hash),
+            }
+        }
+        result.items = Self::files_redact(result.items, authed);
+        Ok(result)
+    }
+
+    /// Files added by the most recent index run.
+    pub fn files_last_indexed(&self, authed: bool) -> Result<Vec<File>, Error> {
+        let con = self.db.get()?;
+        let files = db::Db::new(&con).files_last_indexed(!authed)?;
+        Ok(Self::files_redact(files, authed))
+    }
+
+    /// Load a file. Private files are only visible to authenticated requests.
+    pub fn file(&self, hash: &str, authed: bool) -> Result<File, Error> {
+        let con = self.db.get()?;
+        let mut file = db::Db::new(&con).file(hash)?;
+        if !authed && file.meta.visibility == t::Visibility::Private {
+            return Err(AppError::not_found().into());
+        }
+        Self::file_redact(&mut file, authed);
+        Ok(file)
+    }
+
+    /// Remove fields only authenticated requests may see.
+    fn file_redact(file: &mut File, authed: bool) {
+        if !authed {
+            file.meta.notes = None;
+        }
+    }
+
+    fn files_redact(mut files: Vec<File>, authed: bool) -> Vec<File> {
+        for file in files.iter_mut() {
+            Self::file_redact(file, authed);
+        }
+        files
+    }
+
+    /// Query files. Unauthenticated requests only see public files.
+    pub fn files(&self, mut query: FileQuery, authed: bool, totals: bool) -> Result<t::FilesPage, Error> {
+        query.filter = self.files_filter(query.filter.take(), authed)?;
+
+        for item in query.sort.iter_mut() {
+            self.sort_direction_apply(item);
+        }
+        if let Some(cursor) = query.cursor.as_mut() {
+            self.sort_direction_apply(&mut cursor.sort);
+        }
+
+        let con = self.db.get()?;
+        let mut page = db::Db::new(&con)
+            .files(query, totals)?;
+        page.items = Self::files_redact(page.items, authed);
+        Ok(page)
+    }
+
+    /// Number of files matching the filter. Unauthenticated requests only
+    /// count public files.
+    pub fn files_count(&self, filter: Option<FileFilter>, authed: bool) -> Result<u32, Error> {
+        let query = FileQuery {
+            filter: self.files_filter(filter, authed)?,
+            ..FileQuery::default()
+        };
+        let con = self.db.get()?;
+        let count = db::Db::new(&con).files_count(&query)?;
+        Ok(count)
+    }
+
+    /// Check a query filter and restrict it to what the request may see.
+    fn files_filter(&self, filter: Option<FileFilter>, authed: bool) -> Result<Option<FileFilter>, Error> {
+        // Applying filters recurses, so bound the depth.
+        let max = self.config.max_filter_depth.unwrap_or(DEFAULT_MAX_FILTER_DEPTH);
+        if filter.as_ref().map(|f| f.depth()).unwrap_or(0) > max {
+            return Err(AppError::BadRequest(format!("filter_too_deep: at most {} levels are allowed", max)).into());
+        }
+
+        if authed {
+            return Ok(filter);
+        }
+        let public = FileFilter::Visibility(t::Visibility::Public);
+        Ok(Some(match filter {
+            Some(filter) => FileFilter::And(Box::new(filter), Box::new(public)),
+            None => public,
+        }))
+    }
+
+    /// Fill in the configured direction if the sort item has none.
+    fn sort_direction_apply(&self, item: &mut FileSortItem) {
+        if item.ascending.is_none() {
+            item.ascending = Some(
+                self.config.sort_ascending.get(&item.sort).cloned()
+                    .unwrap_or_else(|| item.sort.default_ascending())
+            );
+        }
+    }
+
+    /// Files featured on the home page.
+    pub fn files_featured(&self, authed: bool) -> Result<Vec<File>, Error> {
+        let con = self.db.get()?;
+        let files = db::Db::new(&con).files_featured(!authed)?;
+        Ok(Self::files_redact(files, authed))
+    }
+
+    /// Load a media file for serving.
+    /// Private files are treated as missing for unauthenticated requests.
+    ///
+    /// Subtitles of videos are available at `{video path}/subtitles/{lang}`.
+    pub fn media(&self, path: &str, authed: bool) -> Result<Option<storage::Media>, Error> {
+        if let Some(index) = path.rfind("/subtitles/") {
+            let (file_path, lang) = (&path[..index], &path[index + "/subtitles/".len()..]);
+            if let Some(subtitle) = self.storage.subtitle(file_path, lang)? {
+                return self.media_visible(file_path, authed, subtitle);
+            }
+        }
+
+        match self.storage.media(path)? {
+            Some(mut media) => {
+                // Indexed files are identified by their hash.
+                let con = self.db.get()?;
+                media.etag = db::Db::new(&con)
+                    .file_hash_by_path(path)?
+                    .map(|hash| format!("\"{}\"", hash));
+                self.media_visible(path, authed, media)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Load a media file and check its content against the indexed hash.
+    ///
+    /// Fails if the file is not indexed or if it changed since, e.g. due to
+    /// corruption on disk.
+    pub fn media_verified(&self, path: &str, authed: bool) -> Result<Option<storage::Media>, Error> {
+        let mut media = match self.media(path, authed)? {
+            Some(media) => media,
+            None => return Ok(None),
+        };
+
+        let con = self.db.get()?;
+        let stored = db::Db::new(&con)
+            .file_hash_by_path(path)?
+            .ok_or_else(|| AppError::NotFound(format!("no indexed hash for {}", path)))?;
+        let live = match media.content {
+            storage::MediaContent::Data(ref data) => self.storage.compute_hash(&data[..])?,
+            storage::MediaContent::File(ref mut file, _) => {
+                let live = self.storage.compute_hash(&mut *file)?;
+                // Served from the start after hashing.
+                file.seek(io::SeekFrom::Start(0))?;
+                live
+            }
+        };
+        if live != stored {
+            return Err(AppError::Internal(format!("hash mismatch for {}: indexed {}, actual {}", path, stored, live)).into());
+        }
+
+        media.hash = Some((self.storage.hash_algo(), stored));
+        Ok(Some(media))
+    }
+
+    /// Thumbnail of an indexed image or video, generated on the first request.
+    /// SVGs are served as they are.
+    ///
+    /// Returns `None` for unknown hashes, files that are neither images nor
+    /// videos and, for unauthenticated requests, private files.
+    pub fn thumbnail(
+        &self,
+        hash: &str,
+        size: Option<u32>,
+        format: thumbs::ThumbFormat,
+        authed: bool,
+    ) -> Result<Option<storage::Media>, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        if !db.file_exists(hash)? {
+            return Ok(None);
+        }
+        let file = db.file(hash)?;
+        if !thumbs::Thumbnailer::supports(file.info.kind) {
+            return Ok(None);
+        }
+        if !authed && file.meta.visibility == t::Visibility::Private {
+            return Ok(None);
+        }
+
+        let source = match self.storage.media_source(&file.path)? {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        // SVGs scale on their own and can't be decoded like raster images.
+        if file.info.is_svg() {
+            return Ok(Some(storage::Media {
+                mime: file.info.mime.clone(),
+                content: storage::MediaContent::open(&source)?,
+                hash: None,
+                etag: None,
+            }));
+        }
+        let size = size.unwrap_or(thumbs::DEFAULT_SIZE);
+        let (path, format) = self.thumbs.thumbnail(&source, file.info.kind, hash, size, format)?;
+        Ok(Some(storage::Media {
+            mime: Some(format.mime().to_string()),
+            content: storage::MediaContent::open(&path)?,
+            hash: None,
+            etag: None,
+        }))
+    }
+
+    fn media_visible(
+        &self,
+        path: &str,
+        authed: bool,
+        media: storage::Media,
+    ) -> Result<Option<storage::Media>, Error> {
+        if !authed && self.storage.file_meta(path)?.visibility == t::Visibility::Private {
+            Ok(None)
+        } else {
+            Ok(Some(media))
+        }
+    }
+
+    /// Update the metadata of a file.
+    ///
+    /// `None` fields keep their current value, so title, description,
+    /// featured weight and notes can not be cleared this way. `Some(vec![])` clears the
+    /// tags. Use `file_patch` to clear fields.
     pub fn file_update(&self, data: t::FileUpdate) -> Result<File, Error> {
         let con = self.db.get()?;
         let db = db::Db::new(&con);
 
         let file = db.file(&data.hash)?;
         let cur_meta = self.storage.file_meta(&file.path)?;
+        let meta = Self::file_update_meta(cur_meta, data);
 
-        let meta = FileMeta{
+        let mut file = self.storage.file_meta_update(&file.path, meta)?;
+        file.info.updated_at = Some(now());
+        db.file_persist(&file)?;
+
+        Ok(file)
+    }
+
+    fn file_update_meta(cur_meta: FileMeta, data: t::FileUpdate) -> FileMeta {
+        let mut tags = data.tags.unwrap_or(cur_meta.tags);
+        for op in &data.tag_ops {
+            op.apply(&mut tags);
+        }
+
+        FileMeta{
             title: data.title.or(cur_meta.title),
             description: data.description.or(cur_meta.description),
+            tags,
+            sources: data.sources.unwrap_or(cur_meta.sources),
+            hash: None,
+            visibility: data.visibility.unwrap_or(cur_meta.visibility),
+            featured_weight: data.featured_weight.or(cur_meta.featured_weight),
+            notes: data.notes.or(cur_meta.notes),
+        }
+    }
+
+    /// Apply several updates like `file_update`.
+    ///
+    /// The index is updated in a single transaction. If an update fails, the
+    /// transaction is rolled back and metadata files written so far are
+    /// restored. The error names the position of the failed update. Each
+    /// update starts from the metadata before the batch, so a file should
+    /// only be listed once.
+    pub fn files_update(&self, updates: Vec<t::FileUpdate>) -> Result<Vec<File>, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+
+        // Load everything first, so unknown files fail before anything is written.
+        let mut changes = Vec::new();
+        for (index, data) in updates.into_iter().enumerate() {
+            let change = db.file(&data.hash).and_then(|file| {
+                let cur_meta = self.storage.file_meta(&file.path)?;
+                let meta = Self::file_update_meta(cur_meta.clone(), data);
+                Ok((file.path, cur_meta, meta))
+            });
+            changes.push(change.map_err(|e| Self::batch_error(index, e))?);
+        }
+
+        let mut written = Vec::new();
+        let res = db.transaction(|| {
+            let mut files = Vec::new();
+            for (index, (path, cur_meta, meta)) in changes.iter().enumerate() {
+                let mut file = self.storage
+                    .file_meta_update(path, meta.clone())
+                    .map_err(|e| Self::batch_error(index, e))?;
+                written.push((path, cur_meta));
+                file.info.updated_at = Some(now());
+                db.file_persist(&file).map_err(|e| Self::batch_error(index, e.into()))?;
+                files.push(file);
+            }
+            Ok(files)
+        });
+
+        if res.is_err() {
+            for (path, cur_meta) in written {
+                if let Err(e) = self.storage.file_meta_update(path, cur_meta.clone()) {
+                    log::error!("Could not restore metadata of {}: {}", path, e);
+                }
+            }
+        }
+        res
+    }
+
+    /// Name the failed update of a batch in its error, keeping the kind.
+    fn batch_error(index: usize, err: Error) -> Error {
+        let message = format!("update {} failed: {}", index, err);
+        let err = match err.downcast_ref::<AppError>() {
+            Some(AppError::NotFound(_)) => AppError::NotFound(message),
+            Some(AppError::Conflict(_)) => AppError::Conflict(message),
+            Some(AppError::BadRequest(_)) => AppError::BadRequest(message),
+            Some(AppError::Internal(_)) | None => AppError::Internal(message),
+        };
+        err.into()
+    }
+
+    /// Partially update the metadata of a file.
+    ///
+    /// Unlike `file_update`, a field set to `Some(None)` is cleared, while
+    /// `None` means no change.
+    pub fn file_patch(&self, data: t::FilePatch) -> Result<File, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+
+        let file = db.file(&data.hash)?;
+        let cur_meta = self.storage.file_meta(&file.path)?;
+
+        let meta = FileMeta{
+            title: data.title.unwrap_or(cur_meta.title),
+            description: data.description.unwrap_or(cur_meta.description),
             tags: data.tags.unwrap_or(cur_meta.tags),
             sources: cur_meta.sources,
-            hash: None
+            hash: None,
+            visibility: data.visibility.unwrap_or(cur_meta.visibility),
+            featured_weight: data.featured_weight.unwrap_or(cur_meta.featured_weight),
+            notes: data.notes.unwrap_or(cur_meta.notes),
         };
 
-        let file = self.storage.file_meta_update(&file.path, meta)?;
+        let mut file = self.storage.file_meta_update(&file.path, meta)?;
+        file.info.updated_at = Some(now());
         db.file_persist(&file)?;
 
         Ok(file)
@@ -102,9 +812,1528 @@ impl App {
 
         let file = db.file(hash)?;
 
-        self.storage.file_delete(&file.path)?;
-        db.file_delete(hash)?;
+        if self.config.trash {
+            self.storage.file_trash(&file.path)?;
+            db.file_trash(hash, &now())?;
+        } else {
+            self.storage.file_delete(&file.path)?;
+            db.file_delete(hash)?;
+        }
+
+        Ok(())
+    }
+
+    /// Move a file to another path. The file keeps its hash, so tags and
+    /// gallery membership are preserved.
+    pub fn file_move(&self, from: &str, to: &str) -> Result<File, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+
+        let hash = db.file_hash_by_path(from)?.ok_or_else(AppError::not_found)?;
+        if db.file_hash_by_path(to)?.is_some() {
+            return Err(AppError::Conflict("path_already_exists".to_string()).into());
+        }
+
+        let subtitles = db.file(&hash)?.info.subtitles;
+        let moved = self.storage.file_move(from, to, &subtitles)?;
+        let res = db.transaction(|| {
+            db.file_path_move(from, to)?;
+            db.file_subtitles_persist(&hash, &moved)
+        });
+        if let Err(e) = res {
+            // Keep the database and storage consistent.
+            if let Err(e) = self.storage.file_move(to, from, &moved) {
+                log::error!("Could not move {} back to {}: {}", to, from, e);
+            }
+            return Err(e.into());
+        }
+        db.file(&hash)
+    }
+
+    /// Files in the trash.
+    pub fn trash(&self) -> Result<Vec<File>, Error> {
+        let con = self.db.get()?;
+        let files = db::Db::new(&con).files_trashed()?;
+        Ok(files)
+    }
+
+    /// Delete files that have been in the trash for longer than
+    /// `trash_retention_days`. Does nothing without a retention period.
+    pub fn trash_purge(&self) -> Result<t::TrashPurgeReport, Error> {
+        let days = match self.config.trash_retention_days {
+            Some(days) => days,
+            None => return Ok(t::TrashPurgeReport::default()),
+        };
+        let cutoff = now() - chrono::Duration::days(i64::from(days));
+
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        let mut report = t::TrashPurgeReport::default();
+        for hash in db.files_trashed_before(&cutoff)? {
+            let file = db.file(&hash)?;
+            self.storage.file_purge(&file.path)?;
+            db.file_delete(&hash)?;
+            report.purged.push(hash);
+        }
+        if !report.purged.is_empty() {
+            log::info!("Purged {} files from the trash", report.purged.len());
+        }
+        Ok(report)
+    }
+
+    /// Move a file back from the trash to its original path.
+    pub fn file_restore(&self, hash: &str) -> Result<File, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        if !db.file_is_trashed(hash)? {
+            return Err(AppError::not_found().into());
+        }
+
+        let trashed = db.file(hash)?;
+        let file = self.storage.file_restore(&trashed.path)?;
+        db.file_untrash(hash)?;
+        db.file_persist(&file)?;
+        db.file_subtitles_persist(&file.info.hash, &file.info.subtitles)?;
+        Ok(file)
+    }
+
+    /// All galleries. Unauthenticated requests only see public items.
+    pub fn galleries(&self, authed: bool) -> Result<Vec<storage::Gallery>, Error> {
+        let con = self.db.get()?;
+        let galleries = db::Db::new(&con).galleries(!authed)?;
+        Ok(galleries)
+    }
+
+    pub fn gallery(&self, path: &str, authed: bool) -> Result<storage::Gallery, Error> {
+        let con = self.db.get()?;
+        db::Db::new(&con)
+            .gallery(path, !authed)?
+            .ok_or_else(|| AppError::not_found().into())
+    }
+
+    /// Create a gallery. Fails if one already exists at the path.
+    pub fn gallery_create(&self, gallery: storage::Gallery) -> Result<storage::Gallery, Error> {
+        let con = self.db.get()?;
+        if db::Db::new(&con).gallery(&gallery.path, false)?.is_some() {
+            return Err(AppError::Conflict("gallery_exists".to_string()).into());
+        }
+        self.gallery_save(&gallery)
+    }
+
+    /// Replace an existing gallery, including its items.
+    pub fn gallery_update(&self, gallery: storage::Gallery) -> Result<storage::Gallery, Error> {
+        let con = self.db.get()?;
+        if db::Db::new(&con).gallery(&gallery.path, false)?.is_none() {
+            return Err(AppError::not_found().into());
+        }
+        self.gallery_save(&gallery)
+    }
+
+    /// Write the gallery file, then update the index.
+    fn gallery_save(&self, gallery: &storage::Gallery) -> Result<storage::Gallery, Error> {
+        self.storage.gallery_write(gallery)?;
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        db.gallery_persist(gallery)?;
+        db.gallery(&gallery.path, false)?
+            .ok_or_else(|| AppError::not_found().into())
+    }
+
+    pub fn gallery_delete(&self, path: &str) -> Result<(), Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        if db.gallery(path, false)?.is_none() {
+            return Err(AppError::not_found().into());
+        }
+        self.storage.gallery_delete(path)?;
+        db.gallery_delete(path)?;
+        Ok(())
+    }
+
+    /// Tags in use. Unauthenticated requests only see tags of public files.
+    pub fn tags(&self, limit: Option<u32>, authed: bool) -> Result<Vec<t::TagCount>, Error> {
+        let con = self.db.get()?;
+        let tags = db::Db::new(&con)
+            .tags(limit, !authed)?
+            .into_iter()
+            .map(|(tag, count)| t::TagCount { tag, count })
+            .collect();
+        Ok(tags)
+    }
+
+    /// Files whose content is stored at more than one path.
+    pub fn duplicates(&self) -> Result<Vec<t::Duplicate>, Error> {
+        let con = self.db.get()?;
+        let duplicates = db::Db::new(&con)
+            .duplicates()?
+            .into_iter()
+            .map(|(hash, paths)| t::Duplicate { hash, paths })
+            .collect();
+        Ok(duplicates)
+    }
+
+    /// Library statistics. Unauthenticated requests only count public files.
+    pub fn stats(&self, authed: bool) -> Result<t::Stats, Error> {
+        let con = self.db.get()?;
+        let kinds = db::Db::new(&con)
+            .kind_counts(!authed)?
+            .into_iter()
+            .map(|(kind, count)| t::KindCount { kind, count })
+            .collect::<Vec<_>>();
+        Ok(t::Stats {
+            total: kinds.iter().map(|k| k.count).sum(),
+            kinds,
+        })
+    }
+
+    /// Rename a tag on all files, in the index and in their metadata files.
+    pub fn tag_rename(&self, data: t::TagRename) -> Result<t::TagRenameReport, Error> {
+        let updated = self.tags_replace(&[data.from], &data.to)?;
+        Ok(t::TagRenameReport { updated })
+    }
+
+    /// Merge tags into one, in the index and in the metadata files.
+    pub fn tag_merge(&self, data: t::TagMerge) -> Result<t::TagRenameReport, Error> {
+        let updated = self.tags_replace(&data.sources, &data.target)?;
+        Ok(t::TagRenameReport { updated })
+    }
+
+    /// Replace tags with `target` on all files.
+    ///
+    /// The index is changed in a single transaction. Metadata files are
+    /// written afterwards, a failure there leaves the remaining ones with the
+    /// old tags until they are updated again.
+    fn tags_replace(&self, sources: &[String], target: &str) -> Result<Vec<String>, Error> {
+        let target = target.trim();
+        if target.is_empty() {
+            return Err(AppError::BadRequest("tag must not be empty".to_string()).into());
+        }
+
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        let updated = db.tag_merge(sources, target)?;
+
+        for hash in &updated {
+            let file = db.file(hash)?;
+            let mut meta = self.storage.file_meta(&file.path)?;
+            let mut tags = Vec::new();
+            for tag in meta.tags {
+                let tag = if sources.contains(&tag) { target.to_string() } else { tag };
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+            meta.tags = tags;
+            self.storage.file_meta_update(&file.path, meta)?;
+        }
+        Ok(updated)
+    }
+
+    /// Existing tags starting with `prefix`, e.g. for autocompletion.
+    /// Unauthenticated requests only get tags of public files.
+    pub fn tag_suggest(&self, prefix: &str, limit: u32, authed: bool) -> Result<Vec<String>, Error> {
+        let con = self.db.get()?;
+        let tags = db::Db::new(&con).tags_matching(prefix, limit, !authed)?;
+        Ok(tags)
+    }
+
+    /// Totals per subfolder of `path`. Private files are only counted for
+    /// authenticated requests.
+    pub fn folder_stats(&self, path: &str, authed: bool) -> Result<Vec<t::FolderStats>, Error> {
+        let con = self.db.get()?;
+        let stats = db::Db::new(&con).folder_aggregates(path, !authed)?;
+        Ok(stats)
+    }
+
+    /// Inspect a file in the storage without indexing it.
+    pub fn storage_info(&self, path: &str) -> Result<t::FileInfo, Error> {
+        if !storage::Storage::is_relative(path) {
+            return Err(AppError::BadRequest("invalid_path".to_string()).into());
+        }
+        self.storage.file_info(path)
+    }
+
+    /// Directory hierarchy of the storage, with the indexing status of files.
+    pub fn tree(&self, path: Option<&str>) -> Result<t::TreeEntry, Error> {
+        fn mark_indexed(entry: &mut t::TreeEntry, indexed_paths: &HashSet<String>) {
+            match entry.kind {
+                t::TreeEntryKind::Directory(ref mut children) => {
+                    for child in children {
+                        mark_indexed(child, indexed_paths);
+                    }
+                }
+                t::TreeEntryKind::File { ref mut indexed, .. } => {
+                    *indexed = indexed_paths.contains(&entry.path);
+                }
+            }
+        }
+
+        let mut tree = self.storage.tree(path)?;
+        let con = self.db.get()?;
+        let indexed_paths = db::Db::new(&con).file_paths()?;
+        mark_indexed(&mut tree, &indexed_paths);
+        Ok(tree)
+    }
+
+    /// List all importer scripts in the storage.
+    pub fn importers(&self) -> Result<Vec<t::ImporterInfo>, Error> {
+        self.storage
+            .importers()
+            .map(|importer| importer.map(|i| i.info()))
+            .collect()
+    }
+
+    /// Evaluate an importer and return its raw output.
+    ///
+    /// `input` is fetched if it is an `http(s)://` url that the importer
+    /// matches, otherwise it is passed to the importer as html with an empty
+    /// url.
+    pub fn import(&self, path: &str, input: &str) -> Result<t::ImporterOutput, Error> {
+        let importer = self.storage.importer(path)?;
+
+        let input = if input.starts_with("http://") || input.starts_with("https://") {
+            if !importer.info().matches_url(input) {
+                return Ok(t::ImporterOutput::NoMatch);
+            }
+            importer::ImporterInput {
+                url: input.to_string(),
+                html: fetcher::fetch_text(input)?,
+            }
+        } else {
+            importer::ImporterInput {
+                url: String::new(),
+                html: input.to_string(),
+            }
+        };
+
+        self.importer_engine.run(&importer, &input)
+    }
+
+    /// Run an importer against a url and return the extracted sources.
+    /// Nothing is downloaded or stored.
+    pub fn import_run(&self, run: t::ImportRun) -> Result<Vec<t::FileSource>, Error> {
+        if !run.url.starts_with("http://") && !run.url.starts_with("https://") {
+            return Err(AppError::BadRequest(format!("invalid url: {}", run.url)).into());
+        }
+        match self.import(&run.importer_path, &run.url)? {
+            t::ImporterOutput::Ok(items) => {
+                let sources = items
+                    .into_iter()
+                    .map(|item| match item {
+                        t::ImporterItem::File(source) => source,
+                    })
+                    .collect();
+                Ok(sources)
+            }
+            t::ImporterOutput::NoMatch => Err(AppError::BadRequest("importer_no_match".to_string()).into()),
+            t::ImporterOutput::Err(e) => Err(format_err!("importer_error: {}", e)),
+        }
+    }
+
+    /// Attach sources to already indexed files, matched by content hash.
+    ///
+    /// Sources with a url the file already has are skipped.
+    pub fn import_backfill(&self, data: t::ImportBackfill) -> Result<t::ImportBackfillReport, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+
+        let mut report = t::ImportBackfillReport::default();
+        for item in data.items {
+            if !db.file_exists(&item.hash)? {
+                report.missing.push(item.hash);
+                continue;
+            }
+            let file = db.file(&item.hash)?;
+            let mut meta = self.storage.file_meta(&file.path)?;
+
+            let mut changed = false;
+            for source in item.sources {
+                if !meta.sources.iter().any(|s| s.url == source.url) {
+                    meta.sources.push(source);
+                    changed = true;
+                }
+            }
+            if changed {
+                let file = self.storage.file_meta_update(&file.path, meta)?;
+                db.file_persist(&file)?;
+                report.updated.push(item.hash);
+            }
+        }
+        Ok(report)
+    }
+
+    /// Propose tags for all indexed files based on their names, and add them
+    /// unless `dry_run` is set.
+    ///
+    /// Only files that would get new tags are reported.
+    pub fn tags_from_filename(&self, dry_run: bool) -> Result<t::TagsFromFilenameReport, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+
+        let mut paths = db.file_paths()?.into_iter().collect::<Vec<_>>();
+        paths.sort();
+
+        let mut report = t::TagsFromFilenameReport {
+            applied: !dry_run,
+            files: Vec::new(),
+        };
+        for path in paths {
+            let mut meta = self.storage.file_meta(&path)?;
+            let tags = storage::Storage::filename_tags(&path)
+                .into_iter()
+                .filter(|tag| !meta.tags.contains(tag))
+                .collect::<Vec<_>>();
+            if tags.is_empty() {
+                continue;
+            }
+
+            if !dry_run {
+                meta.tags.extend(tags.iter().cloned());
+                let file = self.storage.file_meta_update(&path, meta)?;
+                db.file_persist(&file)?;
+            }
+            report.files.push(t::TagProposal { path, tags });
+        }
+        Ok(report)
+    }
+
+    /// Download the files of importer sources and add them to the vault.
+    ///
+    /// Files whose content is already stored are skipped. Failures are
+    /// reported per source and don't abort the remaining downloads.
+    pub fn import_fetch(&self, data: t::ImportFetch) -> Result<Vec<t::ImportFetchItem>, Error> {
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        let dir = data.path.unwrap_or_else(|| "imports".to_string());
+
+        let items = data.sources
+            .into_iter()
+            .map(|source| {
+                let url = source.url.clone();
+                let status = match self.import_source(&db, &dir, source) {
+                    Ok(status) => status,
+                    Err(e) => t::ImportFetchStatus::Failed(e.to_string()),
+                };
+                t::ImportFetchItem { url, status }
+            })
+            .collect();
+        Ok(items)
+    }
+
+    fn import_source(
+        &self,
+        db: &db::Db,
+        dir: &str,
+        source: t::FileSource,
+    ) -> Result<t::ImportFetchStatus, Error> {
+        let data = fetcher::fetch_bytes(&source.url)?;
+
+        let hash = self.storage.compute_hash(&data[..])?;
+        if db.file_exists(&hash)? {
+            return Ok(t::ImportFetchStatus::Duplicate(hash));
+        }
+
+        let name = fetcher::file_name(&source.url).unwrap_or_else(|| hash.clone());
+        let mut path = format!("{}/{}", dir, name);
+        if self.storage.exists(&path) {
+            path = format!("{}/{}_{}", dir, hash, name);
+        }
+
+        let meta = FileMeta {
+            title: source.title.clone(),
+            description: source.description.clone(),
+            tags: source.tags.clone(),
+            sources: vec![source],
+            hash: None,
+            visibility: Default::default(),
+            featured_weight: None,
+            notes: None,
+        };
+        let file = self.storage.file_create(&path, meta, &data[..])?;
+        db.file_persist(&file)?;
+
+        Ok(t::ImportFetchStatus::Stored(file))
+    }
+
+    /// Index the storage, then remove files and galleries from the index that
+    /// no longer exist in the storage.
+    pub fn reindex(&self) -> Result<IndexReport, Error> {
+        let mut report = self.index()?;
+
+        let present = self.storage.paths(None).collect::<Result<HashSet<_>, _>>()?;
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+
+        let mut removed = db.file_paths()?
+            .into_iter()
+            .filter(|path| !present.contains(path))
+            .collect::<Vec<_>>();
+        removed.sort();
+        for path in &removed {
+            db.file_path_delete(path)?;
+        }
 
+        for gallery in db.galleries(false)? {
+            if !present.contains(&gallery.path) {
+                db.gallery_delete(&gallery.path)?;
+                removed.push(gallery.path);
+            }
+        }
+
+        report.removed = removed;
+        Ok(report)
+    }
+
+    /// Skipped by the watcher: sidecars, which are tracked with their file,
+    /// the trash and cached thumbnails in case they are stored inside a root.
+    fn watch_ignored(path: &str) -> bool {
+        storage::Storage::is_sidecar(path)
+            || path.split('/').any(|segment| segment == ".thumbs" || segment == storage::TRASH_DIR)
+    }
+
+    /// Index a file or gallery that was created or modified on disk.
+    pub fn path_changed(&self, full_path: &Path) -> Result<(), Error> {
+        let path = match self.storage.storage_path(full_path) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if Self::watch_ignored(&path) || !full_path.is_file() {
+            return Ok(());
+        }
+        log::debug!("Indexing changed path {}", path);
+
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        match self.storage.item(&path)? {
+            storage::StorageItem::File(f) => {
+                // The old content of the path.
+                if db.file_hash_by_path(&path)?.map(|hash| hash != f.info.hash).unwrap_or(false) {
+                    db.file_path_delete(&path)?;
+                }
+                db.file_persist(&f)?;
+                db.file_subtitles_persist(&f.info.hash, &f.info.subtitles)?;
+            }
+            storage::StorageItem::Gallery(g) => db.gallery_persist(&g)?,
+            storage::StorageItem::Importer(_) => {}
+        }
         Ok(())
     }
+
+    /// Remove a file, gallery or directory that was deleted on disk from the
+    /// index.
+    pub fn path_removed(&self, full_path: &Path) -> Result<(), Error> {
+        let path = match self.storage.storage_path(full_path) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if Self::watch_ignored(&path) {
+            return Ok(());
+        }
+        log::debug!("Removing deleted path {}", path);
+
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        if path.ends_with(".gallery.yaml") {
+            db.gallery_delete(&path)?;
+            return Ok(());
+        }
+
+        // Directories are reported as a whole.
+        let prefix = format!("{}/", path);
+        for indexed in db.file_paths()? {
+            if indexed == path || indexed.starts_with(&prefix) {
+                db.file_path_delete(&indexed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Index the storage from scratch.
+    ///
+    /// Everything derived from the storage is thrown away first. Files in the
+    /// trash and edit timestamps survive, see `Db::reset`. Files that are no
+    /// longer in the storage are removed once the index run completed.
+    pub fn rebuild_index(&self) -> Result<IndexReport, Error> {
+        {
+            let con = self.db.get()?;
+            db::Db::new(&con).reset()?;
+        }
+        let mut report = self.index()?;
+
+        let con = self.db.get()?;
+        let db = db::Db::new(&con);
+        for hash in db.files_unindexed()? {
+            db.file_delete(&hash)?;
+            report.removed.push(hash);
+        }
+        Ok(report)
+    }
+
+    /// Remove gallery items whose file is no longer indexed.
+    pub fn galleries_prune(&self) -> Result<usize, Error> {
+        let con = self.db.get()?;
+        let pruned = db::Db::new(&con).gallery_items_prune()?;
+        Ok(pruned)
+    }
+
+    /// Reclaim free space and refresh query planner statistics.
+    pub fn optimize(&self) -> Result<(), Error> {
+        // VACUUM can not run inside a transaction, so use a dedicated
+        // connection instead of a pooled one.
+        let con = db::Connection::open(&self.config.db_path)?;
+        db::Db::new(&con).optimize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// An app over a fresh storage directory and database, removed on drop.
+    pub struct TestApp {
+        pub app: App,
+        pub dir: PathBuf,
+    }
+
+    impl TestApp {
+        pub fn new() -> Self {
+            Self::with_config(Config::default())
+        }
+
+        pub fn with_config(config: Config) -> Self {
+            let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+            let storage_dir = dir.join("storage");
+            fs::create_dir_all(&storage_dir).unwrap();
+
+            let config = Config {
+                db_path: dir.join("db.sqlite3").to_string_lossy().into_owned(),
+                storage_roots: vec![storage::StorageRoot {
+                    id: String::new(),
+                    path: storage_dir.to_string_lossy().into_owned(),
+                }],
+                thumbs_path: Some(dir.join("thumbs").to_string_lossy().into_owned()),
+                ..config
+            };
+            TestApp {
+                app: App::new(config).unwrap(),
+                dir,
+            }
+        }
+
+        pub fn full_path(&self, path: &str) -> PathBuf {
+            self.dir.join("storage").join(path)
+        }
+
+        pub fn write(&self, path: &str, data: &[u8]) {
+            let path = self.full_path(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        }
+
+        pub fn total(&self) -> u32 {
+            self.app.files(FileQuery::default(), true, false).unwrap().total
+        }
+
+        pub fn hash(&self, path: &str) -> String {
+            let con = self.app.db.get().unwrap();
+            db::Db::new(&con).file_hash_by_path(path).unwrap().unwrap()
+        }
+    }
+
+    impl Drop for TestApp {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    pub fn update(hash: &str) -> t::FileUpdate {
+        t::FileUpdate {
+            hash: hash.to_string(),
+            title: None,
+            description: None,
+            tags: None,
+            visibility: None,
+            featured_weight: None,
+            notes: None,
+            sources: None,
+            tag_ops: Vec::new(),
+        }
+    }
+
+    /// Just the `ftyp` box of an MP4 file, enough for `file` to detect it.
+    pub const MP4: &[u8] = b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00mp42isom";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::*;
+
+    #[test]
+    fn file_update_keeps_subtitles() {
+        let vault = TestApp::new();
+        vault.write("clip.mp4", MP4);
+        vault.write("clip.en.vtt", b"WEBVTT\n");
+        vault.app.index().unwrap();
+        let hash = vault.hash("clip.mp4");
+
+        let updated = vault.app.file_update(t::FileUpdate {
+            title: Some("Clip".to_string()),
+            ..update(&hash)
+        }).unwrap();
+        assert_eq!(updated.info.subtitles.len(), 1);
+
+        let file = vault.app.file(&hash, true).unwrap();
+        assert_eq!(file.meta.title, Some("Clip".to_string()));
+        assert_eq!(file.info.subtitles.len(), 1);
+        assert_eq!(file.info.subtitles[0].lang, "en");
+        assert_eq!(file.info.subtitles[0].path, "clip.en.vtt");
+    }
+
+    #[test]
+    fn trashed_file_stays_trashed_when_a_copy_is_indexed() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"same");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.txt");
+
+        vault.app.file_delete(&hash).unwrap();
+        assert!(!vault.full_path("a.txt").exists());
+        assert_eq!(vault.total(), 0);
+
+        vault.write("copy.txt", b"same");
+        vault.app.path_changed(&vault.full_path("copy.txt")).unwrap();
+        assert_eq!(vault.total(), 0);
+        let trash = vault.app.trash().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].path, "a.txt");
+
+        let restored = vault.app.file_restore(&hash).unwrap();
+        assert_eq!(restored.path, "a.txt");
+        assert!(vault.full_path("a.txt").exists());
+        assert!(vault.app.trash().unwrap().is_empty());
+        assert_eq!(vault.total(), 1);
+    }
+
+    #[test]
+    fn file_update_bumps_updated_at_across_index_runs() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.txt");
+        let before = vault.app.file(&a, true).unwrap().info.updated_at.unwrap();
+
+        let updated = vault.app.file_update(t::FileUpdate {
+            title: Some("A".to_string()),
+            ..update(&a)
+        }).unwrap();
+        let bumped = updated.info.updated_at.unwrap();
+        assert!(bumped > before);
+
+        let query = FileQuery {
+            sort: vec![FileSortItem { sort: FileSort::Updated, ascending: Some(false) }],
+            ..FileQuery::default()
+        };
+        let first = |vault: &TestApp| vault.app.files(query.clone(), true, false).unwrap().items[0].info.hash.clone();
+        assert_eq!(first(&vault), a);
+
+        // Indexing again reads the older modification time of the file.
+        vault.app.index().unwrap();
+        assert_eq!(vault.app.file(&a, true).unwrap().info.updated_at, Some(bumped));
+        assert_eq!(first(&vault), a);
+    }
+
+    #[test]
+    fn trash_purge_removes_expired_files() {
+        let vault = TestApp::with_config(Config {
+            trash_retention_days: Some(30),
+            ..Config::default()
+        });
+        vault.write("old.txt", b"old");
+        vault.write("new.txt", b"new");
+        vault.app.index().unwrap();
+        let old = vault.hash("old.txt");
+        let new = vault.hash("new.txt");
+        vault.app.file_delete(&old).unwrap();
+        vault.app.file_delete(&new).unwrap();
+
+        {
+            let con = vault.app.db.get().unwrap();
+            db::Db::new(&con)
+                .file_trash(&old, &(now() - chrono::Duration::days(40)))
+                .unwrap();
+        }
+
+        let report = vault.app.trash_purge().unwrap();
+        assert_eq!(report.purged, vec![old.clone()]);
+        assert!(!vault.full_path(".trash/old.txt").exists());
+        assert!(vault.full_path(".trash/new.txt").exists());
+
+        let trash = vault.app.trash().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].info.hash, new);
+        assert!(vault.app.file(&old, true).is_err());
+    }
+
+    #[test]
+    fn trash_purge_without_retention_keeps_files() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.txt");
+        vault.app.file_delete(&hash).unwrap();
+
+        assert!(vault.app.trash_purge().unwrap().purged.is_empty());
+        assert_eq!(vault.app.trash().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn files_missing_thumbnails_lists_uncached_images() {
+        let vault = TestApp::new();
+        for path in &["a.png", "b.png"] {
+            image::RgbImage::new(4, 4).save(vault.full_path(path)).unwrap();
+        }
+        vault.write("c.txt", b"c");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.png");
+        let b = vault.hash("b.png");
+
+        let missing = |size| -> Vec<String> {
+            vault.app.files_missing_thumbnails(size).unwrap()
+                .into_iter()
+                .map(|file| file.info.hash)
+                .collect()
+        };
+        assert_eq!(missing(None), vec![a.clone(), b.clone()]);
+
+        vault.app.thumbnail(&a, None, thumbs::ThumbFormat::Jpeg, true).unwrap().unwrap();
+        assert_eq!(missing(None), vec![b.clone()]);
+        // Thumbnails are cached per size.
+        assert_eq!(missing(Some(64)), vec![a, b]);
+    }
+
+    #[test]
+    fn config_requires_a_token_for_public_addresses() {
+        assert!(Config::default().validate().is_ok());
+
+        let public = Config {
+            bind_address: IpAddr::from([0, 0, 0, 0]),
+            ..Config::default()
+        };
+        assert!(public.validate().is_err());
+        assert!(App::new(public.clone()).is_err());
+
+        let with_token = Config {
+            auth_token: Some("secret".to_string()),
+            ..public
+        };
+        assert!(with_token.validate().is_ok());
+    }
+
+    #[test]
+    fn rebuild_index_keeps_trash_and_edit_timestamps() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.write("c.txt", b"c");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.txt");
+        let b = vault.hash("b.txt");
+        let c = vault.hash("c.txt");
+
+        vault.app.file_delete(&a).unwrap();
+        let edited = vault.app.file_update(t::FileUpdate {
+            title: Some("B".to_string()),
+            ..update(&b)
+        }).unwrap();
+        fs::remove_file(vault.full_path("c.txt")).unwrap();
+
+        let report = vault.app.rebuild_index().unwrap();
+        assert_eq!(report.removed, vec![c.clone()]);
+        assert!(vault.app.file(&c, true).is_err());
+        assert_eq!(
+            vault.app.file(&b, true).unwrap().info.updated_at,
+            edited.info.updated_at,
+        );
+
+        let trash = vault.app.trash().unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].info.hash, a);
+        let restored = vault.app.file_restore(&a).unwrap();
+        assert_eq!(restored.path, "a.txt");
+        assert_eq!(vault.total(), 2);
+    }
+
+    #[test]
+    fn file_move_takes_subtitles_and_metadata_along() {
+        let vault = TestApp::new();
+        vault.write("clip.mp4", MP4);
+        vault.write("clip.en.vtt", b"WEBVTT\n");
+        vault.app.index().unwrap();
+        let hash = vault.hash("clip.mp4");
+        vault.app.file_update(t::FileUpdate {
+            title: Some("Clip".to_string()),
+            ..update(&hash)
+        }).unwrap();
+
+        let moved = vault.app.file_move("clip.mp4", "videos/film.mp4").unwrap();
+        assert_eq!(moved.path, "videos/film.mp4");
+        assert_eq!(moved.meta.title, Some("Clip".to_string()));
+        assert_eq!(moved.info.subtitles.len(), 1);
+        assert_eq!(moved.info.subtitles[0].path, "videos/film.en.vtt");
+
+        assert!(vault.full_path("videos/film.en.vtt").exists());
+        assert!(vault.full_path("videos/film.mp4.meta.yaml").exists());
+        assert!(!vault.full_path("clip.en.vtt").exists());
+        assert!(!vault.full_path("clip.mp4.meta.yaml").exists());
+
+        // The next index run finds the same state.
+        vault.app.index().unwrap();
+        let file = vault.app.file(&hash, true).unwrap();
+        assert_eq!(file.path, "videos/film.mp4");
+        assert_eq!(file.meta.title, Some("Clip".to_string()));
+        assert_eq!(file.info.subtitles[0].path, "videos/film.en.vtt");
+    }
+
+    fn tags_of(vault: &TestApp, hash: &str) -> Vec<String> {
+        let mut tags = vault.app.file(hash, true).unwrap().meta.tags;
+        tags.sort();
+        tags
+    }
+
+    #[test]
+    fn tag_rename_to_a_name_with_a_quote() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.txt");
+        let b = vault.hash("b.txt");
+        for hash in &[&a, &b] {
+            vault.app.file_update(t::FileUpdate {
+                tags: Some(vec!["dog".to_string(), "cat".to_string()]),
+                ..update(hash)
+            }).unwrap();
+        }
+
+        let report = vault.app.tag_rename(t::TagRename {
+            from: "dog".to_string(),
+            to: "o'dog".to_string(),
+        }).unwrap();
+        assert_eq!(report.updated.len(), 2);
+        assert_eq!(tags_of(&vault, &a), vec!["cat".to_string(), "o'dog".to_string()]);
+
+        // Sidecars and the index agree, so indexing again changes nothing.
+        vault.app.index().unwrap();
+        assert_eq!(tags_of(&vault, &a), vec!["cat".to_string(), "o'dog".to_string()]);
+        assert_eq!(tags_of(&vault, &b), vec!["cat".to_string(), "o'dog".to_string()]);
+    }
+
+    #[test]
+    fn tag_merge_with_quotes() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.txt");
+        let b = vault.hash("b.txt");
+        vault.app.file_update(t::FileUpdate {
+            tags: Some(vec!["rock 'n' roll".to_string(), "live".to_string()]),
+            ..update(&a)
+        }).unwrap();
+        vault.app.file_update(t::FileUpdate {
+            tags: Some(vec!["rock'n'roll".to_string()]),
+            ..update(&b)
+        }).unwrap();
+
+        let report = vault.app.tag_merge(t::TagMerge {
+            sources: vec!["rock 'n' roll".to_string(), "rock'n'roll".to_string()],
+            target: "'rock'".to_string(),
+        }).unwrap();
+        assert_eq!(report.updated.len(), 2);
+
+        vault.app.index().unwrap();
+        assert_eq!(tags_of(&vault, &a), vec!["'rock'".to_string(), "live".to_string()]);
+        assert_eq!(tags_of(&vault, &b), vec!["'rock'".to_string()]);
+    }
+
+    #[test]
+    fn access_log_that_cannot_be_opened_fails_startup() {
+        let vault = TestApp::new();
+        let config = Config {
+            access_log_path: Some(vault.dir.join("missing").join("access.log").to_string_lossy().into_owned()),
+            ..vault.app.config.clone()
+        };
+        let err = App::new(config).err().unwrap();
+        assert!(err.to_string().contains("access log"));
+    }
+
+    #[test]
+    fn index_more_files_than_the_queue_size() {
+        let vault = TestApp::with_config(Config {
+            index_concurrency: Some(2),
+            index_queue_size: Some(1),
+            ..Config::default()
+        });
+        for i in 0..10 {
+            vault.write(&format!("{}.txt", i), i.to_string().as_bytes());
+        }
+
+        vault.app.index().unwrap();
+        assert_eq!(vault.total(), 10);
+    }
+
+    #[test]
+    fn importers_are_listed_with_their_declarations() {
+        let vault = TestApp::new();
+        vault.write("scripts/gallery.importer.js", b"// @name Gallery\n// @match https://example.com/*\n\nfunction run() {}\n");
+        vault.write("scripts/notes.txt", b"not an importer");
+
+        let importers = vault.app.importers().unwrap();
+        assert_eq!(importers.len(), 1);
+        assert_eq!(importers[0].path, "scripts/gallery.importer.js");
+        assert_eq!(importers[0].name, Some("Gallery".to_string()));
+        assert_eq!(importers[0].matches, vec!["https://example.com/*".to_string()]);
+    }
+
+    #[test]
+    fn featured_files_are_ordered_by_weight() {
+        let vault = TestApp::new();
+        for name in &["a", "b", "c"] {
+            vault.write(&format!("{}.txt", name), name.as_bytes());
+        }
+        vault.app.index().unwrap();
+        let (a, b) = (vault.hash("a.txt"), vault.hash("b.txt"));
+
+        vault.app.file_update(t::FileUpdate { featured_weight: Some(2), ..update(&a) }).unwrap();
+        vault.app.file_update(t::FileUpdate {
+            featured_weight: Some(1),
+            visibility: Some(t::Visibility::Private),
+            ..update(&b)
+        }).unwrap();
+
+        let featured = |authed: bool| -> Vec<String> {
+            vault.app.files_featured(authed).unwrap().into_iter().map(|f| f.info.hash).collect()
+        };
+        assert_eq!(featured(true), vec![b.clone(), a.clone()]);
+        // Private files are only featured for authenticated requests.
+        assert_eq!(featured(false), vec![a.clone()]);
+    }
+
+    #[test]
+    fn svgs_are_their_own_thumbnail() {
+        let vault = TestApp::new();
+        let svg = b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+        vault.write("logo.svg", svg);
+        vault.app.index().unwrap();
+        let hash = vault.hash("logo.svg");
+
+        let file = vault.app.file(&hash, true).unwrap();
+        assert_eq!(file.info.mime, Some("image/svg+xml".to_string()));
+        assert_eq!(file.info.kind, t::FileKind::Image);
+
+        let thumb = vault.app.thumbnail(&hash, None, thumbs::ThumbFormat::Jpeg, true).unwrap().unwrap();
+        assert_eq!(thumb.mime, Some("image/svg+xml".to_string()));
+        assert_eq!(thumb.content.len(), svg.len() as u64);
+        assert!(vault.app.files_missing_thumbnails(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn storage_info_of_an_unindexed_file() {
+        let vault = TestApp::new();
+        vault.write("new.txt", b"hello");
+
+        let info = vault.app.storage_info("new.txt").unwrap();
+        assert_eq!(info.size, 5);
+        assert_eq!(info.kind, t::FileKind::Other);
+        // Nothing is written to the database.
+        assert_eq!(vault.total(), 0);
+
+        vault.app.index().unwrap();
+        assert_eq!(info.hash, vault.hash("new.txt"));
+
+        assert!(vault.app.storage_info("../new.txt").is_err());
+        assert!(vault.app.storage_info("missing.txt").is_err());
+    }
+
+    #[test]
+    fn sort_direction_defaults_apply_without_a_direction() {
+        let by_size = |vault: &TestApp| -> Vec<String> {
+            vault.write("small.txt", b"a");
+            vault.write("large.txt", b"aaaa");
+            vault.app.index().unwrap();
+            let query = FileQuery {
+                sort: vec![FileSortItem { sort: FileSort::Size, ascending: None }],
+                ..FileQuery::default()
+            };
+            vault.app.files(query, true, false).unwrap().items.into_iter().map(|f| f.path).collect()
+        };
+
+        // Sizes sort descending by default.
+        let vault = TestApp::new();
+        assert_eq!(by_size(&vault), vec!["large.txt", "small.txt"]);
+
+        let mut sort_ascending = HashMap::new();
+        sort_ascending.insert(FileSort::Size, true);
+        let vault = TestApp::with_config(Config { sort_ascending, ..Config::default() });
+        assert_eq!(by_size(&vault), vec!["small.txt", "large.txt"]);
+    }
+
+    #[test]
+    fn import_backfill_adds_sources_by_hash() {
+        let vault = TestApp::new();
+        vault.write("a.jpg", b"a");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.jpg");
+
+        let source = t::FileSource {
+            url: "https://example.com/a.jpg".to_string(),
+            page_url: None,
+            title: Some("A".to_string()),
+            description: None,
+            tags: Vec::new(),
+            uploader: None,
+            created_at: None,
+            extra: None,
+        };
+        let backfill = t::ImportBackfill {
+            items: vec![
+                t::ImportBackfillItem { hash: hash.clone(), sources: vec![source.clone()] },
+                t::ImportBackfillItem { hash: "missing".to_string(), sources: vec![source.clone()] },
+            ],
+        };
+        let report = vault.app.import_backfill(backfill.clone()).unwrap();
+        assert_eq!(report.updated, vec![hash.clone()]);
+        assert_eq!(report.missing, vec!["missing".to_string()]);
+
+        let sources = vault.app.file(&hash, true).unwrap().meta.sources;
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].url, source.url);
+
+        // Known urls are not added again.
+        let report = vault.app.import_backfill(backfill).unwrap();
+        assert!(report.updated.is_empty());
+        assert_eq!(vault.app.file(&hash, true).unwrap().meta.sources.len(), 1);
+    }
+
+    #[test]
+    fn file_patch_clears_only_given_fields() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.txt");
+        vault.app.file_update(t::FileUpdate {
+            title: Some("Title".to_string()),
+            description: Some("Description".to_string()),
+            tags: Some(vec!["cat".to_string()]),
+            ..update(&hash)
+        }).unwrap();
+
+        let patch = |json: &str| {
+            let patch: t::FilePatch = serde_json::from_str(json).unwrap();
+            vault.app.file_patch(patch).unwrap();
+            vault.app.file(&hash, true).unwrap().meta
+        };
+
+        // Absent fields are kept.
+        let meta = patch(&format!(r#"{{"hash": "{}", "tags": []}}"#, hash));
+        assert!(meta.tags.is_empty());
+        assert_eq!(meta.title, Some("Title".to_string()));
+        assert_eq!(meta.description, Some("Description".to_string()));
+
+        let meta = patch(&format!(r#"{{"hash": "{}", "description": null}}"#, hash));
+        assert_eq!(meta.title, Some("Title".to_string()));
+        assert_eq!(meta.description, None);
+    }
+
+    #[test]
+    fn deep_filters_are_rejected() {
+        let vault = TestApp::with_config(Config {
+            max_filter_depth: Some(3),
+            ..Config::default()
+        });
+        let nested = |depth: usize| {
+            let mut filter = FileFilter::Untagged;
+            for _ in 1..depth {
+                filter = FileFilter::Not(Box::new(filter));
+            }
+            FileQuery { filter: Some(filter), ..FileQuery::default() }
+        };
+
+        assert!(vault.app.files(nested(3), true, false).is_ok());
+        let err = vault.app.files(nested(4), true, false).err().unwrap();
+        match err.downcast_ref::<AppError>() {
+            Some(AppError::BadRequest(_)) => {}
+            other => panic!("expected a bad request, got {:?}", other),
+        }
+        // Unauthenticated requests are checked before the visibility filter is added.
+        assert!(vault.app.files(nested(3), false, false).is_ok());
+        assert!(TestApp::new().app.files(nested(DEFAULT_MAX_FILTER_DEPTH + 1), true, false).is_err());
+    }
+
+    #[test]
+    fn notes_round_trip_and_are_hidden_from_anonymous_requests() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.txt");
+
+        vault.app.file_update(t::FileUpdate {
+            notes: Some("call back".to_string()),
+            ..update(&hash)
+        }).unwrap();
+
+        let notes = Some("call back".to_string());
+        assert_eq!(vault.app.file(&hash, true).unwrap().meta.notes, notes);
+        assert_eq!(vault.app.storage.file_meta("a.txt").unwrap().notes, notes);
+        assert_eq!(vault.app.file(&hash, false).unwrap().meta.notes, None);
+        let page = vault.app.files(FileQuery::default(), false, false).unwrap();
+        assert_eq!(page.items[0].meta.notes, None);
+
+        // The sidecar is the source of truth.
+        vault.app.rebuild_index().unwrap();
+        assert_eq!(vault.app.file(&hash, true).unwrap().meta.notes, notes);
+    }
+
+    #[test]
+    fn media_verified_detects_changed_files() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"hello");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.txt");
+
+        let media = vault.app.media_verified("a.txt", true).unwrap().unwrap();
+        assert_eq!(media.hash.map(|(_, hash)| hash), Some(hash));
+
+        // Changed on disk without a reindex.
+        vault.write("a.txt", b"hellO");
+        let err = vault.app.media_verified("a.txt", true).err().unwrap();
+        match err.downcast_ref::<AppError>() {
+            Some(AppError::Internal(_)) => {}
+            other => panic!("expected an internal error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tags_from_filename_preview_and_apply() {
+        let vault = TestApp::new();
+        vault.write("holiday_2018-beach 01.jpg", b"a");
+        vault.write("cat.txt", b"b");
+        vault.write("x1.txt", b"c");
+        vault.app.index().unwrap();
+        let cat = vault.hash("cat.txt");
+        vault.app.file_update(t::FileUpdate { tags: Some(vec!["cat".to_string()]), ..update(&cat) }).unwrap();
+        let holiday = vault.hash("holiday_2018-beach 01.jpg");
+
+        let preview = vault.app.tags_from_filename(true).unwrap();
+        assert!(!preview.applied);
+        assert_eq!(preview.files.len(), 1);
+        assert_eq!(preview.files[0].path, "holiday_2018-beach 01.jpg");
+        assert_eq!(preview.files[0].tags, vec!["holiday".to_string(), "beach".to_string()]);
+        assert!(tags_of(&vault, &holiday).is_empty());
+
+        let applied = vault.app.tags_from_filename(false).unwrap();
+        assert!(applied.applied);
+        assert_eq!(applied.files.len(), 1);
+        assert_eq!(tags_of(&vault, &holiday), vec!["beach".to_string(), "holiday".to_string()]);
+
+        assert!(vault.app.tags_from_filename(true).unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn files_by_hashes_keep_the_requested_order() {
+        let vault = TestApp::new();
+        for name in &["a", "b", "c"] {
+            vault.write(&format!("{}.txt", name), name.as_bytes());
+        }
+        vault.app.index().unwrap();
+        let (a, b, c) = (vault.hash("a.txt"), vault.hash("b.txt"), vault.hash("c.txt"));
+        vault.app.file_update(t::FileUpdate { visibility: Some(t::Visibility::Private), ..update(&b) }).unwrap();
+
+        let request = t::FilesByHashes {
+            hashes: vec![c.clone(), "missing".to_string(), b.clone(), a.clone(), c.clone()],
+        };
+        let by_hashes = |authed: bool| {
+            let result = vault.app.files_by_hashes(request.clone(), authed).unwrap();
+            let items = result.items.into_iter().map(|f| f.info.hash).collect::<Vec<_>>();
+            (items, result.missing)
+        };
+
+        assert_eq!(by_hashes(true), (vec![c.clone(), b.clone(), a.clone()], vec!["missing".to_string()]));
+        // Private files are missing for anonymous requests.
+        assert_eq!(by_hashes(false), (vec![c.clone(), a.clone()], vec!["missing".to_string(), b.clone()]));
+    }
+
+    /// Returns a single source for the html `known`, and no match otherwise.
+    struct MockEngine;
+
+    impl importer::Engine for MockEngine {
+        fn run(&self, _importer: &storage::Importer, input: &importer::ImporterInput) -> Result<t::ImporterOutput, Error> {
+            if input.html != "known" {
+                return Ok(t::ImporterOutput::NoMatch);
+            }
+            Ok(t::ImporterOutput::Ok(vec![t::ImporterItem::File(t::FileSource {
+                url: "https://example.com/a.jpg".to_string(),
+                page_url: None,
+                title: None,
+                description: None,
+                tags: Vec::new(),
+                uploader: None,
+                created_at: None,
+                extra: None,
+            })]))
+        }
+    }
+
+    #[test]
+    fn import_runs_the_engine() {
+        let mut vault = TestApp::new();
+        vault.app.importer_engine = Arc::new(MockEngine);
+        vault.write("a.importer.js", b"// @match https://example.com/*\n");
+
+        match vault.app.import("a.importer.js", "known").unwrap() {
+            t::ImporterOutput::Ok(ref items) => assert_eq!(items.len(), 1),
+            other => panic!("expected items, got {:?}", other),
+        }
+        match vault.app.import("a.importer.js", "unknown").unwrap() {
+            t::ImporterOutput::NoMatch => {}
+            other => panic!("expected no match, got {:?}", other),
+        }
+        // Urls the importer doesn't declare are not fetched.
+        match vault.app.import("a.importer.js", "https://other.com/").unwrap() {
+            t::ImporterOutput::NoMatch => {}
+            other => panic!("expected no match, got {:?}", other),
+        }
+        assert!(vault.app.import("a.txt", "known").is_err());
+    }
+
+    #[test]
+    fn reindex_removes_files_deleted_from_disk() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b/c.txt", b"c");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.txt");
+        assert_eq!(vault.total(), 2);
+
+        fs::remove_file(vault.full_path("b/c.txt")).unwrap();
+        let report = vault.app.reindex().unwrap();
+
+        assert_eq!(report.removed, vec!["b/c.txt".to_string()]);
+        assert!(report.added.is_empty());
+        assert_eq!(vault.total(), 1);
+        assert_eq!(vault.hash("a.txt"), a);
+        let con = vault.app.db.get().unwrap();
+        assert!(db::Db::new(&con).file_hash_by_path("b/c.txt").unwrap().is_none());
+    }
+
+    #[test]
+    fn watcher_indexes_created_and_removed_files() {
+        let vault = TestApp::with_config(Config {
+            watch: true,
+            ..Config::default()
+        });
+        let wait_for_total = |total: u32| {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(20);
+            while vault.total() != total && std::time::Instant::now() < deadline {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            vault.total()
+        };
+
+        vault.write("a/b.txt", b"b");
+        assert_eq!(wait_for_total(1), 1);
+        assert!(!vault.hash("a/b.txt").is_empty());
+
+        fs::remove_file(vault.full_path("a/b.txt")).unwrap();
+        assert_eq!(wait_for_total(0), 0);
+    }
+
+    #[test]
+    fn config_load_from_file_env_and_defaults() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mediavault.toml");
+        fs::write(&path, "db_path = \"file.sqlite3\"\nport = 9000\n\n[[storage_roots]]\nid = \"\"\npath = \"media\"\n").unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let from_file = Config::load(Some(&path));
+        std::env::set_var("MEDIAVAULT_DB_PATH", "env.sqlite3");
+        std::env::set_var("MEDIAVAULT_STORAGE_PATH", "env-media");
+        let overridden = Config::load(Some(&path));
+        std::env::set_var("MEDIAVAULT_PORT", "not a port");
+        let invalid = Config::load(Some(&path));
+        for name in &["MEDIAVAULT_DB_PATH", "MEDIAVAULT_STORAGE_PATH", "MEDIAVAULT_PORT"] {
+            std::env::remove_var(name);
+        }
+        let missing = Config::load(Some(&dir.join("missing.toml").to_string_lossy()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let from_file = from_file.unwrap();
+        assert_eq!(from_file.db_path, "file.sqlite3");
+        assert_eq!(from_file.port, 9000);
+        assert_eq!(from_file.storage_roots.len(), 1);
+        assert_eq!(from_file.storage_roots[0].path, "media");
+        // Settings missing from the file keep their defaults.
+        assert_eq!(from_file.bind_address, Config::default().bind_address);
+        assert!(from_file.trash);
+
+        let overridden = overridden.unwrap();
+        assert_eq!(overridden.db_path, "env.sqlite3");
+        assert_eq!(overridden.storage_roots[0].path, "env-media");
+        assert_eq!(overridden.port, 9000);
+        assert!(invalid.is_err());
+        assert!(missing.is_err());
+
+        if !Path::new(Config::DEFAULT_PATH).exists() {
+            let default = Config::load(None).unwrap();
+            assert_eq!(default.db_path, Config::default().db_path);
+            assert_eq!(default.port, Config::default().port);
+        }
+    }
+
+    #[test]
+    fn files_update_applies_a_batch_atomically() {
+        let vault = TestApp::new();
+        for path in &["a.txt", "b.txt", "c.txt"] {
+            vault.write(path, path.as_bytes());
+        }
+        vault.app.index().unwrap();
+        let hashes = ["a.txt", "b.txt", "c.txt"].iter().map(|path| vault.hash(path)).collect::<Vec<_>>();
+
+        let updates = hashes.iter().enumerate().map(|(index, hash)| t::FileUpdate {
+            title: Some(format!("title {}", index)),
+            tags: Some(vec!["batch".to_string()]),
+            ..update(hash)
+        }).collect();
+        let files = vault.app.files_update(updates).unwrap();
+        assert_eq!(files.len(), 3);
+        for (index, (hash, path)) in hashes.iter().zip(&["a.txt", "b.txt", "c.txt"]).enumerate() {
+            let file = vault.app.file(hash, true).unwrap();
+            assert_eq!(file.meta.title, Some(format!("title {}", index)));
+            assert_eq!(tags_of(&vault, hash), vec!["batch".to_string()]);
+            assert_eq!(vault.app.storage.file_meta(path).unwrap().title, file.meta.title);
+        }
+
+        // An unknown file fails the whole batch and names its index.
+        let err = vault.app.files_update(vec![
+            t::FileUpdate { title: Some("changed".to_string()), ..update(&hashes[0]) },
+            update("unknown"),
+            t::FileUpdate { title: Some("changed".to_string()), ..update(&hashes[2]) },
+        ]).unwrap_err();
+        assert!(err.to_string().contains("update 1"));
+        match err.downcast_ref::<AppError>() {
+            Some(AppError::NotFound(_)) => {}
+            other => panic!("expected not found, got {:?}", other),
+        }
+        for (index, (hash, path)) in hashes.iter().zip(&["a.txt", "b.txt", "c.txt"]).enumerate() {
+            let title = Some(format!("title {}", index));
+            assert_eq!(vault.app.file(hash, true).unwrap().meta.title, title);
+            assert_eq!(vault.app.storage.file_meta(path).unwrap().title, title);
+        }
+    }
+
+    #[test]
+    fn file_move_keeps_the_hash_and_refuses_to_overwrite() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.txt");
+
+        let moved = vault.app.file_move("a.txt", "docs/a.txt").unwrap();
+        assert_eq!(moved.info.hash, hash);
+        assert_eq!(vault.hash("docs/a.txt"), hash);
+        assert_eq!(vault.app.file(&hash, true).unwrap().path, "docs/a.txt");
+        assert_eq!(fs::read(vault.full_path("docs/a.txt")).unwrap(), b"a");
+        assert!(!vault.full_path("a.txt").exists());
+        assert_eq!(vault.total(), 2);
+
+        match vault.app.file_move("docs/a.txt", "b.txt").unwrap_err().downcast_ref::<AppError>() {
+            Some(AppError::Conflict(_)) => {}
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(fs::read(vault.full_path("b.txt")).unwrap(), b"b");
+        assert_eq!(vault.app.file(&hash, true).unwrap().path, "docs/a.txt");
+    }
+
+    #[test]
+    fn file_update_replaces_or_keeps_sources() {
+        let vault = TestApp::new();
+        vault.write("a.jpg", b"a");
+        vault.app.index().unwrap();
+        let hash = vault.hash("a.jpg");
+        let source = |url: &str| t::FileSource {
+            url: url.to_string(),
+            page_url: None,
+            title: None,
+            description: None,
+            tags: Vec::new(),
+            uploader: None,
+            created_at: None,
+            extra: None,
+        };
+        let urls = |sources: Vec<t::FileSource>| sources.into_iter().map(|s| s.url).collect::<Vec<_>>();
+
+        vault.app.file_update(t::FileUpdate {
+            sources: Some(vec![source("https://a.com/1"), source("https://b.com/2")]),
+            ..update(&hash)
+        }).unwrap();
+        assert_eq!(urls(vault.app.file(&hash, true).unwrap().meta.sources), vec!["https://a.com/1", "https://b.com/2"]);
+        assert_eq!(urls(vault.app.storage.file_meta("a.jpg").unwrap().sources), vec!["https://a.com/1", "https://b.com/2"]);
+
+        vault.app.file_update(t::FileUpdate {
+            title: Some("A".to_string()),
+            ..update(&hash)
+        }).unwrap();
+        assert_eq!(urls(vault.app.file(&hash, true).unwrap().meta.sources), vec!["https://a.com/1", "https://b.com/2"]);
+
+        vault.app.file_update(t::FileUpdate {
+            sources: Some(vec![source("https://c.com/3")]),
+            ..update(&hash)
+        }).unwrap();
+        assert_eq!(urls(vault.app.file(&hash, true).unwrap().meta.sources), vec!["https://c.com/3"]);
+
+        vault.app.file_update(t::FileUpdate {
+            sources: Some(Vec::new()),
+            ..update(&hash)
+        }).unwrap();
+        assert!(vault.app.file(&hash, true).unwrap().meta.sources.is_empty());
+    }
+
+    #[test]
+    fn files_count_matches_the_query_total() {
+        let vault = TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.write("c.png", b"c");
+        vault.app.index().unwrap();
+        let a = vault.hash("a.txt");
+        vault.app.file_update(t::FileUpdate {
+            tags: Some(vec!["x".to_string()]),
+            visibility: Some(t::Visibility::Private),
+            ..update(&a)
+        }).unwrap();
+
+        let filters = vec![
+            None,
+            Some(FileFilter::Tag("x".to_string())),
+            Some(FileFilter::Untagged),
+            Some(FileFilter::Kinds(Vec::new())),
+        ];
+        for filter in filters {
+            for authed in &[true, false] {
+                let query = FileQuery {
+                    filter: filter.clone(),
+                    ..FileQuery::default()
+                };
+                let total = vault.app.files(query, *authed, false).unwrap().total;
+                assert_eq!(vault.app.files_count(filter.clone(), *authed).unwrap(), total, "{:?} {}", filter, authed);
+            }
+        }
+        assert_eq!(vault.app.files_count(None, true).unwrap(), 3);
+        assert_eq!(vault.app.files_count(None, false).unwrap(), 2);
+    }
 }
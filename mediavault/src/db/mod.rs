@@ -1,11 +1,11 @@
 use serde_derive::{Serialize, Deserialize};
-use failure::format_err;
 use r2d2_sqlite::SqliteConnectionManager as Manager;
-use rusqlite::{Error as DbError, types::ToSql};
+use rusqlite::{Error as DbError, NO_PARAMS, types::{ToSql, Value}};
 use mediavault_common::{
     types as t,
     types::{FileQuery, FileFilter},
 };
+use std::collections::HashSet;
 use crate::{prelude::*, storage as st};
 
 pub type Connection = rusqlite::Connection;
@@ -23,7 +23,7 @@ impl r2d2::CustomizeConnection<Connection, DbError> for Customizer {
 }
 
 pub fn build_pool(path: &str) -> Result<Pool, r2d2::Error> {
-    let manager = Manager::file("db.sqlite3");
+    let manager = Manager::file(path);
     let pool = Pool::builder()
         .connection_customizer(Box::new(Customizer))
         .build(manager)?;
@@ -40,19 +40,109 @@ pub struct Db<'a> {
     connection: &'a Connection,
 }
 
+/// Media files that lack the dimensions or length expected for their kind,
+/// e.g. because probing them failed.
+const MISSING_MEDIA_INFO: &str = " ((files.kind = 'image' AND (files.width IS NULL OR files.height IS NULL))
+    OR (files.kind = 'video' AND (files.width IS NULL OR files.height IS NULL OR files.length IS NULL))
+    OR (files.kind = 'audio' AND files.length IS NULL)) ";
+
 impl<'a> Db<'a> {
     pub fn new(connection: &Connection) -> Db {
         Db { connection }
     }
 
 
+    /// Build the WHERE condition for a filter on the `files` table.
+    ///
+    /// Columns are qualified, since the query may join other tables. Filters
+    /// on data in other tables (tags, full text index) use subqueries that
+    /// select matching file hashes.
     fn file_filter_apply<'f>(filter: &'f FileFilter) -> (String, Vec<Box<dyn ToSql>>) {
         match filter {
             FileFilter::Tag(ref t) => {
-                (" tag = ? ".to_string(), vec![Box::new(t.to_string())])
+                (" files.hash IN (SELECT file_hash FROM files_tags WHERE tag = ?) ".to_string(), vec![Box::new(t.to_string())])
+            },
+            FileFilter::MissingMediaInfo => (MISSING_MEDIA_INFO.to_string(), vec![]),
+            FileFilter::Untagged => {
+                (" files.hash NOT IN (SELECT file_hash FROM files_tags) ".to_string(), vec![])
             },
             FileFilter::Kind(ref kind) => {
-                (" kind = ?".to_string(), vec![Box::new(&*kind.to_str())])
+                (" files.kind = ?".to_string(), vec![Box::new(&*kind.to_str())])
+            },
+            FileFilter::Kinds(ref kinds) => {
+                if kinds.is_empty() {
+                    (" 0=1 ".to_string(), vec![])
+                } else {
+                    let placeholders = vec!["?"; kinds.len()].join(", ");
+                    let params = kinds
+                        .iter()
+                        .map(|kind| Box::new(kind.to_str()) as Box<dyn ToSql>)
+                        .collect();
+                    (format!(" files.kind IN ({}) ", placeholders), params)
+                }
+            },
+            FileFilter::Mime(ref mime) => {
+                if mime.ends_with('*') {
+                    let pattern = format!("{}%", Self::like_escape(mime.trim_end_matches('*')));
+                    (" files.mime LIKE ? ESCAPE '\\' ".to_string(), vec![Box::new(pattern)])
+                } else {
+                    (" files.mime = ? ".to_string(), vec![Box::new(mime.to_string())])
+                }
+            },
+            FileFilter::Visibility(ref visibility) => {
+                (" files.visibility = ?".to_string(), vec![Box::new(&*visibility.to_str())])
+            },
+            FileFilter::BoundingBox { min_lat, max_lat, min_lon, max_lon } => {
+                (
+                    " (files.lat BETWEEN ? AND ? AND files.lon BETWEEN ? AND ?) ".to_string(),
+                    vec![Box::new(*min_lat), Box::new(*max_lat), Box::new(*min_lon), Box::new(*max_lon)],
+                )
+            },
+            FileFilter::SizeRange { min, max } => {
+                let mut clauses = Vec::new();
+                let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+                if let Some(min) = min {
+                    clauses.push("files.size >= ?");
+                    params.push(Box::new(*min));
+                }
+                if let Some(max) = max {
+                    clauses.push("files.size <= ?");
+                    params.push(Box::new(*max));
+                }
+                if clauses.is_empty() {
+                    (" 1 ".to_string(), params)
+                } else {
+                    (format!(" ({}) ", clauses.join(" AND ")), params)
+                }
+            },
+            FileFilter::Dimension { min_width, max_width, min_height, max_height } => {
+                let bounds = [
+                    ("files.width >= ?", min_width),
+                    ("files.width <= ?", max_width),
+                    ("files.height >= ?", min_height),
+                    ("files.height <= ?", max_height),
+                ];
+                let mut clauses = Vec::new();
+                let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+                for (clause, bound) in bounds.iter() {
+                    if let Some(bound) = bound {
+                        clauses.push(*clause);
+                        params.push(Box::new(*bound));
+                    }
+                }
+                if clauses.is_empty() {
+                    (" 1 ".to_string(), params)
+                } else {
+                    (format!(" ({}) ", clauses.join(" AND ")), params)
+                }
+            },
+            FileFilter::Text(ref text) => {
+                match Self::fts_query(text) {
+                    Some(q) => {
+                        (" files.hash IN (SELECT hash FROM files_fts WHERE files_fts MATCH ?) ".to_string(), vec![Box::new(q)])
+                    }
+                    None => (" 1 ".to_string(), vec![]),
+                }
             },
             FileFilter::And(ref left, ref right) => {
                 let (q1, mut p1) = Self::file_filter_apply(left);
@@ -68,12 +158,79 @@ impl<'a> Db<'a> {
                 p1.extend(p2.into_iter());
                 (q, p1)
             },
+            FileFilter::Not(ref inner) => {
+                // Conditions on NULL columns (e.g. a missing location) are
+                // NULL, which should count as not matching.
+                let (q, p) = Self::file_filter_apply(inner);
+                (format!(" (NOT COALESCE({}, 0)) ", q), p)
+            },
+        }
+    }
+
+    /// Escape the wildcards of a LIKE pattern, for use with `ESCAPE '\'`.
+    fn like_escape(value: &str) -> String {
+        value
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    }
+
+    /// Hashes as an array parameter for `rarray(?)`.
+    fn hash_array(hashes: &[String]) -> std::rc::Rc<Vec<Value>> {
+        std::rc::Rc::new(hashes.iter().map(|h| Value::from(h.clone())).collect())
+    }
+
+    /// Build a FTS5 query matching all words of the text.
+    ///
+    /// Every word is quoted, so user input can't use the query syntax.
+    fn fts_query(text: &str) -> Option<String> {
+        let words = text
+            .split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" "))
+        }
+    }
+
+    /// The text search that decides the ordering of results: a `Text` filter
+    /// or the leftmost operand of an `And`.
+    fn primary_text(filter: &FileFilter) -> Option<String> {
+        match filter {
+            FileFilter::Text(ref text) => Self::fts_query(text),
+            FileFilter::And(ref left, _) => Self::primary_text(left),
+            _ => None,
         }
     }
 
+    /// Add a column to an existing table if it is missing.
+    ///
+    /// `CREATE TABLE IF NOT EXISTS` leaves tables of older databases untouched,
+    /// so columns added later need to be created explicitly.
+    fn column_ensure(&self, table: &str, column: &str, definition: &str) -> Result<(), DbError> {
+        let columns = self.connection
+            .prepare(&format!("PRAGMA table_info({})", table))?
+            .query_and_then(NO_PARAMS, |row| row.get_checked::<_, String>("name"))?
+            .collect::<Result<Vec<_>, DbError>>()?;
+
+        if !columns.iter().any(|c| c == column) {
+            self.connection.execute_batch(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition)
+            )?;
+        }
+        Ok(())
+    }
+
     pub fn migrate(&self) -> Result<(), DbError> {
         self.connection.execute_batch(
             r#"
+            CREATE TABLE IF NOT EXISTS index_runs(
+                id TEXT NOT NULL PRIMARY KEY,
+                started_at DATETIME NOT NULL
+            );
+
             CREATE TABLE IF NOT EXISTS files(
                 hash TEXT NOT NULL PRIMARY KEY,
                 path TEXT NOT NULL UNIQUE,
@@ -88,7 +245,18 @@ impl<'a> Db<'a> {
 
                 width INTEGER,
                 height INTEGER,
-                length INTEGER
+                length INTEGER,
+
+                visibility TEXT NOT NULL DEFAULT 'public',
+                featured_weight INTEGER,
+
+                lat REAL,
+                lon REAL,
+
+                notes TEXT,
+
+                -- The index run that first added the file.
+                index_run TEXT REFERENCES index_runs (id)
             );
 
             CREATE TABLE IF NOT EXISTS files_tags(
@@ -97,6 +265,14 @@ impl<'a> Db<'a> {
                 UNIQUE (tag, file_hash)
             );
 
+            -- Every path a file was indexed at. files.path only holds the
+            -- most recent one, so copies of a file would go unnoticed.
+            CREATE TABLE IF NOT EXISTS file_paths(
+                path TEXT NOT NULL PRIMARY KEY,
+                file_hash TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS file_paths_hash ON file_paths (file_hash);
+
             CREATE TABLE IF NOT EXISTS galleries(
                 path TEXT NOT NULL PRIMARY KEY,
                 title TEXT NOT NULL,
@@ -109,8 +285,85 @@ impl<'a> Db<'a> {
                 weight INTEGER NOT NULL,
                 UNIQUE (gallery_path, file_hash)
             );
+
+            CREATE TABLE IF NOT EXISTS subtitles(
+                file_hash TEXT NOT NULL REFERENCES files (hash) ON DELETE CASCADE,
+                lang TEXT NOT NULL,
+                path TEXT NOT NULL,
+                UNIQUE (file_hash, lang)
+            );
         "#,
-        )
+        )?;
+
+        self.column_ensure("files", "visibility", "TEXT NOT NULL DEFAULT 'public'")?;
+        self.column_ensure("files", "featured_weight", "INTEGER")?;
+        self.column_ensure("files", "index_run", "TEXT REFERENCES index_runs (id)")?;
+        self.column_ensure("files", "lat", "REAL")?;
+        self.column_ensure("files", "lon", "REAL")?;
+        self.column_ensure("files", "notes", "TEXT")?;
+        // Set for files in the trash.
+        self.column_ensure("files", "deleted_at", "TEXT")?;
+
+        self.connection.execute_batch(
+            "INSERT OR IGNORE INTO file_paths (path, file_hash) SELECT path, hash FROM files",
+        )?;
+
+        // Full text index over titles and descriptions.
+        let fts_exists = self.connection.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'files_fts'",
+            NO_PARAMS,
+            |row| row.get::<_, i64>(0),
+        )? > 0;
+        if !fts_exists {
+            self.connection.execute_batch(
+                r#"
+                CREATE VIRTUAL TABLE files_fts USING fts5(hash UNINDEXED, title, description);
+                INSERT INTO files_fts (hash, title, description) SELECT hash, title, description FROM files;
+            "#,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Clear the tables that are derived from the storage, so the next index
+    /// run fills them from scratch.
+    ///
+    /// File rows are kept, since they hold what the storage can't tell: files
+    /// in the trash and edit timestamps. Tags and subtitles of trashed files
+    /// are kept for the same reason. Rows of files the index run doesn't find
+    /// again are removed by `files_unindexed`.
+    pub fn reset(&self) -> Result<(), DbError> {
+        self.connection.execute_batch(
+            r#"
+            BEGIN;
+            DELETE FROM file_paths;
+            DELETE FROM files_tags WHERE file_hash IN (SELECT hash FROM files WHERE deleted_at IS NULL);
+            DELETE FROM subtitles WHERE file_hash IN (SELECT hash FROM files WHERE deleted_at IS NULL);
+            DELETE FROM gallery_items;
+            DELETE FROM galleries;
+            DELETE FROM files_fts;
+            INSERT INTO files_fts (hash, title, description) SELECT hash, title, description FROM files;
+            COMMIT;
+        "#,
+        )?;
+        self.migrate()
+    }
+
+    /// Hashes of files outside the trash without any indexed path.
+    pub fn files_unindexed(&self) -> Result<Vec<String>, DbError> {
+        self.connection
+            .prepare_cached("
+                SELECT hash FROM files
+                WHERE deleted_at IS NULL AND hash NOT IN (SELECT file_hash FROM file_paths)
+                ORDER BY path
+            ")?
+            .query_and_then(NO_PARAMS, |row| row.get_checked::<_, String>(0))?
+            .collect()
+    }
+
+    pub fn optimize(&self) -> Result<(), DbError> {
+        self.connection.execute_batch("VACUUM; ANALYZE;")
     }
 
     fn file_tags(&self, hash: &str) -> Result<Vec<String>, DbError> {
@@ -151,19 +404,12 @@ impl<'a> Db<'a> {
     }
 
     fn file_tags_persist(&self, hash: &str, tags: Vec<String>) -> Result<(), DbError> {
-        // First, delete all stale tags.
-
-        let quoted_tags = tags
-            .iter()
-            .map(|t| format!("'{}'", t))
-            .collect::<Vec<_>>()
-            .join(",");
-
-        let q = format!(
-            "DELETE FROM files_tags WHERE file_hash = ? AND tag NOT IN ({})",
-            quoted_tags
-        );
-        self.connection.prepare_cached(&q)?.execute(&[&hash])?;
+        // First, delete all stale tags. Tags come from requests, so they are
+        // bound like any other value.
+        let keep = std::rc::Rc::new(tags.iter().map(|t| Value::from(t.clone())).collect::<Vec<_>>());
+        self.connection
+            .prepare_cached("DELETE FROM files_tags WHERE file_hash = ? AND tag NOT IN rarray(?)")?
+            .execute(&[&hash as &dyn ToSql, &keep])?;
 
         for tag in &tags {
             self.connection
@@ -174,9 +420,198 @@ impl<'a> Db<'a> {
         Ok(())
     }
 
+    /// Tags with the number of files using them, most used first.
+    ///
+    /// Without a limit, all tags are returned. Files in the trash, and with
+    /// `public_only` private files, are not counted.
+    pub fn tags(&self, limit: Option<u32>, public_only: bool) -> Result<Vec<(String, u32)>, Error> {
+        // A negative limit means no limit in SQLite.
+        let limit = limit.map(i64::from).unwrap_or(-1);
+        let q = format!(
+            r#"
+            SELECT files_tags.tag, COUNT(*) FROM files_tags
+            JOIN files ON files.hash = files_tags.file_hash
+            WHERE files.deleted_at IS NULL {}
+            GROUP BY files_tags.tag
+            ORDER BY COUNT(*) DESC, files_tags.tag
+            LIMIT ?
+            "#,
+            if public_only { "AND files.visibility = 'public'" } else { "" },
+        );
+        let tags = self.connection
+            .prepare_cached(&q)?
+            .query_and_then(&[&limit], |row| -> Result<(String, u32), DbError> {
+                Ok((row.get_checked(0)?, row.get_checked(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tags)
+    }
+
+    /// Run `f` in a transaction, which is rolled back if it fails.
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<DbError>,
+    {
+        self.connection.execute_batch("BEGIN")?;
+        match f() {
+            Ok(value) => {
+                self.connection.execute_batch("COMMIT")?;
+                Ok(value)
+            }
+            Err(e) => {
+                if let Err(rollback_err) = self.connection.execute_batch("ROLLBACK") {
+                    log::error!("Could not roll back transaction: {}", rollback_err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Rename a tag on all files, merging it into `new` if a file has both.
+    ///
+    /// Returns the hashes of the files that had the tag. Only the index is
+    /// changed, metadata files have to be updated separately.
+    pub fn tag_rename(&self, old: &str, new: &str) -> Result<Vec<String>, DbError> {
+        self.tag_merge(&[old.to_string()], new)
+    }
+
+    /// Replace the source tags with `target` on all files. Files end up with
+    /// `target` once, no matter how many of the tags they had.
+    ///
+    /// Returns the hashes of the files that had any of the source tags. Like
+    /// `tag_rename`, this only changes the index.
+    pub fn tag_merge(&self, sources: &[String], target: &str) -> Result<Vec<String>, DbError> {
+        self.transaction(|| {
+            let mut hashes = Vec::new();
+            for source in sources.iter().filter(|source| source.as_str() != target) {
+                let source_hashes = self.connection
+                    .prepare_cached("SELECT file_hash FROM files_tags WHERE tag = ?")?
+                    .query_and_then(&[source], |row| row.get_checked::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                hashes.extend(source_hashes);
+
+                self.connection
+                    .prepare_cached("UPDATE OR IGNORE files_tags SET tag = ? WHERE tag = ?")?
+                    .execute(&[&target, &source.as_str()])?;
+                // Rows left over are files that already had the target tag.
+                self.connection
+                    .prepare_cached("DELETE FROM files_tags WHERE tag = ?")?
+                    .execute(&[source])?;
+            }
+            hashes.sort();
+            hashes.dedup();
+            Ok(hashes)
+        })
+    }
+
+    /// Tags starting with `prefix`, in alphabetical order. Like `tags`, only
+    /// tags of files outside the trash, and with `public_only` of public
+    /// files, are considered.
+    pub fn tags_matching(&self, prefix: &str, limit: u32, public_only: bool) -> Result<Vec<String>, DbError> {
+        let q = format!(
+            r#"
+            SELECT DISTINCT files_tags.tag FROM files_tags
+            JOIN files ON files.hash = files_tags.file_hash
+            WHERE files_tags.tag LIKE ? || '%' ESCAPE '\' AND files.deleted_at IS NULL {}
+            ORDER BY files_tags.tag
+            LIMIT ?
+            "#,
+            if public_only { "AND files.visibility = 'public'" } else { "" },
+        );
+        self.connection
+            .prepare_cached(&q)?
+            .query_and_then(&[&Self::like_escape(prefix) as &dyn ToSql, &limit], |row| row.get_checked(0))?
+            .collect()
+    }
+
+    /// Number of files per kind, not counting the trash.
+    pub fn kind_counts(&self, public_only: bool) -> Result<Vec<(t::FileKind, u32)>, DbError> {
+        let q = if public_only {
+            "SELECT kind, COUNT(*) FROM files WHERE deleted_at IS NULL AND visibility = 'public' GROUP BY kind ORDER BY kind"
+        } else {
+            "SELECT kind, COUNT(*) FROM files WHERE deleted_at IS NULL GROUP BY kind ORDER BY kind"
+        };
+        self.connection
+            .prepare_cached(q)?
+            .query_and_then(NO_PARAMS, |row| -> Result<(t::FileKind, u32), DbError> {
+                let kind = t::FileKind::from_str(&row.get_checked::<_, String>(0)?);
+                Ok((kind, row.get_checked(1)?))
+            })?
+            .collect()
+    }
+
+    /// Number and total size of files in each subfolder of `prefix`, ordered
+    /// by path. Files in nested folders count towards the subfolder that
+    /// contains them. Files directly in `prefix` and files in the trash are
+    /// not included.
+    pub fn folder_aggregates(&self, prefix: &str, public_only: bool) -> Result<Vec<t::FolderStats>, DbError> {
+        let prefix = prefix.trim_matches('/');
+        let (start, pattern) = if prefix.is_empty() {
+            (1, "%".to_string())
+        } else {
+            (prefix.chars().count() as i64 + 2, format!("{}/%", Self::like_escape(prefix)))
+        };
+        let visibility = if public_only { " AND visibility = 'public'" } else { "" };
+        let q = format!(
+            "SELECT substr(rest, 1, instr(rest, '/') - 1) AS folder, COUNT(*), SUM(size)
+             FROM (SELECT substr(path, ?) AS rest, size FROM files WHERE deleted_at IS NULL AND path LIKE ? ESCAPE '\\'{})
+             WHERE instr(rest, '/') > 0
+             GROUP BY folder
+             ORDER BY folder",
+            visibility,
+        );
+        self.connection
+            .prepare(&q)?
+            .query_and_then(&[&start as &dyn ToSql, &pattern], |row| -> Result<t::FolderStats, DbError> {
+                let folder: String = row.get_checked(0)?;
+                Ok(t::FolderStats {
+                    path: if prefix.is_empty() { folder } else { format!("{}/{}", prefix, folder) },
+                    count: row.get_checked(1)?,
+                    size: row.get_checked(2)?,
+                })
+            })?
+            .collect()
+    }
+
+    /// Rebuild the media info from the dimension columns.
+    ///
+    /// The kind decides the variant. Files of other kinds get the variant that
+    /// fits the columns that are set.
+    fn media_from_columns(
+        kind: t::FileKind,
+        width: Option<u32>,
+        height: Option<u32>,
+        length: Option<u32>,
+    ) -> Option<t::MediaInfo> {
+        if width.is_none() && height.is_none() && length.is_none() {
+            return None;
+        }
+        let has_dimensions = width.is_some() || height.is_some();
+        let (width, height) = (width.unwrap_or(0), height.unwrap_or(0));
+        let info = match kind {
+            t::FileKind::Image => t::MediaInfo::Image(t::ImageInfo { width, height }),
+            t::FileKind::Video => t::MediaInfo::Video(t::VideoInfo { width, height, length: length.unwrap_or(0) }),
+            t::FileKind::Audio => t::MediaInfo::Audio(t::AudioInfo { length: length.unwrap_or(0) }),
+            t::FileKind::Other => match length {
+                Some(length) if has_dimensions => t::MediaInfo::Video(t::VideoInfo { width, height, length }),
+                Some(length) => t::MediaInfo::Audio(t::AudioInfo { length }),
+                None => t::MediaInfo::Image(t::ImageInfo { width, height }),
+            },
+        };
+        Some(info)
+    }
+
     fn file_from_row(&self, row: &rusqlite::Row, get_tags: bool) -> Result<t::File, DbError> {
         let hash: String = row.get_checked("hash")?;
         let tags = if get_tags { self.file_tags(&hash)? } else { Vec::new() };
+        let kind = t::FileKind::from_str(&row.get_checked::<_, String>("kind")?);
+        let media = Self::media_from_columns(
+            kind,
+            row.get_checked("width")?,
+            row.get_checked("height")?,
+            row.get_checked("length")?,
+        );
 
         Ok(t::File {
             path: row.get_checked("path")?,
@@ -184,10 +619,15 @@ impl<'a> Db<'a> {
                 hash: hash.clone(),
                 size: row.get_checked("size")?,
                 mime: row.get_checked("mime")?,
-                kind: t::FileKind::from_str(&row.get_checked::<_, String>("kind")?),
-                media: None,
+                kind,
+                media,
                 created_at: row.get_checked("created_at")?,
                 updated_at: row.get_checked("updated_at")?,
+                subtitles: Vec::new(),
+                location: match (row.get_checked::<_, Option<f64>>("lat")?, row.get_checked::<_, Option<f64>>("lon")?) {
+                    (Some(lat), Some(lon)) => Some(t::GeoLocation { lat, lon }),
+                    _ => None,
+                },
             },
             meta: t::FileMeta {
                 title: row.get_checked("title")?,
@@ -195,77 +635,395 @@ impl<'a> Db<'a> {
                 tags,
                 sources: Vec::new(),
                 hash: Some(hash),
+                visibility: t::Visibility::from_str(&row.get_checked::<_, String>("visibility")?),
+                featured_weight: row.get_checked("featured_weight")?,
+                notes: row.get_checked("notes")?,
             },
         })
     }
 
     pub fn file(&self, hash: &str) -> Result<t::File, Error> {
-        self.connection
+        let mut file = self.connection
             .prepare_cached("SELECT * FROM files WHERE hash = ?")?
             .query_and_then(&[&*hash], |row| -> Result<t::File, DbError> {
                 self.file_from_row(row, true)
             })?
             .next()
             .map(|x| x.map_err(Error::from))
-            .unwrap_or(Err(format_err!("not_found")))
+            .unwrap_or_else(|| Err(AppError::not_found().into()))?;
+        file.info.subtitles = self.file_subtitles(hash)?;
+        Ok(file)
     }
 
-    pub fn files(&self, query: FileQuery) -> Result<t::FilesPage, DbError> {
-        let mut query_parts: Vec<String> = vec!["SELECT * FROM files".to_string()];
-        let mut params: Vec<&rusqlite::types::ToSql> = Vec::new();
+    fn file_subtitles(&self, hash: &str) -> Result<Vec<t::Subtitle>, DbError> {
+        self.connection
+            .prepare_cached("SELECT lang, path FROM subtitles WHERE file_hash = ? ORDER BY lang")?
+            .query_and_then(&[&*hash], |row| -> Result<t::Subtitle, DbError> {
+                Ok(t::Subtitle {
+                    lang: row.get_checked(0)?,
+                    path: row.get_checked(1)?,
+                })
+            })?
+            .collect()
+    }
 
+    /// Replace the recorded subtitles of a file.
+    pub fn file_subtitles_persist(&self, hash: &str, subtitles: &[t::Subtitle]) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("DELETE FROM subtitles WHERE file_hash = ?")?
+            .execute(&[&hash])?;
 
-        let (where_clause, where_params) = match query.filter.as_ref() {
-            Some(f) => {
-                let (q, p) = Self::file_filter_apply(f);
-                (format!("WHERE {}", q), p)
-            },
-            None => ("".to_string(), vec![]),
+        for subtitle in subtitles {
+            self.connection
+                .prepare_cached("INSERT INTO subtitles (file_hash, lang, path) VALUES (?, ?, ?)")?
+                .execute(&[&hash, &subtitle.lang.as_str(), &subtitle.path.as_str()])?;
+        }
+        Ok(())
+    }
+
+    fn sort_column(sort: t::FileSort) -> &'static str {
+        match sort {
+            t::FileSort::Updated => "updated_at",
+            t::FileSort::Created => "created_at",
+            t::FileSort::Type => "mime",
+            t::FileSort::Size => "size",
+            t::FileSort::Length => "length",
+            t::FileSort::Title => "title",
+            t::FileSort::Random => "RANDOM()",
+        }
+    }
+
+    /// Expression to order and compare by.
+    fn sort_expr(sort: t::FileSort) -> String {
+        match sort {
+            t::FileSort::Title => "title COLLATE NOCASE".to_string(),
+            sort => Self::sort_column(sort).to_string(),
+        }
+    }
+
+    /// Whether NULLs come after all values. SQLite puts NULLs first in
+    /// ascending and last in descending order, titles always put them last.
+    fn sort_nulls_last(item: &t::FileSortItem) -> bool {
+        item.sort == t::FileSort::Title || !item.is_ascending()
+    }
+
+    fn sort_order(item: &t::FileSortItem) -> String {
+        if item.sort == t::FileSort::Random {
+            return Self::sort_expr(item.sort);
+        }
+        let direction = if item.is_ascending() { "ASC" } else { "DESC" };
+        let expr = Self::sort_expr(item.sort);
+        if item.sort == t::FileSort::Title && item.is_ascending() {
+            format!("{} IS NULL, {} {}", Self::sort_column(item.sort), expr, direction)
+        } else {
+            format!("{} {}", expr, direction)
+        }
+    }
+
+    fn cursor_value(value: &t::CursorValue) -> Value {
+        match value {
+            t::CursorValue::Null => Value::Null,
+            t::CursorValue::Integer(i) => Value::Integer(*i),
+            t::CursorValue::Text(ref s) => Value::Text(s.clone()),
+        }
+    }
+
+    fn cursor_value_from_sql(value: Value) -> t::CursorValue {
+        match value {
+            Value::Integer(i) => t::CursorValue::Integer(i),
+            Value::Text(s) => t::CursorValue::Text(s),
+            Value::Real(r) => t::CursorValue::Text(r.to_string()),
+            Value::Null | Value::Blob(_) => t::CursorValue::Null,
+        }
+    }
+
+    /// Build a condition that selects all rows after the cursor.
+    ///
+    /// Rows are ordered by the sort column, then by hash in the same direction.
+    /// The position of NULLs has to match `sort_nulls_last`.
+    fn file_cursor_apply(cursor: &t::FileCursor) -> (String, Vec<Box<dyn ToSql>>) {
+        let field = Self::sort_expr(cursor.sort.sort);
+        let ascending = cursor.sort.is_ascending();
+        let nulls_last = Self::sort_nulls_last(&cursor.sort);
+        let op = if ascending { ">" } else { "<" };
+        let hash = Value::Text(cursor.hash.clone());
+
+        match cursor.value {
+            t::CursorValue::Null if !nulls_last => (
+                format!(" ({f} IS NOT NULL OR hash {op} ?) ", f = field, op = op),
+                vec![Box::new(hash)],
+            ),
+            t::CursorValue::Null => (
+                format!(" ({f} IS NULL AND hash {op} ?) ", f = field, op = op),
+                vec![Box::new(hash)],
+            ),
+            ref value => {
+                let nulls = if nulls_last {
+                    format!(" OR {} IS NULL", field)
+                } else {
+                    String::new()
+                };
+                let q = format!(
+                    " ({f} {op} ? OR ({f} = ? AND hash {op} ?){nulls}) ",
+                    f = field,
+                    op = op,
+                    nulls = nulls,
+                );
+                (q, vec![
+                    Box::new(Self::cursor_value(value)),
+                    Box::new(Self::cursor_value(value)),
+                    Box::new(hash),
+                ])
+            }
+        }
+    }
+
+    /// All indexed paths, including those of copies.
+    pub fn file_paths(&self) -> Result<HashSet<String>, DbError> {
+        self.connection
+            .prepare_cached("SELECT path FROM file_paths")?
+            .query_and_then(NO_PARAMS, |row| row.get_checked::<_, String>(0))?
+            .collect()
+    }
+
+    /// Hash of the file indexed at `path`, if any.
+    pub fn file_hash_by_path(&self, path: &str) -> Result<Option<String>, DbError> {
+        let mut stmt = self.connection.prepare_cached("SELECT hash FROM files WHERE path = ?")?;
+        let mut rows = stmt.query_and_then(&[&path], |row| row.get_checked::<_, String>(0))?;
+        match rows.next() {
+            Some(hash) => hash.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn file_exists(&self, hash: &str) -> Result<bool, DbError> {
+        self.connection
+            .prepare_cached("SELECT COUNT(*) FROM files WHERE hash = ?")?
+            .query_row(&[&hash], |row| row.get::<_, i64>(0))
+            .map(|count| count > 0)
+    }
+
+    /// Log a query with its parameters and query plan.
+    fn sql_debug(&self, q: &str, params: &[&dyn ToSql]) -> Result<(), DbError> {
+        let values = params
+            .iter()
+            .map(|p| p.to_sql().map(|v| format!("{:?}", v)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let plan = self.connection
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", q))?
+            .query_and_then(params, |row| row.get_checked::<_, String>(3))?
+            .collect::<Result<Vec<_>, _>>()?;
+        log::debug!("SQL: {}\nParameters: {}\nPlan:\n  {}", q.trim(), values.join(", "), plan.join("\n  "));
+        Ok(())
+    }
+
+    /// Sort items that take effect. Items after a random order would never
+    /// apply.
+    fn sort_effective(sort: &[t::FileSortItem]) -> &[t::FileSortItem] {
+        match sort.iter().position(|item| item.sort == t::FileSort::Random) {
+            Some(index) => &sort[..index + 1],
+            None => sort,
+        }
+    }
+
+    /// The sort item cursors refer to. Random orders can't be continued.
+    fn cursor_sort(query: &FileQuery) -> Option<t::FileSortItem> {
+        let sort = Self::sort_effective(&query.sort);
+        if sort.iter().any(|item| item.sort == t::FileSort::Random) {
+            None
+        } else {
+            sort.first().cloned()
+        }
+    }
+
+    /// WHERE clause of a files query, shared by the count and the page query.
+    fn files_where(query: &FileQuery) -> (String, Vec<Box<dyn ToSql>>) {
+        // Files in the trash are only listed by `files_trashed`.
+        let mut where_parts = vec!["files.deleted_at IS NULL".to_string()];
+        let mut where_params: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(f) = query.filter.as_ref() {
+            let (q, p) = Self::file_filter_apply(f);
+            where_parts.push(format!("({})", q));
+            where_params.extend(p);
+        }
+        if let Some(hashes) = query.include_hashes.as_ref() {
+            where_parts.push("files.hash IN rarray(?)".to_string());
+            where_params.push(Box::new(Self::hash_array(hashes)));
+        }
+        if let Some(hashes) = query.exclude_hashes.as_ref() {
+            where_parts.push("files.hash NOT IN rarray(?)".to_string());
+            where_params.push(Box::new(Self::hash_array(hashes)));
+        }
+        let where_clause = format!("WHERE {}", where_parts.join(" AND "));
+        (where_clause, where_params)
+    }
+
+    /// Query for the number of matching files, and their total size if
+    /// `totals` is set.
+    fn build_files_count_query(query: &FileQuery, totals: bool) -> (String, Vec<Box<dyn ToSql>>) {
+        let (where_clause, params) = Self::files_where(query);
+        let count_select = if totals { "COUNT(*), COALESCE(SUM(size), 0)" } else { "COUNT(*), NULL" };
+        (format!("SELECT {} FROM files {}", count_select, where_clause), params)
+    }
+
+    /// Query for a page of files.
+    ///
+    /// Fails for cursors that can't be applied, e.g. for text searches or a
+    /// different sort than the query.
+    pub fn build_files_query(query: &FileQuery) -> Result<(String, Vec<Box<dyn ToSql>>), Error> {
+        // A page size of 0 would never return anything.
+        let page_size = query.page_size.max(1).min(FileQuery::MAX_PAGE_SIZE);
+
+        // Text searches are ordered by relevance. Title matches weigh more than
+        // description matches; the weights are per column of files_fts.
+        let rank_text = query.filter.as_ref().and_then(Self::primary_text);
+        let mut query_parts: Vec<String> = match rank_text {
+            Some(_) => vec![r#"
+                SELECT files.*, fts_score FROM files
+                JOIN (
+                    SELECT hash AS fts_hash, bm25(files_fts, 0.0, 10.0, 1.0) AS fts_score
+                    FROM files_fts WHERE files_fts MATCH ?
+                ) ON fts_hash = files.hash"#.to_string()],
+            None => vec!["SELECT * FROM files".to_string()],
         };
-        params.extend(where_params.iter().map(|x| -> &dyn ToSql { x.as_ref() }));
 
-        // Get result count.
-        let count = self.connection.query_row_and_then(
-            &format!("SELECT COUNT(*) FROM files {}", where_clause),
-                &params,
-                |row| row.get_checked::<_, u32>(0)
-        )?;
+        let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+        if let Some(text) = rank_text.as_ref() {
+            params.push(Box::new(text.clone()));
+        }
+
+        if rank_text.is_some() && query.cursor.is_some() {
+            return Err(AppError::BadRequest("cursors are not supported for text searches".to_string()).into());
+        }
+        let sort = Self::sort_effective(&query.sort);
+        if query.cursor.is_some() && sort.iter().any(|item| item.sort == t::FileSort::Random) {
+            return Err(AppError::BadRequest("cursors are not supported for random order".to_string()).into());
+        }
+        // A cursor only holds the value of the first sort item, so rows that
+        // tie on it would be skipped or repeated if later items reorder them.
+        if query.cursor.is_some() && sort.len() > 1 {
+            return Err(AppError::BadRequest("cursors are not supported for more than one sort item".to_string()).into());
+        }
+
+        // Keyset pagination continues after the cursor of the previous page.
+        let (where_clause, where_params) = Self::files_where(query);
+        params.extend(where_params);
+        let first_sort = Self::cursor_sort(query);
+        match (query.cursor.as_ref(), first_sort.as_ref()) {
+            (Some(cursor), Some(sort)) if &cursor.sort == sort => {
+                let (q, p) = Self::file_cursor_apply(cursor);
+                query_parts.push(format!("{} AND {}", where_clause, q));
+                params.extend(p);
+            },
+            (Some(_), _) => {
+                return Err(AppError::BadRequest("cursor does not match the query sort".to_string()).into());
+            },
+            (None, _) => {
+                query_parts.push(where_clause);
+            }
+        }
 
         // Order.
-        let order_parts = query.sort
-            .into_iter()
-            .map(|item| {
-                let field = match item.sort {
-                    t::FileSort::Updated => "updated_at",
-                    t::FileSort::Created => "created_at",
-                    t::FileSort::Type => "mime",
-                    t::FileSort::Size => "size",
-                    t::FileSort::Length => "length",
-                };
-                let direction = if item.ascending { "ASC" } else { "DESC" };
-                format!("{} {}", field, direction)
-            })
-            .collect::<Vec<_>>();
+        let mut order_parts = Vec::new();
+        if rank_text.is_some() {
+            order_parts.push("fts_score ASC".to_string());
+        }
+        order_parts.extend(sort.iter().map(Self::sort_order));
+        if let Some(sort) = first_sort.as_ref() {
+            // Hash is the tie breaker that makes cursors unambiguous.
+            let direction = if sort.is_ascending() { "ASC" } else { "DESC" };
+            order_parts.push(format!("hash {}", direction));
+        }
         if order_parts.len() > 0 {
             query_parts.push(format!("ORDER BY {}", order_parts.join(", ")));
         }
 
         // LIMIT and OFFSET.
         query_parts.push("LIMIT ? OFFSET ?".to_string());
-        params.push(&query.page_size);
-        let offset = if query.page < 2 { 0 } else { query.page * query.page_size };
-        params.push(&offset);
+        let offset = if query.cursor.is_some() {
+            0
+        } else {
+            // Pages start at 1; page 0 is treated as the first page too.
+            (query.page.max(1) - 1)
+                .checked_mul(page_size)
+                .ok_or_else(|| AppError::BadRequest(format!("page {} is out of range", query.page)))?
+        };
+        params.push(Box::new(page_size));
+        params.push(Box::new(offset));
+
+        Ok((query_parts.join(" "), params))
+    }
+
+    /// Number of files matching the query, ignoring pagination.
+    pub fn files_count(&self, query: &FileQuery) -> Result<u32, DbError> {
+        self.files_totals(query, false).map(|(count, _)| count)
+    }
 
-        // Build final query string.
-        let q = query_parts.join(" ");
+    /// Count of matching files, and their total size if `totals` is set.
+    fn files_totals(&self, query: &FileQuery, totals: bool) -> Result<(u32, Option<i64>), DbError> {
+        let (q, params) = Self::build_files_count_query(query, totals);
+        let params: Vec<&dyn ToSql> = params.iter().map(|x| -> &dyn ToSql { x.as_ref() }).collect();
+        self.connection.query_row_and_then(
+            &q,
+            &params,
+            |row| -> Result<_, DbError> {
+                Ok((row.get_checked::<_, u32>(0)?, row.get_checked::<_, Option<i64>>(1)?))
+            },
+        )
+    }
 
-        let mut files = self.connection
+    /// Query files. With `totals`, the combined size of all matches is
+    /// computed as well.
+    pub fn files(&self, mut query: FileQuery, totals: bool) -> Result<t::FilesPage, Error> {
+        query.page_size = query.page_size.max(1).min(FileQuery::MAX_PAGE_SIZE);
+        let (q, params) = Self::build_files_query(&query)?;
+
+        // Get result count, and the size if requested.
+        let (count, total_size) = self.files_totals(&query, totals)?;
+
+        let params: Vec<&dyn ToSql> = params.iter().map(|x| -> &dyn ToSql { x.as_ref() }).collect();
+        if std::env::var_os("MEDIAVAULT_DEBUG_SQL").is_some() {
+            self.sql_debug(&q, &params)?;
+        }
+
+        // Cursors can only continue queries sorted by a single item.
+        let first_sort = Self::cursor_sort(&query)
+            .filter(|_| Self::sort_effective(&query.sort).len() == 1);
+        let sort_column = first_sort.as_ref().map(|item| Self::sort_column(item.sort));
+        let ranked = query.filter.as_ref().and_then(Self::primary_text).is_some();
+        let rows = self.connection
             .prepare(&q)?
-            .query_and_then(params, |row| -> Result<t::File, DbError> {
-                self.file_from_row(row, false)
+            .query_and_then(params, |row| -> Result<(t::File, Option<Value>, Option<f64>), DbError> {
+                let file = self.file_from_row(row, false)?;
+                let value = match sort_column {
+                    Some(column) => Some(row.get_checked::<_, Value>(column)?),
+                    None => None,
+                };
+                let score = if ranked {
+                    Some(row.get_checked::<_, f64>("fts_score")?)
+                } else {
+                    None
+                };
+                Ok((file, value, score))
             })?
             .collect::<Result<Vec<_>, _>>()?;
 
+        let full_page = rows.len() as u64 == query.page_size as u64;
+        let next_cursor = match (rows.last(), first_sort) {
+            (Some((file, Some(value), None)), Some(sort)) if full_page => Some(t::FileCursor {
+                sort,
+                value: Self::cursor_value_from_sql(value.clone()),
+                hash: file.info.hash.clone(),
+            }),
+            _ => None,
+        };
+        let scores = if ranked {
+            Some(rows.iter().filter_map(|(_, _, score)| *score).collect())
+        } else {
+            None
+        };
+        let mut files = rows.into_iter().map(|(file, _, _)| file).collect::<Vec<_>>();
+
         let hashes = files.iter().map(|f| f.info.hash.as_str()).collect::<Vec<_>>();
         let tags = self.files_tags(&hashes)?;
 
@@ -279,20 +1037,139 @@ impl<'a> Db<'a> {
             total: count,
             page: query.page,
             page_size: query.page_size,
+            total_pages: t::FilesPage::page_count(count, query.page_size),
+            next_cursor,
+            scores,
+            total_size,
         })
     }
 
+    /// Featured files, ordered by weight.
+    pub fn files_featured(&self, public_only: bool) -> Result<Vec<t::File>, DbError> {
+        let q = if public_only {
+            "SELECT * FROM files WHERE featured_weight IS NOT NULL AND deleted_at IS NULL AND visibility = 'public' ORDER BY featured_weight, hash"
+        } else {
+            "SELECT * FROM files WHERE featured_weight IS NOT NULL AND deleted_at IS NULL ORDER BY featured_weight, hash"
+        };
+        self.connection
+            .prepare_cached(q)?
+            .query_and_then(NO_PARAMS, |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Register a new index run.
+    pub fn index_run_create(&self, id: &Uuid, started_at: &DateTime) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("INSERT INTO index_runs (id, started_at) VALUES (?, ?)")?
+            .execute::<&[&rusqlite::types::ToSql]>(&[&id.to_string(), started_at])?;
+        Ok(())
+    }
+
+    /// Mark a file as added by the given index run.
+    pub fn file_index_run_set(&self, hash: &str, run: &Uuid) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("UPDATE files SET index_run = ? WHERE hash = ?")?
+            .execute::<&[&rusqlite::types::ToSql]>(&[&run.to_string(), &hash])?;
+        Ok(())
+    }
+
+    /// Files with a location, for the map view.
+    pub fn files_geo(&self, public_only: bool) -> Result<Vec<t::File>, DbError> {
+        let q = if public_only {
+            "SELECT * FROM files WHERE lat IS NOT NULL AND lon IS NOT NULL AND deleted_at IS NULL AND visibility = 'public' ORDER BY hash"
+        } else {
+            "SELECT * FROM files WHERE lat IS NOT NULL AND lon IS NOT NULL AND deleted_at IS NULL ORDER BY hash"
+        };
+        self.connection
+            .prepare_cached(q)?
+            .query_and_then(NO_PARAMS, |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Files that should have media info but don't, ordered by path.
+    pub fn files_missing_media_info(&self, public_only: bool) -> Result<Vec<t::File>, DbError> {
+        let visibility = if public_only { " AND visibility = 'public'" } else { "" };
+        let q = format!("SELECT * FROM files WHERE {} AND deleted_at IS NULL{} ORDER BY path", MISSING_MEDIA_INFO, visibility);
+        self.connection
+            .prepare_cached(&q)?
+            .query_and_then(NO_PARAMS, |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Files thumbnails can be generated for, ordered by path.
+    pub fn files_with_thumbnails(&self) -> Result<Vec<t::File>, DbError> {
+        self.connection
+            .prepare_cached("SELECT * FROM files WHERE kind IN ('image', 'video') AND deleted_at IS NULL ORDER BY path")?
+            .query_and_then(NO_PARAMS, |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Files with the given hashes, in no particular order.
+    pub fn files_by_hashes(&self, hashes: &[String], public_only: bool) -> Result<Vec<t::File>, DbError> {
+        let q = if public_only {
+            "SELECT * FROM files WHERE hash IN rarray(?) AND deleted_at IS NULL AND visibility = 'public'"
+        } else {
+            "SELECT * FROM files WHERE hash IN rarray(?) AND deleted_at IS NULL"
+        };
+        self.connection
+            .prepare_cached(q)?
+            .query_and_then(&[&Self::hash_array(hashes) as &dyn ToSql], |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Files added by the most recent index run.
+    pub fn files_last_indexed(&self, public_only: bool) -> Result<Vec<t::File>, DbError> {
+        let q = format!(
+            r#"
+            SELECT * FROM files
+            WHERE index_run = (SELECT id FROM index_runs ORDER BY started_at DESC LIMIT 1)
+                AND deleted_at IS NULL
+            {}
+            ORDER BY path
+            "#,
+            if public_only { "AND visibility = 'public'" } else { "" },
+        );
+        self.connection
+            .prepare_cached(&q)?
+            .query_and_then(NO_PARAMS, |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Insert a file, or update its row if the hash is already indexed.
+    ///
+    /// The row is updated in place rather than replaced: replacing would
+    /// delete it first, and with it the tags, gallery items and subtitles
+    /// that reference it. Columns not set from the file, like `index_run`
+    /// and `deleted_at`, are kept. Files in the trash also keep their path,
+    /// which `file_restore` moves them back to; see `file_untrash`.
+    ///
+    /// `updated_at` never moves backwards. Metadata edits are recorded there
+    /// but don't change the modification time of the file, so indexing the
+    /// file again must not replace them with it.
     pub fn file_persist(&self, file: &t::File) -> Result<(), DbError> {
+        self.file_path_release(&file.path, &file.info.hash)?;
+
+        let stored_updated_at = {
+            let mut stmt = self.connection.prepare_cached("SELECT updated_at FROM files WHERE hash = ?")?;
+            let mut rows = stmt.query_and_then(&[&file.info.hash], |row| row.get_checked::<_, Option<DateTime>>(0))?;
+            match rows.next() {
+                Some(updated_at) => updated_at?,
+                None => None,
+            }
+        };
+        let updated_at = match (file.info.updated_at, stored_updated_at) {
+            (Some(new), Some(stored)) => Some(new.max(stored)),
+            (new, stored) => new.or(stored),
+        };
+
         let q = r#"
-            INSERT OR REPLACE INTO files (
-                hash, path, title, description, size, mime, kind, created_at, updated_at, width, height, length
-            ) VALUES (
-               ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
-            )"#;
-        let mut stmt = self.connection.prepare_cached(q)?;
-
-        stmt.execute::<&[&rusqlite::types::ToSql]>(&[
-            &file.info.hash,
+            UPDATE files SET
+                path = CASE WHEN deleted_at IS NULL THEN ? ELSE path END, title = ?, description = ?, size = ?, mime = ?, kind = ?,
+                created_at = COALESCE(?, created_at), updated_at = ?,
+                width = ?, height = ?, length = ?,
+                visibility = ?, featured_weight = ?, lat = ?, lon = ?, notes = ?
+            WHERE hash = ?"#;
+        let updated = self.connection.prepare_cached(q)?.execute::<&[&rusqlite::types::ToSql]>(&[
             &file.path,
             &file.meta.title,
             &file.meta.description,
@@ -300,19 +1177,1017 @@ impl<'a> Db<'a> {
             &file.info.mime,
             &file.info.kind.to_str(),
             &file.info.created_at,
-            &file.info.updated_at,
+            &updated_at,
             &file.info.media.as_ref().map(|m| m.width()),
             &file.info.media.as_ref().map(|m| m.height()),
             &file.info.media.as_ref().map(|m| m.length()),
+            &file.meta.visibility.to_str(),
+            &file.meta.featured_weight,
+            &file.info.location.map(|l| l.lat),
+            &file.info.location.map(|l| l.lon),
+            &file.meta.notes,
+            &file.info.hash,
         ])?;
 
+        if updated == 0 {
+            let q = r#"
+                INSERT INTO files (
+                    hash, path, title, description, size, mime, kind, created_at, updated_at, width, height, length,
+                    visibility, featured_weight, lat, lon, notes
+                ) VALUES (
+                   ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+                   ?, ?, ?, ?, ?
+                )"#;
+            self.connection.prepare_cached(q)?.execute::<&[&rusqlite::types::ToSql]>(&[
+                &file.info.hash,
+                &file.path,
+                &file.meta.title,
+                &file.meta.description,
+                &file.info.size,
+                &file.info.mime,
+                &file.info.kind.to_str(),
+                &file.info.created_at.unwrap_or_else(now),
+                &updated_at.unwrap_or_else(now),
+                &file.info.media.as_ref().map(|m| m.width()),
+                &file.info.media.as_ref().map(|m| m.height()),
+                &file.info.media.as_ref().map(|m| m.length()),
+                &file.meta.visibility.to_str(),
+                &file.meta.featured_weight,
+                &file.info.location.map(|l| l.lat),
+                &file.info.location.map(|l| l.lon),
+                &file.meta.notes,
+            ])?;
+        }
+
+        self.connection
+            .prepare_cached("DELETE FROM files_fts WHERE hash = ?")?
+            .execute(&[&file.info.hash])?;
+        self.connection
+            .prepare_cached("INSERT INTO files_fts (hash, title, description) VALUES (?, ?, ?)")?
+            .execute::<&[&rusqlite::types::ToSql]>(&[
+                &file.info.hash,
+                &file.meta.title,
+                &file.meta.description,
+            ])?;
+
+        self.connection
+            .prepare_cached("INSERT OR REPLACE INTO file_paths (path, file_hash) VALUES (?, ?)")?
+            .execute(&[&file.path, &file.info.hash])?;
+
         self.file_tags_persist(&file.info.hash, file.meta.tags.clone())?;
         Ok(())
     }
 
-    pub fn file_delete(&self, hash: &str) -> Result<(), Error> {
+    /// Make `path` available for the file with `hash`.
+    ///
+    /// `files.path` is unique, so a different file indexed at the path, e.g.
+    /// the previous content of a changed file, loses it.
+    fn file_path_release(&self, path: &str, hash: &str) -> Result<(), DbError> {
+        match self.file_hash_by_path(path)? {
+            Some(ref other) if other != hash => {
+                self.file_path_delete(path)?;
+                // Rows without recorded paths, like files in the trash.
+                self.connection
+                    .prepare_cached("DELETE FROM files WHERE path = ? AND hash != ?")?
+                    .execute(&[&path, &hash])?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove a path from the index.
+    ///
+    /// The file is deleted once its last path is gone, otherwise it moves to
+    /// one of its remaining paths.
+    pub fn file_path_delete(&self, path: &str) -> Result<(), DbError> {
+        let hash = {
+            let mut stmt = self.connection.prepare_cached("SELECT file_hash FROM file_paths WHERE path = ?")?;
+            let mut rows = stmt.query_and_then(&[&path], |row| row.get_checked::<_, String>(0))?;
+            match rows.next() {
+                Some(hash) => hash?,
+                None => return Ok(()),
+            }
+        };
+        self.connection
+            .prepare_cached("DELETE FROM file_paths WHERE path = ?")?
+            .execute(&[&path])?;
+
+        let remaining = {
+            let mut stmt = self.connection
+                .prepare_cached("SELECT path FROM file_paths WHERE file_hash = ? ORDER BY path LIMIT 1")?;
+            let mut rows = stmt.query_and_then(&[&hash], |row| row.get_checked::<_, String>(0))?;
+            match rows.next() {
+                Some(remaining) => Some(remaining?),
+                None => None,
+            }
+        };
+        match remaining {
+            Some(remaining) => {
+                self.connection
+                    .prepare_cached("UPDATE files SET path = ? WHERE hash = ? AND path = ?")?
+                    .execute(&[&remaining.as_str(), &hash.as_str(), &path])?;
+            }
+            None => self.file_delete(&hash)?,
+        }
+        Ok(())
+    }
+
+    /// Record that the file at `from` now lives at `to`. The hash, and with
+    /// it tags, galleries and subtitles, stays the same.
+    pub fn file_path_move(&self, from: &str, to: &str) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("UPDATE file_paths SET path = ? WHERE path = ?")?
+            .execute(&[&to, &from])?;
+        self.connection
+            .prepare_cached("UPDATE files SET path = ? WHERE path = ?")?
+            .execute(&[&to, &from])?;
+        Ok(())
+    }
+
+    /// Mark a file as moved to the trash. Its paths are forgotten, so
+    /// re-indexing a copy brings it back.
+    pub fn file_trash(&self, hash: &str, deleted_at: &DateTime) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("UPDATE files SET deleted_at = ? WHERE hash = ?")?
+            .execute(&[deleted_at as &dyn ToSql, &hash])?;
+        self.connection
+            .prepare_cached("DELETE FROM file_paths WHERE file_hash = ?")?
+            .execute(&[&hash])?;
+        Ok(())
+    }
+
+    /// Take a file out of the trash.
+    pub fn file_untrash(&self, hash: &str) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("UPDATE files SET deleted_at = NULL WHERE hash = ?")?
+            .execute(&[&hash])?;
+        Ok(())
+    }
+
+    /// Files in the trash, most recently deleted first.
+    pub fn files_trashed(&self) -> Result<Vec<t::File>, DbError> {
+        self.connection
+            .prepare_cached("SELECT * FROM files WHERE deleted_at IS NOT NULL ORDER BY deleted_at DESC")?
+            .query_and_then(NO_PARAMS, |row| self.file_from_row(row, true))?
+            .collect()
+    }
+
+    /// Hashes of files that were moved to the trash before `cutoff`.
+    pub fn files_trashed_before(&self, cutoff: &DateTime) -> Result<Vec<String>, DbError> {
+        let rows = self.connection
+            .prepare_cached("SELECT hash, deleted_at FROM files WHERE deleted_at IS NOT NULL ORDER BY deleted_at")?
+            .query_and_then(NO_PARAMS, |row| -> Result<(String, DateTime), DbError> {
+                Ok((row.get_checked(0)?, row.get_checked(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows.into_iter()
+            .filter(|(_, deleted_at)| deleted_at < cutoff)
+            .map(|(hash, _)| hash)
+            .collect())
+    }
+
+    pub fn file_is_trashed(&self, hash: &str) -> Result<bool, DbError> {
+        self.connection
+            .prepare_cached("SELECT COUNT(*) FROM files WHERE hash = ? AND deleted_at IS NOT NULL")?
+            .query_row(&[&hash], |row| row.get::<_, i64>(0))
+            .map(|count| count > 0)
+    }
+
+    /// Files indexed at more than one path, with their paths in order.
+    pub fn duplicates(&self) -> Result<Vec<(String, Vec<String>)>, DbError> {
+        let rows = self.connection
+            .prepare_cached("
+                SELECT file_hash, path FROM file_paths
+                WHERE file_hash IN (SELECT file_hash FROM file_paths GROUP BY file_hash HAVING COUNT(*) > 1)
+                ORDER BY file_hash, path
+            ")?
+            .query_and_then(NO_PARAMS, |row| -> Result<(String, String), DbError> {
+                Ok((row.get_checked(0)?, row.get_checked(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut duplicates: Vec<(String, Vec<String>)> = Vec::new();
+        for (hash, path) in rows {
+            match duplicates.last_mut() {
+                Some((last, paths)) if *last == hash => paths.push(path),
+                _ => duplicates.push((hash, vec![path])),
+            }
+        }
+        Ok(duplicates)
+    }
+
+    pub fn file_delete(&self, hash: &str) -> Result<(), DbError> {
         self.connection.prepare_cached("DELETE FROM files WHERE hash = ?")?
             .execute(&[&hash])?;
+        self.connection.prepare_cached("DELETE FROM files_fts WHERE hash = ?")?
+            .execute(&[&hash])?;
+        self.connection.prepare_cached("DELETE FROM file_paths WHERE file_hash = ?")?
+            .execute(&[&hash])?;
         Ok(())
     }
+
+    /// Insert or replace a gallery and its items.
+    ///
+    /// Items are stored in the given order. Items that don't reference an
+    /// indexed file are skipped.
+    pub fn gallery_persist(&self, gallery: &st::Gallery) -> Result<(), DbError> {
+        self.transaction(|| {
+            let updated = self.connection
+                .prepare_cached("UPDATE galleries SET title = ?, description = ? WHERE path = ?")?
+                .execute(&[&gallery.title as &dyn ToSql, &gallery.description, &gallery.path])?;
+            if updated == 0 {
+                self.connection
+                    .prepare_cached("INSERT INTO galleries (path, title, description) VALUES (?, ?, ?)")?
+                    .execute(&[&gallery.path as &dyn ToSql, &gallery.title, &gallery.description])?;
+            }
+
+            self.connection
+                .prepare_cached("DELETE FROM gallery_items WHERE gallery_path = ?")?
+                .execute(&[&gallery.path])?;
+            let mut weight = 0;
+            for item in &gallery.items {
+                let hash = match item._hash {
+                    Some(ref hash) => Some(hash.clone()),
+                    None => self.file_hash_by_path(&item.path)?,
+                };
+                let hash = match hash {
+                    Some(hash) => hash,
+                    None => {
+                        log::warn!("Skipping gallery item {} in {}: file not indexed", item.path, gallery.path);
+                        continue;
+                    }
+                };
+                self.connection
+                    .prepare_cached("INSERT OR IGNORE INTO gallery_items (gallery_path, file_hash, weight) VALUES (?, ?, ?)")?
+                    .execute(&[&gallery.path as &dyn ToSql, &hash, &weight])?;
+                weight += 1;
+            }
+            Ok(())
+        })
+    }
+
+    /// A gallery with its items in order. With `public_only`, items for
+    /// private files are left out. Items of trashed or removed files are
+    /// skipped, so the rest of the gallery still loads.
+    pub fn gallery(&self, path: &str, public_only: bool) -> Result<Option<st::Gallery>, DbError> {
+        let mut stmt = self.connection
+            .prepare_cached("SELECT path, title, description FROM galleries WHERE path = ?")?;
+        let mut rows = stmt.query_and_then(&[&path], |row| self.gallery_from_row(row, public_only))?;
+        match rows.next() {
+            Some(gallery) => gallery.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// All galleries, ordered by path.
+    pub fn galleries(&self, public_only: bool) -> Result<Vec<st::Gallery>, DbError> {
+        self.connection
+            .prepare_cached("SELECT path, title, description FROM galleries ORDER BY path")?
+            .query_and_then(NO_PARAMS, |row| self.gallery_from_row(row, public_only))?
+            .collect()
+    }
+
+    fn gallery_from_row(&self, row: &rusqlite::Row, public_only: bool) -> Result<st::Gallery, DbError> {
+        let path: String = row.get_checked(0)?;
+        let items = self.connection
+            .prepare_cached("
+                SELECT files.path, files.hash
+                FROM gallery_items
+                INNER JOIN files ON files.hash = gallery_items.file_hash
+                WHERE gallery_items.gallery_path = ?
+                    AND files.deleted_at IS NULL
+                    AND (? = 0 OR files.visibility = 'public')
+                ORDER BY gallery_items.weight
+            ")?
+            .query_and_then(&[&path as &dyn ToSql, &public_only], |row| -> Result<st::GalleryItem, DbError> {
+                Ok(st::GalleryItem {
+                    path: row.get_checked(0)?,
+                    _hash: Some(row.get_checked(1)?),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(st::Gallery {
+            path,
+            title: row.get_checked(1)?,
+            description: row.get_checked(2)?,
+            items,
+        })
+    }
+
+    /// Remove gallery items whose file is no longer indexed, e.g. rows left
+    /// behind from before foreign keys were enforced. Items of trashed files
+    /// are kept, so they come back when the file is restored.
+    ///
+    /// Returns the number of removed items.
+    pub fn gallery_items_prune(&self) -> Result<usize, DbError> {
+        self.connection
+            .prepare_cached("DELETE FROM gallery_items WHERE file_hash NOT IN (SELECT hash FROM files)")?
+            .execute(NO_PARAMS)
+    }
+
+    /// Delete a gallery. Its items are removed by the foreign key cascade.
+    pub fn gallery_delete(&self, path: &str) -> Result<(), DbError> {
+        self.connection
+            .prepare_cached("DELETE FROM galleries WHERE path = ?")?
+            .execute(&[&path])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection() -> Connection {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute_batch("PRAGMA foreign_keys = ON;").unwrap();
+        rusqlite::vtab::array::load_module(&con).unwrap();
+        Db::new(&con).migrate().unwrap();
+        con
+    }
+
+    fn file(hash: &str, size: i64) -> t::File {
+        t::File {
+            path: format!("{}.jpg", hash),
+            info: t::FileInfo {
+                hash: hash.to_string(),
+                size,
+                mime: Some("image/jpeg".to_string()),
+                kind: t::FileKind::Image,
+                media: None,
+                created_at: None,
+                updated_at: None,
+                subtitles: Vec::new(),
+                location: None,
+            },
+            meta: t::FileMeta::default(),
+        }
+    }
+
+    fn sort(sort: t::FileSort, ascending: bool) -> t::FileSortItem {
+        t::FileSortItem { sort, ascending: Some(ascending) }
+    }
+
+    fn hashes(page: &t::FilesPage) -> Vec<String> {
+        page.items.iter().map(|f| f.info.hash.clone()).collect()
+    }
+
+    #[test]
+    fn cursor_pages_across_ties() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, size) in &[("a", 10), ("b", 20), ("c", 20), ("d", 20), ("e", 5)] {
+            db.file_persist(&file(hash, *size)).unwrap();
+        }
+
+        let mut query = FileQuery {
+            page_size: 2,
+            sort: vec![sort(t::FileSort::Size, false)],
+            ..FileQuery::default()
+        };
+        let mut seen = Vec::new();
+        loop {
+            let page = db.files(query.clone(), false).unwrap();
+            seen.extend(hashes(&page));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(seen, vec!["d", "c", "b", "a", "e"]);
+    }
+
+    #[test]
+    fn cursor_rejected_for_several_sort_items() {
+        let con = connection();
+        let db = Db::new(&con);
+        for hash in &["a", "b", "c"] {
+            db.file_persist(&file(hash, 10)).unwrap();
+        }
+
+        let query = FileQuery {
+            page_size: 2,
+            sort: vec![sort(t::FileSort::Size, false), sort(t::FileSort::Title, true)],
+            ..FileQuery::default()
+        };
+        let page = db.files(query.clone(), false).unwrap();
+        assert!(page.next_cursor.is_none());
+
+        let cursor = t::FileCursor {
+            sort: sort(t::FileSort::Size, false),
+            value: t::CursorValue::Integer(10),
+            hash: "b".to_string(),
+        };
+        let query = FileQuery { cursor: Some(cursor), ..query };
+        assert!(db.files(query, false).is_err());
+    }
+
+    #[test]
+    fn tags_of_private_and_trashed_files_are_hidden() {
+        let con = connection();
+        let db = Db::new(&con);
+
+        let mut public = file("a", 1);
+        public.meta.tags = vec!["cat".to_string()];
+        let mut private = file("b", 1);
+        private.meta.tags = vec!["cat".to_string(), "secret".to_string()];
+        private.meta.visibility = t::Visibility::Private;
+        let mut trashed = file("c", 1);
+        trashed.meta.tags = vec!["cats".to_string()];
+        for f in &[public, private, trashed] {
+            db.file_persist(f).unwrap();
+        }
+        db.file_trash("c", &now()).unwrap();
+
+        assert_eq!(db.tags(None, true).unwrap(), vec![("cat".to_string(), 1)]);
+        assert_eq!(
+            db.tags(None, false).unwrap(),
+            vec![("cat".to_string(), 2), ("secret".to_string(), 1)],
+        );
+        assert_eq!(db.tags_matching("ca", 10, true).unwrap(), vec!["cat".to_string()]);
+        assert_eq!(db.tags_matching("s", 10, true).unwrap(), Vec::<String>::new());
+        assert_eq!(db.tags_matching("s", 10, false).unwrap(), vec!["secret".to_string()]);
+    }
+
+    #[test]
+    fn gallery_skips_and_prunes_dangling_items() {
+        let con = connection();
+        let db = Db::new(&con);
+        for hash in &["a", "b", "c"] {
+            db.file_persist(&file(hash, 1)).unwrap();
+        }
+        let item = |hash: &str| st::GalleryItem {
+            path: format!("{}.jpg", hash),
+            _hash: None,
+        };
+        db.gallery_persist(&st::Gallery {
+            path: "g.gallery.yaml".to_string(),
+            title: "G".to_string(),
+            description: None,
+            items: vec![item("a"), item("b"), item("c")],
+        }).unwrap();
+
+        db.file_trash("b", &now()).unwrap();
+        // Leave an item behind without its file, as without foreign keys.
+        con.execute_batch("PRAGMA foreign_keys = OFF; DELETE FROM files WHERE hash = 'c'; PRAGMA foreign_keys = ON;").unwrap();
+
+        let paths = |db: &Db| -> Vec<String> {
+            db.gallery("g.gallery.yaml", false).unwrap().unwrap()
+                .items
+                .into_iter()
+                .map(|item| item.path)
+                .collect()
+        };
+        assert_eq!(paths(&db), vec!["a.jpg".to_string()]);
+
+        assert_eq!(db.gallery_items_prune().unwrap(), 1);
+        assert_eq!(db.gallery_items_prune().unwrap(), 0);
+        db.file_untrash("b").unwrap();
+        assert_eq!(paths(&db), vec!["a.jpg".to_string(), "b.jpg".to_string()]);
+    }
+
+    #[test]
+    fn offset_pages_do_not_overlap() {
+        let con = connection();
+        let db = Db::new(&con);
+        for size in 0..70 {
+            db.file_persist(&file(&format!("{:02}", size), size)).unwrap();
+        }
+
+        let mut seen = Vec::new();
+        for page in 1..=3 {
+            let query = FileQuery {
+                page,
+                page_size: 30,
+                sort: vec![sort(t::FileSort::Size, true)],
+                ..FileQuery::default()
+            };
+            let result = db.files(query, false).unwrap();
+            assert_eq!(result.total, 70);
+            assert_eq!(result.total_pages, 3);
+            assert_eq!(result.items.len(), if page == 3 { 10 } else { 30 });
+            seen.extend(hashes(&result));
+        }
+        let expected = (0..70).map(|size| format!("{:02}", size)).collect::<Vec<_>>();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn build_files_query_rejects_overflowing_pages() {
+        let query = FileQuery {
+            page: u32::max_value(),
+            page_size: 30,
+            ..FileQuery::default()
+        };
+        let err = Db::build_files_query(&query).err().unwrap();
+        assert!(err.downcast_ref::<AppError>().is_some());
+    }
+
+    #[test]
+    fn build_files_query_parts() {
+        let (q, params) = Db::build_files_query(&FileQuery::default()).unwrap();
+        assert!(q.contains("WHERE files.deleted_at IS NULL"));
+        assert!(q.contains("ORDER BY updated_at DESC, hash DESC"));
+        assert!(q.ends_with("LIMIT ? OFFSET ?"));
+        assert_eq!(params.len(), 2);
+
+        let filtered = FileQuery {
+            filter: Some(FileFilter::Tag("cat".to_string())),
+            ..FileQuery::default()
+        };
+        let (q, params) = Db::build_files_query(&filtered).unwrap();
+        assert!(q.contains("files_tags WHERE tag = ?"));
+        assert_eq!(params.len(), 3);
+
+        let sorted = FileQuery {
+            sort: vec![sort(t::FileSort::Size, false)],
+            ..FileQuery::default()
+        };
+        let (q, params) = Db::build_files_query(&sorted).unwrap();
+        assert!(q.contains("ORDER BY size DESC, hash DESC"));
+        assert_eq!(params.len(), 2);
+
+        let combined = FileQuery {
+            page: 3,
+            ..FileQuery { sort: sorted.sort.clone(), ..filtered.clone() }
+        };
+        let (q, params) = Db::build_files_query(&combined).unwrap();
+        assert!(q.contains("files_tags WHERE tag = ?"));
+        assert!(q.contains("ORDER BY size DESC, hash DESC"));
+        assert_eq!(params.len(), 3);
+
+        let paginated = FileQuery {
+            cursor: Some(t::FileCursor {
+                sort: sort(t::FileSort::Size, false),
+                value: t::CursorValue::Integer(10),
+                hash: "a".to_string(),
+            }),
+            ..combined
+        };
+        let (q, params) = Db::build_files_query(&paginated).unwrap();
+        assert!(q.contains("size < ? OR (size = ? AND hash < ?) OR size IS NULL"));
+        assert_eq!(params.len(), 6);
+    }
+
+    #[test]
+    fn file_cursor_apply_conditions() {
+        let cursor = |sort: t::FileSortItem, value| t::FileCursor { sort, value, hash: "a".to_string() };
+
+        let (q, params) = Db::file_cursor_apply(&cursor(sort(t::FileSort::Size, true), t::CursorValue::Integer(1)));
+        assert_eq!(q, " (size > ? OR (size = ? AND hash > ?)) ");
+        assert_eq!(params.len(), 3);
+
+        // Descending orders put NULLs last, so they follow every value.
+        let (q, _) = Db::file_cursor_apply(&cursor(sort(t::FileSort::Size, false), t::CursorValue::Integer(1)));
+        assert_eq!(q, " (size < ? OR (size = ? AND hash < ?) OR size IS NULL) ");
+
+        // Ascending orders put NULLs first, so only values follow them.
+        let (q, params) = Db::file_cursor_apply(&cursor(sort(t::FileSort::Length, true), t::CursorValue::Null));
+        assert_eq!(q, " (length IS NOT NULL OR hash > ?) ");
+        assert_eq!(params.len(), 1);
+
+        let (q, params) = Db::file_cursor_apply(&cursor(sort(t::FileSort::Title, true), t::CursorValue::Null));
+        assert_eq!(q, " (title COLLATE NOCASE IS NULL AND hash > ?) ");
+        assert_eq!(params.len(), 1);
+    }
+
+    #[test]
+    fn file_tags_with_quotes() {
+        let con = connection();
+        let db = Db::new(&con);
+        let mut other = file("b", 1);
+        other.meta.tags = vec!["cat".to_string()];
+        db.file_persist(&other).unwrap();
+
+        let mut quoted = file("a", 1);
+        quoted.meta.tags = vec!["it's".to_string(), "cat".to_string()];
+        db.file_persist(&quoted).unwrap();
+        let tags = |hash: &str| {
+            let mut tags = db.file(hash).unwrap().meta.tags;
+            tags.sort();
+            tags
+        };
+        assert_eq!(tags("a"), vec!["cat".to_string(), "it's".to_string()]);
+
+        // Would delete the tags of every file if it was spliced into the query.
+        quoted.meta.tags = vec!["it's".to_string(), "x') OR 1=1 OR tag IN ('cat".to_string()];
+        db.file_persist(&quoted).unwrap();
+        assert_eq!(tags("a"), vec!["it's".to_string(), "x') OR 1=1 OR tag IN ('cat".to_string()]);
+        assert_eq!(tags("b"), vec!["cat".to_string()]);
+    }
+
+    #[test]
+    fn page_size_is_capped() {
+        let con = connection();
+        let db = Db::new(&con);
+        let max = FileQuery::MAX_PAGE_SIZE as usize;
+        for size in 0..max + 10 {
+            db.file_persist(&file(&format!("{:03}", size), size as i64)).unwrap();
+        }
+
+        let query = FileQuery {
+            page_size: FileQuery::MAX_PAGE_SIZE * 2,
+            ..FileQuery::default()
+        };
+        let page = db.files(query, false).unwrap();
+        assert_eq!(page.items.len(), max);
+        assert_eq!(page.page_size, FileQuery::MAX_PAGE_SIZE);
+        assert_eq!(page.total_pages, 2);
+    }
+
+    #[test]
+    fn text_search_ranks_title_matches_first() {
+        let con = connection();
+        let db = Db::new(&con);
+        let mut described = file("a", 1);
+        described.meta.description = Some("A sunset at the sea".to_string());
+        let mut titled = file("b", 1);
+        titled.meta.title = Some("Sunset".to_string());
+        let mut other = file("c", 1);
+        other.meta.title = Some("Sunrise".to_string());
+        for f in &[described, titled, other] {
+            db.file_persist(f).unwrap();
+        }
+
+        let query = FileQuery {
+            filter: Some(t::FileFilter::Text("sunset".to_string())),
+            ..FileQuery::default()
+        };
+        let page = db.files(query, false).unwrap();
+        assert_eq!(hashes(&page), vec!["b", "a"]);
+        let scores = page.scores.unwrap();
+        assert_eq!(scores.len(), 2);
+        assert!(scores[0] < scores[1]);
+    }
+
+    #[test]
+    fn files_include_and_exclude_hashes() {
+        let con = connection();
+        let db = Db::new(&con);
+        for hash in &["a", "b", "c", "d", "e"] {
+            let mut f = file(hash, 1);
+            if *hash != "d" {
+                f.meta.tags = vec!["cat".to_string()];
+            }
+            db.file_persist(&f).unwrap();
+        }
+        let list = |hashes: &[&str]| Some(hashes.iter().map(|h| h.to_string()).collect::<Vec<_>>());
+        let sorted = |query: FileQuery| {
+            let mut hashes = hashes(&db.files(query, false).unwrap());
+            hashes.sort();
+            hashes
+        };
+
+        let query = FileQuery {
+            exclude_hashes: list(&["b", "d"]),
+            ..FileQuery::default()
+        };
+        assert_eq!(sorted(query), vec!["a", "c", "e"]);
+
+        // Composes with the filter.
+        let query = FileQuery {
+            filter: Some(t::FileFilter::Tag("cat".to_string())),
+            include_hashes: list(&["b", "d", "missing"]),
+            ..FileQuery::default()
+        };
+        assert_eq!(sorted(query), vec!["b"]);
+    }
+
+    #[test]
+    fn files_in_a_bounding_box() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, location) in &[
+            ("vienna", Some((48.2, 16.4))),
+            ("berlin", Some((52.5, 13.4))),
+            ("unknown", None),
+        ] {
+            let mut f = file(hash, 1);
+            f.info.location = location.map(|(lat, lon)| t::GeoLocation { lat, lon });
+            db.file_persist(&f).unwrap();
+        }
+
+        let query = FileQuery {
+            filter: Some(t::FileFilter::BoundingBox {
+                min_lat: 47.0,
+                max_lat: 49.0,
+                min_lon: 9.0,
+                max_lon: 17.0,
+            }),
+            ..FileQuery::default()
+        };
+        assert_eq!(hashes(&db.files(query, false).unwrap()), vec!["vienna"]);
+
+        let geo = db.files_geo(false).unwrap();
+        let geo = geo.iter().map(|f| f.info.hash.as_str()).collect::<Vec<_>>();
+        assert_eq!(geo, vec!["berlin", "vienna"]);
+    }
+
+    #[test]
+    fn files_total_size_only_with_totals() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, size) in &[("a", 10), ("b", 20), ("c", 5)] {
+            let mut f = file(hash, *size);
+            if *hash != "c" {
+                f.meta.tags = vec!["cat".to_string()];
+            }
+            db.file_persist(&f).unwrap();
+        }
+
+        let query = FileQuery {
+            page_size: 1,
+            filter: Some(t::FileFilter::Tag("cat".to_string())),
+            ..FileQuery::default()
+        };
+        // The sum covers all matches, not just the page.
+        assert_eq!(db.files(query.clone(), true).unwrap().total_size, Some(30));
+        assert_eq!(db.files(query, false).unwrap().total_size, None);
+    }
+
+    #[test]
+    fn build_pool_uses_the_given_path() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("custom.sqlite3");
+
+        let pool = build_pool(path.to_str().unwrap()).unwrap();
+        Db::new(&pool.get().unwrap()).migrate().unwrap();
+        let exists = path.exists();
+        drop(pool);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(exists);
+    }
+
+    #[test]
+    fn media_info_is_read_back() {
+        let con = connection();
+        let db = Db::new(&con);
+        let mut image = file("image", 1);
+        image.info.media = Some(t::MediaInfo::Image(t::ImageInfo { width: 40, height: 30 }));
+        let mut video = file("video", 1);
+        video.info.kind = t::FileKind::Video;
+        video.info.media = Some(t::MediaInfo::Video(t::VideoInfo { width: 1920, height: 1080, length: 60 }));
+        let mut audio = file("audio", 1);
+        audio.info.kind = t::FileKind::Audio;
+        audio.info.media = Some(t::MediaInfo::Audio(t::AudioInfo { length: 180 }));
+        for f in &[image, video, audio, file("none", 1)] {
+            db.file_persist(f).unwrap();
+        }
+
+        match db.file("image").unwrap().info.media {
+            Some(t::MediaInfo::Image(ref i)) => assert_eq!((i.width, i.height), (40, 30)),
+            ref other => panic!("expected image info, got {:?}", other),
+        }
+        match db.file("video").unwrap().info.media {
+            Some(t::MediaInfo::Video(ref v)) => assert_eq!((v.width, v.height, v.length), (1920, 1080, 60)),
+            ref other => panic!("expected video info, got {:?}", other),
+        }
+        match db.file("audio").unwrap().info.media {
+            Some(t::MediaInfo::Audio(ref a)) => assert_eq!(a.length, 180),
+            ref other => panic!("expected audio info, got {:?}", other),
+        }
+        assert!(db.file("none").unwrap().info.media.is_none());
+    }
+
+    #[test]
+    fn files_in_a_size_range() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, size) in &[("a", 10), ("b", 1_000), ("c", 20_000_000)] {
+            db.file_persist(&file(hash, *size)).unwrap();
+        }
+        let sorted = |min: Option<i64>, max: Option<i64>| {
+            let query = FileQuery {
+                filter: Some(t::FileFilter::SizeRange { min, max }),
+                ..FileQuery::default()
+            };
+            let mut hashes = hashes(&db.files(query, false).unwrap());
+            hashes.sort();
+            hashes
+        };
+
+        assert_eq!(sorted(Some(10 * 1024 * 1024), None), vec!["c"]);
+        assert_eq!(sorted(None, Some(1_000)), vec!["a", "b"]);
+        // Bounds are inclusive.
+        assert_eq!(sorted(Some(10), Some(1_000)), vec!["a", "b"]);
+        assert_eq!(sorted(Some(11), Some(999)), Vec::<String>::new());
+        assert_eq!(sorted(None, None), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn untagged_filter_composes() {
+        let con = connection();
+        let db = Db::new(&con);
+        let mut tagged = file("a", 1);
+        tagged.meta.tags = vec!["cat".to_string()];
+        let mut large = file("c", 100);
+        large.meta.tags = vec!["dog".to_string()];
+        for f in &[tagged, file("b", 1), large, file("d", 100)] {
+            db.file_persist(f).unwrap();
+        }
+        let sorted = |filter: t::FileFilter| {
+            let query = FileQuery { filter: Some(filter), ..FileQuery::default() };
+            let mut hashes = hashes(&db.files(query, false).unwrap());
+            hashes.sort();
+            hashes
+        };
+        let large_files = || t::FileFilter::SizeRange { min: Some(50), max: None };
+
+        assert_eq!(sorted(t::FileFilter::Untagged), vec!["b", "d"]);
+        assert_eq!(
+            sorted(t::FileFilter::And(Box::new(t::FileFilter::Untagged), Box::new(large_files()))),
+            vec!["d"],
+        );
+        assert_eq!(
+            sorted(t::FileFilter::Or(Box::new(t::FileFilter::Untagged), Box::new(t::FileFilter::Tag("cat".to_string())))),
+            vec!["a", "b", "d"],
+        );
+    }
+
+    #[test]
+    fn files_missing_media_info() {
+        let con = connection();
+        let db = Db::new(&con);
+        let mut with_info = file("a", 1);
+        with_info.info.media = Some(t::MediaInfo::Image(t::ImageInfo { width: 4, height: 3 }));
+        let mut video = file("c", 1);
+        video.info.kind = t::FileKind::Video;
+        video.info.mime = Some("video/mp4".to_string());
+        let mut other = file("d", 1);
+        other.info.kind = t::FileKind::Other;
+        other.info.mime = Some("text/plain".to_string());
+        for f in &[with_info, file("b", 1), video, other] {
+            db.file_persist(f).unwrap();
+        }
+
+        let query = FileQuery {
+            filter: Some(t::FileFilter::MissingMediaInfo),
+            ..FileQuery::default()
+        };
+        let mut filtered = hashes(&db.files(query, false).unwrap());
+        filtered.sort();
+        assert_eq!(filtered, vec!["b", "c"]);
+
+        let missing = db.files_missing_media_info(false).unwrap();
+        let missing = missing.iter().map(|f| f.info.hash.as_str()).collect::<Vec<_>>();
+        assert_eq!(missing, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn mime_filter_exact_and_wildcard() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, mime) in &[("a", "image/gif"), ("b", "image/png"), ("c", "video/webm"), ("d", "video/mp4")] {
+            let mut f = file(hash, 1);
+            f.info.mime = Some(mime.to_string());
+            db.file_persist(&f).unwrap();
+        }
+        let sorted = |mime: &str| {
+            let query = FileQuery {
+                filter: Some(t::FileFilter::Mime(mime.to_string())),
+                ..FileQuery::default()
+            };
+            let mut hashes = hashes(&db.files(query, false).unwrap());
+            hashes.sort();
+            hashes
+        };
+
+        assert_eq!(sorted("image/gif"), vec!["a"]);
+        assert_eq!(sorted("video/*"), vec!["c", "d"]);
+        assert_eq!(sorted("*"), vec!["a", "b", "c", "d"]);
+        assert_eq!(sorted("image/"), Vec::<String>::new());
+        // LIKE wildcards in the pattern are matched literally.
+        assert_eq!(sorted("image/_*"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn title_sort_ignores_case_and_puts_untitled_last() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, title) in &[("a", Some("banana")), ("b", Some("Apple")), ("c", None), ("d", Some("cherry"))] {
+            let mut f = file(hash, 1);
+            f.meta.title = title.map(|title| title.to_string());
+            db.file_persist(&f).unwrap();
+        }
+        let by_title = |ascending: bool| {
+            let query = FileQuery {
+                sort: vec![sort(t::FileSort::Title, ascending)],
+                ..FileQuery::default()
+            };
+            hashes(&db.files(query, false).unwrap())
+        };
+
+        assert_eq!(by_title(true), vec!["b", "a", "d", "c"]);
+        assert_eq!(by_title(false), vec!["d", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn random_sort_varies_and_wins_over_later_sorts() {
+        let con = connection();
+        let db = Db::new(&con);
+        for size in 0..20 {
+            db.file_persist(&file(&format!("{:02}", size), size)).unwrap();
+        }
+        let query = FileQuery {
+            sort: vec![sort(t::FileSort::Random, true), sort(t::FileSort::Size, true)],
+            ..FileQuery::default()
+        };
+
+        let (q, _) = Db::build_files_query(&query).unwrap();
+        assert!(q.contains("ORDER BY RANDOM() LIMIT"));
+        let first = db.files(query.clone(), false).unwrap();
+        assert!(first.next_cursor.is_none());
+
+        // The chance of 20 items coming back in the same order every time is negligible.
+        let varies = (0..10).any(|_| hashes(&db.files(query.clone(), false).unwrap()) != hashes(&first));
+        assert!(varies);
+    }
+
+    #[test]
+    fn tags_with_counts() {
+        let con = connection();
+        let db = Db::new(&con);
+        for (hash, tags) in &[("a", vec!["cat", "dog"]), ("b", vec!["cat"]), ("c", vec!["cat", "bird", "dog"])] {
+            let mut f = file(hash, 1);
+            f.meta.tags = tags.iter().map(|tag| tag.to_string()).collect();
+            db.file_persist(&f).unwrap();
+        }
+
+        let expected = vec![("cat".to_string(), 3), ("dog".to_string(), 2), ("bird".to_string(), 1)];
+        assert_eq!(db.tags(None, false).unwrap(), expected);
+        assert_eq!(db.tags(Some(2), false).unwrap(), expected[..2].to_vec());
+    }
+
+    #[test]
+    fn gallery_create_read_update_delete() {
+        let con = connection();
+        let db = Db::new(&con);
+        for hash in &["a", "b", "c"] {
+            db.file_persist(&file(hash, 1)).unwrap();
+        }
+        let gallery = |title: &str, hashes: &[&str]| st::Gallery {
+            path: "g.gallery.yaml".to_string(),
+            title: title.to_string(),
+            description: None,
+            items: hashes.iter().map(|hash| st::GalleryItem { path: format!("{}.jpg", hash), _hash: None }).collect(),
+        };
+        let paths = |gallery: &st::Gallery| gallery.items.iter().map(|item| item.path.clone()).collect::<Vec<_>>();
+        let item_count = || -> i64 {
+            con.query_row("SELECT COUNT(*) FROM gallery_items", NO_PARAMS, |row| row.get(0)).unwrap()
+        };
+
+        db.gallery_persist(&gallery("G", &["c", "a", "b"])).unwrap();
+        let stored = db.gallery("g.gallery.yaml", false).unwrap().unwrap();
+        assert_eq!(stored.title, "G");
+        assert_eq!(paths(&stored), vec!["c.jpg", "a.jpg", "b.jpg"]);
+
+        db.gallery_persist(&gallery("H", &["b", "c"])).unwrap();
+        let galleries = db.galleries(false).unwrap();
+        assert_eq!(galleries.len(), 1);
+        assert_eq!(galleries[0].title, "H");
+        assert_eq!(paths(&galleries[0]), vec!["b.jpg", "c.jpg"]);
+        assert_eq!(item_count(), 2);
+
+        db.gallery_delete("g.gallery.yaml").unwrap();
+        assert!(db.gallery("g.gallery.yaml", false).unwrap().is_none());
+        assert_eq!(item_count(), 0);
+    }
+
+    #[test]
+    fn kinds_filter_matches_any_of_the_kinds() {
+        let con = connection();
+        let db = Db::new(&con);
+        let kinds = [
+            ("a", t::FileKind::Image),
+            ("b", t::FileKind::Video),
+            ("c", t::FileKind::Audio),
+            ("d", t::FileKind::Other),
+            ("e", t::FileKind::Image),
+        ];
+        for (hash, kind) in &kinds {
+            let mut f = file(hash, 1);
+            f.info.kind = *kind;
+            db.file_persist(&f).unwrap();
+        }
+        let sorted = |filter: t::FileFilter| {
+            let query = FileQuery {
+                filter: Some(filter),
+                ..FileQuery::default()
+            };
+            let mut hashes = hashes(&db.files(query, false).unwrap());
+            hashes.sort();
+            hashes
+        };
+
+        assert_eq!(sorted(t::FileFilter::Kinds(vec![t::FileKind::Image, t::FileKind::Video])), vec!["a", "b", "e"]);
+        assert_eq!(sorted(t::FileFilter::Kinds(vec![t::FileKind::Audio])), vec!["c"]);
+        assert_eq!(sorted(t::FileFilter::Kinds(Vec::new())), Vec::<String>::new());
+        assert_eq!(
+            sorted(t::FileFilter::Not(Box::new(t::FileFilter::Kinds(Vec::new())))),
+            vec!["a", "b", "c", "d", "e"],
+        );
+    }
 }
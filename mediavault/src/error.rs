@@ -0,0 +1,44 @@
+use std::fmt;
+
+/// Errors that clients should be able to tell apart. The server maps them
+/// to HTTP status codes.
+///
+/// They travel as `failure::Error` like any other error. Errors of other
+/// types are treated as `Internal`.
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    Conflict(String),
+    BadRequest(String),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn not_found() -> Self {
+        AppError::NotFound(String::new())
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            AppError::NotFound(m) | AppError::Conflict(m) | AppError::BadRequest(m) | AppError::Internal(m) => m,
+        };
+        if message.is_empty() {
+            write!(f, "{}", self.code())
+        } else {
+            write!(f, "{}", message)
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
@@ -0,0 +1,71 @@
+use crate::prelude::*;
+
+/// Fetch the body of a url as text.
+pub fn fetch_text(url: &str) -> Result<String, Error> {
+    let mut res = reqwest::get(url)?.error_for_status()?;
+    Ok(res.text()?)
+}
+
+/// Fetch the body of a url as raw bytes.
+pub fn fetch_bytes(url: &str) -> Result<Vec<u8>, Error> {
+    let mut res = reqwest::get(url)?.error_for_status()?;
+    let mut data = Vec::new();
+    res.copy_to(&mut data)?;
+    Ok(data)
+}
+
+/// Suffixes of files the storage treats specially. Downloads must never
+/// create them: a remote server could otherwise plant an importer script,
+/// which is executed, or override the metadata of other files.
+const RESERVED_SUFFIXES: &[&str] = &[".importer.js", ".gallery.yaml", ".meta.yaml", ".vtt", ".srt"];
+
+/// Derive a file name from the last path segment of a url.
+///
+/// Characters other than alphanumerics, `.`, `-` and `_` are replaced.
+/// Names with a reserved suffix get `.download` appended.
+pub fn file_name(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let name = url.path_segments()?.last()?
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' })
+        .collect::<String>();
+    if name.is_empty() || name.starts_with('.') {
+        return None;
+    }
+
+    let lower = name.to_lowercase();
+    if RESERVED_SUFFIXES.iter().any(|suffix| lower.ends_with(suffix)) {
+        Some(format!("{}.download", name))
+    } else {
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_name_from_url() {
+        assert_eq!(file_name("https://example.com/a/cat.jpg?x=1"), Some("cat.jpg".to_string()));
+        assert_eq!(file_name("https://example.com/a/my%20cat.jpg"), Some("my_20cat.jpg".to_string()));
+        assert_eq!(file_name("https://example.com/"), None);
+        assert_eq!(file_name("https://example.com/.hidden"), None);
+    }
+
+    #[test]
+    fn file_name_renames_reserved_suffixes() {
+        assert_eq!(
+            file_name("https://example.com/x.importer.js"),
+            Some("x.importer.js.download".to_string()),
+        );
+        assert_eq!(
+            file_name("https://example.com/cat.jpg.meta.yaml"),
+            Some("cat.jpg.meta.yaml.download".to_string()),
+        );
+        assert_eq!(file_name("https://example.com/a.GALLERY.YAML"), Some("a.GALLERY.YAML.download".to_string()));
+        assert_eq!(file_name("https://example.com/movie.en.vtt"), Some("movie.en.vtt.download".to_string()));
+        assert_eq!(file_name("https://example.com/movie.srt"), Some("movie.srt.download".to_string()));
+        assert_eq!(file_name("https://example.com/script.js"), Some("script.js".to_string()));
+    }
+}
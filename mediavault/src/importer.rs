@@ -0,0 +1,138 @@
+use failure::format_err;
+use mediavault_common::types::ImporterOutput;
+use std::{
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{prelude::*, storage::Importer};
+
+/// Input passed to an importer script.
+#[derive(Serialize, Clone, Debug)]
+pub struct ImporterInput {
+    pub url: String,
+    pub html: String,
+}
+
+/// Executes importer scripts.
+///
+/// An importer defines a `run(input)` function that receives the
+/// `ImporterInput` and returns a list of file sources, or `null` if it can
+/// not handle the input.
+pub trait Engine: Send + Sync {
+    fn run(&self, importer: &Importer, input: &ImporterInput) -> Result<ImporterOutput, Error>;
+}
+
+const SCRIPT_PRELUDE: &str = r#"
+var input = JSON.parse(require("fs").readFileSync(0, "utf8"));
+var output;
+try {
+    var result = (function() {
+"#;
+
+const SCRIPT_EPILOGUE: &str = r#"
+        ;
+        return run(input);
+    })();
+    if (result === null || result === undefined) {
+        output = "NoMatch";
+    } else {
+        output = { Ok: result.map(function(source) { return { File: source }; }) };
+    }
+} catch (e) {
+    output = { Err: String(e) };
+}
+process.stdout.write(JSON.stringify(output));
+"#;
+
+/// Time an importer may run by default before it is killed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs importers with a `node` binary.
+///
+/// The script is wrapped so that it reads the input from stdin and writes a
+/// serialized `ImporterOutput` to stdout.
+///
+/// Importer scripts are fully trusted code: they run as a regular node
+/// process with the permissions of the server, including access to the file
+/// system, the network and other processes. Only put scripts into storage
+/// that you would run yourself. Scripts are killed once they exceed
+/// `timeout`.
+pub struct NodeEngine {
+    pub binary: String,
+    pub timeout: Duration,
+}
+
+impl Default for NodeEngine {
+    fn default() -> Self {
+        NodeEngine {
+            binary: "node".to_string(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// Read a pipe to the end on a separate thread, so a child process never
+/// blocks on a full pipe.
+fn read_to_end<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data)?;
+        Ok(data)
+    })
+}
+
+impl Engine for NodeEngine {
+    fn run(&self, importer: &Importer, input: &ImporterInput) -> Result<ImporterOutput, Error> {
+        let script = format!("{}{}{}", SCRIPT_PRELUDE, importer.content, SCRIPT_EPILOGUE);
+
+        let mut child = Command::new(&self.binary)
+            .arg("-e")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Dropping stdin after writing closes it, so the script sees EOF.
+        let mut stdin = child.stdin.take().unwrap();
+        let input = serde_json::to_vec(input)?;
+        let writer = thread::spawn(move || stdin.write_all(&input));
+        let stdout = read_to_end(child.stdout.take().unwrap());
+        let stderr = read_to_end(child.stderr.take().unwrap());
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                // Fails if the script exited in the meantime.
+                let _ = child.kill();
+                child.wait()?;
+                return Err(format_err!(
+                    "importer {} timed out after {} seconds",
+                    importer.path,
+                    self.timeout.as_secs()
+                ));
+            }
+            thread::sleep(Duration::from_millis(20));
+        };
+
+        // Scripts that exit without reading their input break the pipe,
+        // which is not an error.
+        let _ = writer.join();
+        let stdout = stdout.join().map_err(|_| format_err!("could not read importer output"))??;
+        let stderr = stderr.join().map_err(|_| format_err!("could not read importer output"))??;
+        if !status.success() {
+            return Err(format_err!(
+                "importer {} failed: {}",
+                importer.path,
+                String::from_utf8_lossy(&stderr)
+            ));
+        }
+        Ok(serde_json::from_slice(&stdout)?)
+    }
+}
@@ -1,17 +1,21 @@
+mod access_log;
 mod app;
 mod db;
+mod error;
 mod prelude;
 mod storage;
 mod fetcher;
+mod importer;
 mod server;
+mod thumbs;
+mod watcher;
 
 fn main() {
-    let config = app::Config{
-        db_path: "db.sqlite3".into(),
-        storage_path: "data".into(),
-    };
+    let config_path = std::env::var("MEDIAVAULT_CONFIG").ok();
+    let config = app::Config::load(config_path.as_ref().map(|path| path.as_str())).unwrap();
     let app = app::App::new(config).unwrap();
     app.index().unwrap();
+    app.trash_purge().unwrap();
 
     server::run_server(app);
 }
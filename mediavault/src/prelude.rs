@@ -2,6 +2,8 @@ pub use failure::Error;
 pub use serde_derive::{Deserialize, Serialize};
 pub use uuid::Uuid;
 
+pub use crate::error::AppError;
+
 pub use mediavault_common::types::DateTime;
 
 pub fn now() -> DateTime {
@@ -1,4 +1,7 @@
-use futures::future::{self as f, Future, poll_fn};
+use futures::{
+    future::{self as f, Future, poll_fn},
+    Async, Poll, Stream,
+};
 use tokio_threadpool::blocking;
 use http::{Response, StatusCode};
 use warp::{path, Filter, filters};
@@ -7,40 +10,420 @@ use serde_json::{json};
 use mediavault_common::{
     types as t,
 };
+use std::{
+    fs,
+    io::{self, Read, Seek},
+};
 use crate::{
     prelude::*,
-    app::{self, App},
+    access_log,
+    app::App,
     storage,
+    thumbs,
 };
 
 fn res_err_json(err: Error) -> http::Response<hyper::Body> {
+    let status = match err.downcast_ref::<AppError>() {
+        Some(AppError::NotFound(_)) => StatusCode::NOT_FOUND,
+        Some(AppError::Conflict(_)) => StatusCode::CONFLICT,
+        Some(AppError::BadRequest(_)) => StatusCode::BAD_REQUEST,
+        Some(AppError::Internal(_)) | None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
     let data = serde_json::to_vec(&json!({
         "message": format!("{}", err),
     })).unwrap();
 
     Response::builder()
-        .status(StatusCode::from_u16(500).unwrap())
+        .status(status)
         .header("content-type", "application/json")
         .body(Body::from(data))
         .unwrap()
 }
 
+/// Part of a media file requested with a `Range` header.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ByteRange {
+    Full,
+    /// Inclusive start and end offsets.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parse a `Range` header for content of `len` bytes.
+    ///
+    /// Only a single range is supported. Headers that can't be parsed or
+    /// that ask for several ranges are ignored, as allowed by RFC 7233, so
+    /// the whole content is sent.
+    fn parse(header: Option<&str>, len: u64) -> ByteRange {
+        let header = match header {
+            Some(header) => header.trim(),
+            None => return ByteRange::Full,
+        };
+        if !header.starts_with("bytes=") || header.contains(',') {
+            return ByteRange::Full;
+        }
+        let mut parts = header["bytes=".len()..].splitn(2, '-');
+        let (start, end) = match (parts.next(), parts.next()) {
+            (Some(start), Some(end)) => (start.trim(), end.trim()),
+            _ => return ByteRange::Full,
+        };
+
+        match (start.parse::<u64>(), end.parse::<u64>()) {
+            // The last `end` bytes.
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 || len == 0 {
+                    ByteRange::Unsatisfiable
+                } else {
+                    ByteRange::Partial(len.saturating_sub(suffix), len - 1)
+                }
+            }
+            (Ok(start), Err(_)) if end.is_empty() => {
+                if start >= len {
+                    ByteRange::Unsatisfiable
+                } else {
+                    ByteRange::Partial(start, len - 1)
+                }
+            }
+            (Ok(start), Ok(end)) => {
+                if start > end {
+                    ByteRange::Full
+                } else if start >= len {
+                    ByteRange::Unsatisfiable
+                } else {
+                    ByteRange::Partial(start, end.min(len - 1))
+                }
+            }
+            _ => ByteRange::Full,
+        }
+    }
+}
+
+/// Conditional and range headers of a media request.
+#[derive(Clone, Debug, Default)]
+struct MediaHeaders {
+    range: Option<String>,
+    if_none_match: Option<String>,
+}
+
+fn media_headers() -> impl Filter<Extract = (MediaHeaders,), Error = warp::reject::Rejection> + Clone {
+    warp::header::optional::<String>("range")
+        .and(warp::header::optional::<String>("if-none-match"))
+        .map(|range, if_none_match| MediaHeaders { range, if_none_match })
+}
+
+/// Response for a media file. HEAD requests get the same headers without a body.
+///
+/// A `Range` header selects a part of the file, sent with 206 Partial Content.
+/// Files with an ETag get 304 Not Modified if the client has them already.
+fn res_media(media: storage::Media, with_body: bool, headers: &MediaHeaders) -> http::Response<hyper::Body> {
+    let mime = media.mime.unwrap_or_else(|| "application/octet-stream".to_string());
+    let total = media.content.len();
+
+    // Visibility may change, so clients have to revalidate.
+    let cache_control = "private, no-cache";
+    if let Some(etag) = media.etag.as_ref() {
+        if etag_matches(headers.if_none_match.as_ref(), etag) {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header("etag", etag.as_str())
+                .header("cache-control", cache_control)
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+
+    let mut builder = Response::builder();
+    let (start, len) = match ByteRange::parse(headers.range.as_ref().map(|range| range.as_str()), total) {
+        ByteRange::Full => {
+            builder.status(StatusCode::OK);
+            (0, total)
+        }
+        ByteRange::Partial(start, end) => {
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("content-range", format!("bytes {}-{}/{}", start, end, total).as_str());
+            (start, end - start + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("content-range", format!("bytes */{}", total).as_str())
+                .header("accept-ranges", "bytes")
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+    builder
+        .header("content-type", mime.as_str())
+        .header("content-length", len.to_string().as_str())
+        .header("content-disposition", "inline")
+        .header("accept-ranges", "bytes")
+        .header("cache-control", cache_control)
+        .header("x-content-type-options", "nosniff");
+    if let Some(etag) = media.etag.as_ref() {
+        builder.header("etag", etag.as_str());
+    }
+    if let Some((algo, hash)) = media.hash.as_ref() {
+        builder.header(format!("x-content-{}", algo.name()).as_str(), hash.as_str());
+    }
+    if mime == "image/svg+xml" {
+        // SVGs can embed scripts, which would run on our origin when the
+        // file is opened directly.
+        builder.header("content-security-policy", "default-src 'none'; style-src 'unsafe-inline'");
+    }
+    let body = if with_body {
+        match media_body(media.content, start, len) {
+            Ok(body) => body,
+            Err(e) => return res_err_json(e.into()),
+        }
+    } else {
+        Body::empty()
+    };
+    builder
+        .body(body)
+        .unwrap()
+}
+
+/// Body with `len` bytes of the content, starting at `start`.
+///
+/// Files are streamed, so large videos are never held in memory.
+fn media_body(content: storage::MediaContent, start: u64, len: u64) -> io::Result<Body> {
+    match content {
+        storage::MediaContent::Data(data) => {
+            let (start, end) = (start as usize, (start + len) as usize);
+            Ok(Body::from(data[start..end].to_vec()))
+        }
+        storage::MediaContent::File(mut file, _) => {
+            file.seek(io::SeekFrom::Start(start))?;
+            Ok(Body::wrap_stream(FileChunks { file, remaining: len }))
+        }
+    }
+}
+
+/// Bytes read from a file per body chunk.
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Stream of the next `remaining` bytes of a file. Reads run on the blocking
+/// pool, like the request handlers.
+struct FileChunks {
+    file: fs::File,
+    remaining: u64,
+}
+
+impl Stream for FileChunks {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None));
+        }
+        let size = self.remaining.min(CHUNK_SIZE) as usize;
+        let file = &mut self.file;
+        let res = blocking(|| -> io::Result<Vec<u8>> {
+            let mut chunk = vec![0; size];
+            let read = file.read(&mut chunk)?;
+            chunk.truncate(read);
+            Ok(chunk)
+        });
+        match res {
+            Ok(Async::Ready(Ok(ref chunk))) if chunk.is_empty() => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than expected"))
+            }
+            Ok(Async::Ready(Ok(chunk))) => {
+                self.remaining -= chunk.len() as u64;
+                Ok(Async::Ready(Some(chunk)))
+            }
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+        }
+    }
+}
+
+/// Decode a path taken from the url into a storage path.
+///
+/// Segments are percent-decoded, so files with spaces or non-ascii names can
+/// be served. Empty segments from duplicate or trailing slashes are dropped.
+/// Returns `None` if the decoded path is not a plain relative path.
+fn media_path(raw: &str) -> Option<String> {
+    let decoded = percent_encoding::percent_decode(raw.as_bytes())
+        .decode_utf8()
+        .ok()?;
+    let path = decoded
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/");
+    if path.is_empty() || !storage::Storage::is_relative(&path) {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+fn media_response(
+    app: App,
+    path: String,
+    authed: bool,
+    with_body: bool,
+    verify: bool,
+    headers: MediaHeaders,
+) -> impl Future<Item = Response<Body>, Error = warp::reject::Rejection> {
+    let path = media_path(&path);
+    poll_fn(move || blocking(|| match path.as_ref() {
+        Some(path) if verify => app.media_verified(path, authed),
+        Some(path) => app.media(path, authed),
+        None => Ok(None),
+    }))
+        .then(move |res| -> Result<Response<Body>, warp::reject::Rejection> {
+            match res {
+                Ok(Ok(Some(media))) => Ok(res_media(media, with_body, &headers)),
+                Ok(Ok(None)) => Err(warp::reject::not_found()),
+                Ok(Err(e)) => Ok(res_err_json(e)),
+                Err(e) => Ok(res_err_json(Error::from(e))),
+            }
+        })
+}
+
+/// Thumbnail response. The format depends on the `Accept` header, so caches
+/// have to keep one entry per format.
+fn thumb_response(
+    app: App,
+    hash: String,
+    authed: bool,
+    size: Option<u32>,
+    accept: Option<String>,
+) -> impl Future<Item = Response<Body>, Error = warp::reject::Rejection> {
+    let format = thumbs::ThumbFormat::negotiate(accept.as_ref().map(|accept| accept.as_str()));
+    poll_fn(move || blocking(|| app.thumbnail(&hash, size, format, authed)))
+        .then(move |res| -> Result<Response<Body>, warp::reject::Rejection> {
+            match res {
+                Ok(Ok(Some(media))) => {
+                    let mut response = res_media(media, true, &MediaHeaders::default());
+                    response.headers_mut().insert("vary", http::header::HeaderValue::from_static("accept"));
+                    Ok(response)
+                }
+                Ok(Ok(None)) => Err(warp::reject::not_found()),
+                Ok(Err(e)) => Ok(res_err_json(e)),
+                Err(e) => Ok(res_err_json(Error::from(e))),
+            }
+        })
+}
+
+/// Files query response, flat or wrapped in an envelope.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum FilesResponse {
+    Page(t::FilesPage),
+    Envelope(t::FilesEnvelope),
+}
+
+impl FilesResponse {
+    fn new(page: t::FilesPage, envelope: bool) -> Self {
+        if envelope {
+            FilesResponse::Envelope(page.into())
+        } else {
+            FilesResponse::Page(page)
+        }
+    }
+}
+
+/// Weak ETag of a files page, derived from the hashes and modification times
+/// of the items. Changes whenever the response would.
+fn files_etag(page: &t::FilesPage, format: Format, envelope: bool) -> String {
+    let mut ctx = md5::Context::new();
+    ctx.consume(format!("{:?}:{}:{}:{}:{}:{:?}\n", format, envelope, page.total, page.page, page.page_size, page.total_size));
+    for file in &page.items {
+        ctx.consume(&file.info.hash);
+        ctx.consume(format!(":{:?}\n", file.info.updated_at));
+    }
+    format!("W/\"{:x}\"", ctx.compute())
+}
+
+/// Whether an `If-None-Match` header lists the ETag. Uses the weak
+/// comparison, which ignores `W/` prefixes.
+fn etag_matches(if_none_match: Option<&String>, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .map(|header| header.split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        }))
+        .unwrap_or(false)
+}
+
+/// Respond with a files page, or with 304 if the client has it already.
+fn res_files(
+    format: Format,
+    page: t::FilesPage,
+    envelope: bool,
+    if_none_match: Option<&String>,
+) -> Result<http::Response<hyper::Body>, Error> {
+    let etag = files_etag(&page, format, envelope);
+    if etag_matches(if_none_match, &etag) {
+        let response = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header("etag", etag.as_str())
+            .body(Body::empty())
+            .unwrap();
+        return Ok(response);
+    }
+
+    let mut response = res_data(format, &FilesResponse::new(page, envelope))?;
+    response.headers_mut().insert("etag", http::header::HeaderValue::from_str(&etag)?);
+    Ok(response)
+}
+
+/// Serialization format of API responses.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Format {
+    Json,
+    Yaml,
+}
+
+/// Picks the response format from the `Accept` header, defaulting to JSON.
+fn response_format() -> impl Filter<Extract = (Format,), Error = warp::reject::Rejection> + Clone {
+    warp::header::optional::<String>("accept")
+        .map(|accept: Option<String>| {
+            let accept = accept.unwrap_or_default();
+            let is_yaml = ["application/yaml", "application/x-yaml", "text/yaml"]
+                .iter()
+                .any(|mime| accept.contains(mime));
+            if is_yaml {
+                Format::Yaml
+            } else {
+                Format::Json
+            }
+        })
+}
+
+fn res_data<T: serde::Serialize>(format: Format, data: &T) -> Result<http::Response<hyper::Body>, Error> {
+    let (content_type, body) = match format {
+        Format::Json => ("application/json", serde_json::to_vec(data)?),
+        Format::Yaml => ("application/yaml", serde_yaml::to_vec(data)?),
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::from_u16(200).unwrap())
+        .header("content-type", content_type)
+        .body(Body::from(body))
+        .unwrap();
+    Ok(response)
+}
+
+/// Wraps a blocking app call into a request handler.
+///
+/// The route must provide a `Format` as the last extracted value.
 macro_rules! api_blocking {
     ($app_name:ident : $app_value:expr; | $( $aname:ident : $aty:ty ),* | $e:expr) => {
-        move | $( $aname : $aty, )* | {
+        move | $( $aname : $aty, )* format: Format | {
             let $app_name = $app_value.clone();
             poll_fn(move || blocking(|| {
                 let res = $e;
                 match res {
-                    Ok(data) => {
-                        let js = serde_json::to_vec(&data).unwrap();
-                        let response = Response::builder()
-                            .status(StatusCode::from_u16(200).unwrap())
-                            .header("content-type", "application/json")
-                            .body(Body::from(js))
-                            .unwrap();
-                        Ok(response)
-                    },
+                    Ok(data) => res_data(format, &data),
                     Err(e) => Err(e)
                 }
             }))
@@ -53,15 +436,110 @@ macro_rules! api_blocking {
                 })
         }
     };
+    // For handlers that build the response themselves.
+    // The format is not appended, it can be listed as an argument instead.
+    ($app_name:ident : $app_value:expr; response | $( $aname:ident : $aty:ty ),* | $e:expr) => {
+        move | $( $aname : $aty, )* | {
+            let $app_name = $app_value.clone();
+            poll_fn(move || blocking(|| $e))
+                .then(|res| -> Result<Response<Body>, warp::reject::Rejection> {
+                    match res {
+                        Ok(Ok(t)) => Ok(t),
+                        Ok(Err(e)) => Ok(res_err_json(e)),
+                        Err(e) => Ok(res_err_json(Error::from(e))),
+                    }
+                })
+        }
+    };
 }
 
-pub fn run_server(app: App) {
+/// Extracts whether the request carries the configured auth token.
+fn authenticated(token: Option<String>) -> impl Filter<Extract = (bool,), Error = warp::reject::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .map(move |header: Option<String>| {
+            match token.as_ref() {
+                None => true,
+                Some(token) => header
+                    .map(|h| h == format!("Bearer {}", token))
+                    .unwrap_or(false),
+            }
+        })
+}
+
+/// Rejects requests that are not authenticated.
+fn auth_required(token: Option<String>) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
+    authenticated(token)
+        .and_then(|authed: bool| {
+            if authed {
+                Ok(())
+            } else {
+                Err(warp::reject::forbidden())
+            }
+        })
+        .untuple_one()
+}
+
+/// Turns a not found rejection of the API routes into a JSON error, so
+/// unknown API paths don't get the frontend. Other rejections, e.g. for a
+/// wrong method or an invalid body, are passed on.
+fn api_not_found(err: warp::reject::Rejection) -> Result<http::Response<hyper::Body>, warp::reject::Rejection> {
+    if err.status() == StatusCode::NOT_FOUND {
+        Ok(res_err_json(AppError::not_found().into()))
+    } else {
+        Err(err)
+    }
+}
+
+/// All routes of the server, without logging.
+pub fn routes(app: App) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::reject::Rejection> + Clone {
+    let authed = authenticated(app.config.auth_token.clone());
+    let auth = auth_required(app.config.auth_token.clone());
+    let format = response_format();
+
+    // Client config.
+    let a = app.clone();
+    let api_config = path!("api" / "config")
+        .and(filters::method::get2())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            Ok::<_, Error>(app.client_config())
+        }});
+
     // File.
     let a = app.clone();
     let api_file = path!("api" / "file" / String)
         .and(filters::method::get2())
-        .and_then(api_blocking!{ app : a.clone(); |hash: String| {
-            app.file(&hash)
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |hash: String, authed: bool| {
+            app.file(&hash, authed)
+        }});
+
+    // Number of files matching a filter.
+    let a = app.clone();
+    let api_files_count = path!("api" / "files" / "count")
+        .and(filters::method::post2())
+        .and(warp::body::json::<Option<t::FileFilter>>())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |filter: Option<t::FileFilter>, authed: bool| {
+            app.files_count(filter.clone(), authed)
+        }});
+
+    // Files, with a query string which may ask for an envelope or totals.
+    // warp::query rejects requests without a query string, so the plain
+    // route below handles those.
+    let a = app.clone();
+    let api_files_query = path!("api" / "files")
+        .and(filters::method::post2())
+        .and(warp::query::<t::FilesParams>())
+        .and(warp::body::json::<t::FileQuery>())
+        .and(authed.clone())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); response |params: t::FilesParams, q: t::FileQuery, authed: bool, if_none_match: Option<String>, format: Format| {
+            app.files(q.clone(), authed, params.totals())
+                .and_then(|page| res_files(format, page, params.envelope(), if_none_match.as_ref()))
         }});
 
     // Files.
@@ -69,41 +547,578 @@ pub fn run_server(app: App) {
     let api_files = path!("api" / "files")
         .and(filters::method::post2())
         .and(warp::body::json::<t::FileQuery>())
-        .and_then(api_blocking!{ app : a.clone(); |q: t::FileQuery| {
-            app.files(q.clone())
+        .and(authed.clone())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); response |q: t::FileQuery, authed: bool, if_none_match: Option<String>, format: Format| {
+            app.files(q.clone(), authed, false)
+                .and_then(|page| res_files(format, page, false, if_none_match.as_ref()))
+        }});
+
+    // Files with coordinates.
+    let a = app.clone();
+    let api_files_geo = path!("api" / "files" / "geo")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.files_geo(authed)
+        }});
+
+    // Files lacking media info.
+    let a = app.clone();
+    let api_files_missing_media_info = path!("api" / "files" / "missing-media-info")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.files_missing_media_info(authed)
+        }});
+
+    // Files in a custom order.
+    let a = app.clone();
+    let api_files_by_hashes = path!("api" / "files" / "by-hashes")
+        .and(filters::method::post2())
+        .and(warp::body::json::<t::FilesByHashes>())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::FilesByHashes, authed: bool| {
+            app.files_by_hashes(data.clone(), authed)
+        }});
+
+    // Files added by the last index run.
+    let a = app.clone();
+    let api_files_last_indexed = path!("api" / "files" / "last-indexed")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.files_last_indexed(authed)
+        }});
+
+    // Featured files.
+    let a = app.clone();
+    let api_featured = path!("api" / "featured")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.files_featured(authed)
+        }});
+
+    // Stats.
+    let a = app.clone();
+    let api_stats = path!("api" / "stats")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.stats(authed)
+        }});
+
+    let a = app.clone();
+    let api_duplicates = path!("api" / "duplicates")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.duplicates()
         }});
 
     // File update.
     let a = app.clone();
     let api_file_update = path!("api" / "file")
         .and(filters::method::put2())
+        .and(auth.clone())
         .and(warp::body::json::<t::FileUpdate>())
+        .and(format.clone())
         .and_then(api_blocking!{ app : a.clone(); |data: t::FileUpdate| {
             app.file_update(data.clone())
         }});
 
+    // Batch update.
+    let a = app.clone();
+    let api_files_update = path!("api" / "files")
+        .and(filters::method::put2())
+        .and(auth.clone())
+        .and(warp::body::json::<Vec<t::FileUpdate>>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |updates: Vec<t::FileUpdate>| {
+            app.files_update(updates.clone())
+        }});
+
+    // Partial file update.
+    let a = app.clone();
+    let api_file_patch = path!("api" / "file")
+        .and(filters::method::patch())
+        .and(auth.clone())
+        .and(warp::body::json::<t::FilePatch>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::FilePatch| {
+            app.file_patch(data.clone())
+        }});
+
+    // File move.
+    let a = app.clone();
+    let api_file_move = path!("api" / "file" / "move")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::FileMove>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::FileMove| {
+            app.file_move(&data.from, &data.to)
+        }});
+
     // File delete.
     let a = app.clone();
     let api_file_delete = path!("api" / "file" / String)
         .and(filters::method::delete2())
+        .and(auth.clone())
+        .and(format.clone())
         .and_then(api_blocking!{ app : a.clone(); |hash: String| {
             app.file_delete(&hash)
                 .map(|_| json!({}))
         }});
 
-    let api = api_file
+    // Trash.
+    let a = app.clone();
+    let api_trash_restore = path!("api" / "trash" / String / "restore")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |hash: String| {
+            app.file_restore(&hash)
+        }});
+
+    let a = app.clone();
+    let api_trash = path!("api" / "trash")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.trash()
+        }});
+
+    // Tags.
+    let a = app.clone();
+    let api_tags = path!("api" / "tags")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.tags(None, authed)
+        }});
+
+    // Most used tags.
+    let a = app.clone();
+    let api_tags_top = path!("api" / "tags" / "top")
+        .and(filters::method::get2())
+        .and(warp::query::<t::TopTagsQuery>())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |query: t::TopTagsQuery, authed: bool| {
+            let limit = query.limit
+                .unwrap_or(t::TopTagsQuery::DEFAULT_LIMIT)
+                .min(t::TopTagsQuery::MAX_LIMIT);
+            app.tags(Some(limit), authed)
+        }});
+
+    // Rename a tag on all files.
+    let a = app.clone();
+    let api_tags_rename = path!("api" / "tags" / "rename")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::TagRename>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::TagRename| {
+            app.tag_rename(data.clone())
+        }});
+
+    // Merge tags into one.
+    let a = app.clone();
+    let api_tags_merge = path!("api" / "tags" / "merge")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::TagMerge>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::TagMerge| {
+            app.tag_merge(data.clone())
+        }});
+
+    // Galleries.
+    let a = app.clone();
+    let api_galleries = path!("api" / "galleries")
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |authed: bool| {
+            app.galleries(authed)
+        }});
+
+    let gallery_path = warp::path::tail()
+        .and_then(|tail: warp::path::Tail| {
+            media_path(tail.as_str()).ok_or_else(warp::reject::not_found)
+        });
+
+    let a = app.clone();
+    let api_gallery = path!("api" / "gallery")
+        .and(filters::method::get2())
+        .and(gallery_path.clone())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |path: String, authed: bool| {
+            app.gallery(&path, authed)
+        }});
+
+    let a = app.clone();
+    let api_gallery_create = path!("api" / "gallery")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<storage::Gallery>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |gallery: storage::Gallery| {
+            app.gallery_create(gallery.clone())
+        }});
+
+    let a = app.clone();
+    let api_gallery_update = path!("api" / "gallery")
+        .and(filters::method::put2())
+        .and(auth.clone())
+        .and(warp::body::json::<storage::Gallery>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |gallery: storage::Gallery| {
+            app.gallery_update(gallery.clone())
+        }});
+
+    let a = app.clone();
+    let api_gallery_delete = path!("api" / "gallery")
+        .and(filters::method::delete2())
+        .and(gallery_path.clone())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |path: String| {
+            app.gallery_delete(&path)
+                .map(|_| json!({}))
+        }});
+
+    // Tag autocompletion.
+    let a = app.clone();
+    let api_tags_suggest = path!("api" / "tags" / "suggest")
+        .and(filters::method::get2())
+        .and(warp::query::<t::TagSuggestQuery>())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |query: t::TagSuggestQuery, authed: bool| {
+            let limit = query.limit
+                .unwrap_or(t::TagSuggestQuery::DEFAULT_LIMIT)
+                .min(t::TagSuggestQuery::MAX_LIMIT);
+            app.tag_suggest(&query.q, limit, authed)
+        }});
+
+    // Totals per folder.
+    let a = app.clone();
+    let api_folder_stats = path!("api" / "folders" / "stats")
+        .and(filters::method::get2())
+        .and(warp::query::<t::FolderStatsQuery>())
+        .and(authed.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |query: t::FolderStatsQuery, authed: bool| {
+            app.folder_stats(query.path.as_ref().map(|p| p.as_str()).unwrap_or(""), authed)
+        }});
+
+    // Storage tree.
+    let a = app.clone();
+    let api_tree = path!("api" / "tree")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(warp::query::<t::TreeQuery>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |q: t::TreeQuery| {
+            app.tree(q.path.as_ref().map(|p| p.as_str()))
+        }});
+
+    // Storage info.
+    let a = app.clone();
+    let api_storage_info = path!("api" / "storage" / "info")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(warp::query::<t::PathQuery>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |q: t::PathQuery| {
+            app.storage_info(&q.path)
+        }});
+
+    // Importers.
+    let a = app.clone();
+    let api_importers = path!("api" / "importers")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.importers()
+        }});
+
+    // Import preview.
+    let a = app.clone();
+    let api_import_run = path!("api" / "import" / "run")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::ImportRun>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |run: t::ImportRun| {
+            app.import_run(run.clone())
+        }});
+
+    // Import download.
+    let a = app.clone();
+    let api_import_fetch = path!("api" / "import" / "fetch")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::ImportFetch>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::ImportFetch| {
+            app.import_fetch(data.clone())
+        }});
+
+    // Attach sources to existing files.
+    let a = app.clone();
+    let api_import_backfill = path!("api" / "import" / "backfill")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::ImportBackfill>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::ImportBackfill| {
+            app.import_backfill(data.clone())
+        }});
+
+    // Raw importer output. Registered after the routes below /api/import.
+    let a = app.clone();
+    let api_import = path!("api" / "import")
+        .and(warp::path::end())
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::body::json::<t::Import>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |data: t::Import| {
+            app.import(&data.importer_path, &data.input)
+        }});
+
+    // Maintenance.
+    let a = app.clone();
+    let api_optimize = path!("api" / "maintenance" / "optimize")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.optimize()
+                .map(|_| json!({}))
+        }});
+
+    // Requests without a query string check the default size.
+    let a = app.clone();
+    let api_missing_thumbnails_query = path!("api" / "maintenance" / "missing-thumbnails")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(warp::query::<t::ThumbParams>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |params: t::ThumbParams| {
+            app.files_missing_thumbnails(params.size)
+        }});
+
+    let a = app.clone();
+    let api_missing_thumbnails = path!("api" / "maintenance" / "missing-thumbnails")
+        .and(filters::method::get2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.files_missing_thumbnails(None)
+        }});
+
+    let a = app.clone();
+    let api_prune_galleries = path!("api" / "maintenance" / "prune-galleries")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.galleries_prune()
+                .map(|pruned| json!({ "pruned": pruned }))
+        }});
+
+    let a = app.clone();
+    let api_purge_trash = path!("api" / "maintenance" / "purge-trash")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.trash_purge()
+        }});
+
+    let a = app.clone();
+    let api_rebuild_index = path!("api" / "maintenance" / "rebuild-index")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.rebuild_index()
+        }});
+
+    let a = app.clone();
+    let api_reindex = path!("api" / "reindex")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.reindex()
+        }});
+
+    // Requests without a query string are dry runs.
+    let a = app.clone();
+    let api_tags_from_filename_query = path!("api" / "maintenance" / "tags-from-filename")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(warp::query::<t::TagsFromFilenameParams>())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); |params: t::TagsFromFilenameParams| {
+            app.tags_from_filename(params.dry_run())
+        }});
+
+    let a = app.clone();
+    let api_tags_from_filename = path!("api" / "maintenance" / "tags-from-filename")
+        .and(filters::method::post2())
+        .and(auth.clone())
+        .and(format.clone())
+        .and_then(api_blocking!{ app : a.clone(); | | {
+            app.tags_from_filename(true)
+        }});
+
+    let api = api_config
+        .or(api_file)
+        .or(api_files_geo)
+        .or(api_files_last_indexed)
+        .or(api_files_missing_media_info)
+        .or(api_files_by_hashes)
+        // Must come before the files queries, which match the prefix.
+        .or(api_files_count)
+        .or(api_files_query)
         .or(api_files)
+        .or(api_featured)
+        .or(api_stats)
+        .or(api_duplicates)
         .or(api_file_update)
-        .or(api_file_delete);
+        .or(api_files_update)
+        .or(api_file_patch)
+        .or(api_file_move)
+        .or(api_file_delete)
+        .or(api_trash_restore)
+        .or(api_trash)
+        // Must come before api_tags, which matches the prefix.
+        .or(api_tags_top)
+        .or(api_tags_suggest)
+        .or(api_tags_rename)
+        .or(api_tags_merge)
+        .or(api_tags)
+        .or(api_folder_stats)
+        .or(api_galleries)
+        .or(api_gallery)
+        .or(api_gallery_create)
+        .or(api_gallery_update)
+        .or(api_gallery_delete)
+        .or(api_tree)
+        .or(api_storage_info)
+        .or(api_importers)
+        .or(api_import_run)
+        .or(api_import_fetch)
+        .or(api_import_backfill)
+        .or(api_import)
+        .or(api_optimize)
+        .or(api_purge_trash)
+        .or(api_prune_galleries)
+        .or(api_missing_thumbnails_query)
+        .or(api_missing_thumbnails)
+        .or(api_rebuild_index)
+        .or(api_reindex)
+        .or(api_tags_from_filename_query)
+        .or(api_tags_from_filename);
+
+    // Only requests below /api/ get the JSON 404, everything else falls
+    // through to the other routes.
+    let api = warp::path::peek()
+        .and_then(|peek: warp::path::Peek| {
+            let path = peek.as_str();
+            if path == "api" || path.starts_with("api/") {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(api.recover(api_not_found));
 
     let js_assets = warp::path("assets").and(warp::path("js"))
         .and(warp::fs::dir("../target/web"));
 
+    // Frontend routes are handled client side, so unknown paths get the app
+    // unless the fallback is disabled.
+    let spa_fallback = !app.config.disable_spa_fallback;
     let index_fallback = warp::any()
+        .and_then(move || if spa_fallback {
+            Ok(())
+        } else {
+            Err(warp::reject::not_found())
+        })
+        .untuple_one()
         .and(warp::fs::file("../target/web/index.html"));
 
+    // Thumbnails, ahead of the media routes which would match the path too.
+    let a = app.clone();
+    let media_thumb_query = path!("media" / "thumb" / String)
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(warp::query::<t::ThumbParams>())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(move |hash: String, authed: bool, params: t::ThumbParams, accept: Option<String>| {
+            thumb_response(a.clone(), hash, authed, params.size, accept)
+        });
+
+    let a = app.clone();
+    let media_thumb = path!("media" / "thumb" / String)
+        .and(filters::method::get2())
+        .and(authed.clone())
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(move |hash: String, authed: bool, accept: Option<String>| {
+            thumb_response(a.clone(), hash, authed, None, accept)
+        });
+
+    let a = app.clone();
+    let media_head = warp::path("media")
+        .and(filters::method::head())
+        .and(warp::path::tail())
+        .and(authed.clone())
+        .and(media_headers())
+        .and_then(move |tail: warp::path::Tail, authed: bool, headers: MediaHeaders| {
+            media_response(a.clone(), tail.as_str().to_string(), authed, false, false, headers)
+        });
+
+    // As for files, requests without a query string are handled by the plain
+    // route below.
+    let a = app.clone();
+    let media_query = warp::path("media")
+        .and(warp::path::tail())
+        .and(authed.clone())
+        .and(warp::query::<t::MediaParams>())
+        .and(media_headers())
+        .and_then(move |tail: warp::path::Tail, authed: bool, params: t::MediaParams, headers: MediaHeaders| {
+            media_response(a.clone(), tail.as_str().to_string(), authed, true, params.verify(), headers)
+        });
+
+    let a = app.clone();
     let media = warp::path("media")
-        .and(warp::fs::dir(app.config.storage_path.clone()));
+        .and(warp::path::tail())
+        .and(authed.clone())
+        .and(media_headers())
+        .and_then(move |tail: warp::path::Tail, authed: bool, headers: MediaHeaders| {
+            media_response(a.clone(), tail.as_str().to_string(), authed, true, false, headers)
+        });
 
     let cors = warp::any()
         .and(filters::method::options())
@@ -111,20 +1126,379 @@ pub fn run_server(app: App) {
             Response::builder()
                 .status(StatusCode::from_u16(200).unwrap())
                 .header("access-control-allow-origin", "*")
-                .header("access-control-allow-methods", "get,post,put")
+                .header("access-control-allow-methods", "get,post,put,patch")
                 .body(Body::empty())
                 .unwrap()
         });
 
-    let routes = cors
+    cors
         .or(api)
         .or(js_assets)
+        .or(media_thumb_query)
+        .or(media_thumb)
+        .or(media_head)
+        .or(media_query)
         .or(media)
-        .or(index_fallback);
+        .or(index_fallback)
+}
 
-    let routes = routes.with(warp::filters::log::log("mediavault"));
+pub fn run_server(app: App) {
+    let addr = (app.config.bind_address, app.config.port);
+    let access_log = app.access_log();
+    let access_log = warp::filters::log::custom(move |info: warp::filters::log::Info| {
+        if let Some(log) = access_log.as_ref() {
+            let entry = access_log::Entry::new(
+                info.method().as_str(),
+                info.path(),
+                info.status().as_u16(),
+                info.elapsed(),
+            );
+            if let Err(e) = log.write(&entry) {
+                log::error!("Could not write access log: {}", e);
+            }
+        }
+    });
+
+    let routes = routes(app)
+        .with(warp::filters::log::log("mediavault"))
+        .with(access_log);
 
     warp::serve(routes)
-        .run(([127, 0, 0, 1], 8080));
+        .run(addr);
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_range_parse() {
+        assert_eq!(ByteRange::parse(None, 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("bytes=0-9"), 100), ByteRange::Partial(0, 9));
+        assert_eq!(ByteRange::parse(Some("bytes=90-"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(ByteRange::parse(Some("bytes=-10"), 100), ByteRange::Partial(90, 99));
+        assert_eq!(ByteRange::parse(Some("bytes=-200"), 100), ByteRange::Partial(0, 99));
+        // The end is clamped to the content.
+        assert_eq!(ByteRange::parse(Some("bytes=50-500"), 100), ByteRange::Partial(50, 99));
+        // Offsets beyond 4 GB.
+        let len = 6 * 1024 * 1024 * 1024;
+        assert_eq!(
+            ByteRange::parse(Some("bytes=5368709120-"), len),
+            ByteRange::Partial(5 * 1024 * 1024 * 1024, len - 1),
+        );
+    }
+
+    #[test]
+    fn byte_range_parse_invalid() {
+        assert_eq!(ByteRange::parse(Some("bytes=100-"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse(Some("bytes=-0"), 100), ByteRange::Unsatisfiable);
+        assert_eq!(ByteRange::parse(Some("bytes=0-"), 0), ByteRange::Unsatisfiable);
+        // Ignored, so the whole content is sent.
+        assert_eq!(ByteRange::parse(Some("bytes=9-0"), 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("items=0-1"), 100), ByteRange::Full);
+        assert_eq!(ByteRange::parse(Some("bytes=a-b"), 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn media_body_data_range() {
+        let content = storage::MediaContent::Data(b"0123456789".to_vec());
+        let body = media_body(content, 2, 3).unwrap();
+        let data = body.concat2().wait().unwrap();
+        assert_eq!(&data[..], b"234");
+    }
+
+    #[test]
+    fn media_path_decoding() {
+        assert_eq!(media_path("a/b.jpg"), Some("a/b.jpg".to_string()));
+        assert_eq!(media_path("a%20b/c%C3%A4.jpg"), Some("a b/c\u{e4}.jpg".to_string()));
+        assert_eq!(media_path("a//b/"), Some("a/b".to_string()));
+        assert_eq!(media_path(""), None);
+        assert_eq!(media_path("a/../b"), None);
+        assert_eq!(media_path("a/%2E%2E/b"), None);
+        assert_eq!(media_path("%2Fetc/passwd"), Some("etc/passwd".to_string()));
+        assert_eq!(media_path("%FF"), None);
+    }
+
+    #[test]
+    fn etag_matching() {
+        let header = |value: &str| Some(value.to_string());
+        assert!(!etag_matches(None, "\"a\""));
+        assert!(etag_matches(header("\"a\"").as_ref(), "\"a\""));
+        assert!(etag_matches(header("\"b\", \"a\"").as_ref(), "\"a\""));
+        assert!(etag_matches(header("W/\"a\"").as_ref(), "\"a\""));
+        assert!(etag_matches(header("\"a\"").as_ref(), "W/\"a\""));
+        assert!(etag_matches(header("*").as_ref(), "\"a\""));
+        assert!(!etag_matches(header("\"b\"").as_ref(), "\"a\""));
+    }
+
+    #[test]
+    fn api_responds_with_yaml_when_accepted() {
+        let vault = crate::app::testing::TestApp::new();
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .path("/api/stats")
+            .header("accept", "application/yaml")
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "application/yaml");
+        let stats: serde_yaml::Value = serde_yaml::from_slice(res.body()).unwrap();
+        assert!(stats.as_mapping().is_some());
+
+        let res = warp::test::request()
+            .path("/api/stats")
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "application/json");
+    }
+
+    #[test]
+    fn media_head_has_headers_without_body() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.txt", b"hello");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .method("HEAD")
+            .path("/media/a.txt")
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-length"], "5");
+        assert_eq!(res.headers()["content-type"], "text/plain");
+        assert_eq!(res.headers()["accept-ranges"], "bytes");
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn files_query_is_not_modified_on_repeat() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+        let query = serde_json::to_vec(&t::FileQuery::default()).unwrap();
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/files")
+            .body(query.clone())
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/files")
+            .header("if-none-match", etag.as_str())
+            .body(query.clone())
+            .reply(&routes);
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+
+        // A new file changes the result.
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/files")
+            .header("if-none-match", etag.as_str())
+            .body(query)
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+    }
+
+    #[test]
+    fn missing_file_is_a_json_not_found() {
+        let vault = crate::app::testing::TestApp::new();
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .path("/api/file/missing")
+            .reply(&routes);
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["content-type"], "application/json");
+    }
+
+    #[test]
+    fn svg_media_is_sandboxed() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.svg", b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .path("/media/a.svg")
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()["content-type"], "image/svg+xml");
+        assert_eq!(res.headers()["x-content-type-options"], "nosniff");
+        assert!(res.headers()["content-security-policy"].to_str().unwrap().starts_with("default-src 'none'"));
+    }
+
+    #[test]
+    fn media_is_not_modified_with_matching_etag() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.txt", b"hello");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .path("/media/a.txt")
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+        let etag = res.headers()["etag"].to_str().unwrap().to_string();
+        assert_eq!(etag, format!("\"{}\"", vault.hash("a.txt")));
+        assert!(res.headers().contains_key("cache-control"));
+
+        let res = warp::test::request()
+            .path("/media/a.txt")
+            .header("if-none-match", etag.as_str())
+            .reply(&routes);
+        assert_eq!(res.status(), 304);
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn file_move_errors_have_statuses() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+        let move_request = |from: &str, to: &str| {
+            let body = serde_json::to_vec(&t::FileMove { from: from.to_string(), to: to.to_string() }).unwrap();
+            warp::test::request()
+                .method("POST")
+                .path("/api/file/move")
+                .body(body)
+                .reply(&routes)
+        };
+
+        assert_eq!(move_request("missing.txt", "c.txt").status(), 404);
+        assert_eq!(move_request("a.txt", "b.txt").status(), 409);
+        assert_eq!(move_request("a.txt", "c.txt").status(), 200);
+    }
+
+    #[test]
+    fn unknown_api_paths_are_a_json_not_found() {
+        let vault = crate::app::testing::TestApp::new();
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .path("/api/bogus")
+            .reply(&routes);
+        assert_eq!(res.status(), 404);
+        assert_eq!(res.headers()["content-type"], "application/json");
+
+        // Rejections of existing routes keep their status.
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/api/stats")
+            .reply(&routes);
+        assert_eq!(res.status(), 405);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/api/file/move")
+            .body("nope")
+            .reply(&routes);
+        assert_eq!(res.status(), 400);
+    }
+
+    #[test]
+    fn importers_require_auth() {
+        let vault = crate::app::testing::TestApp::with_config(crate::app::Config {
+            auth_token: Some("secret".to_string()),
+            ..crate::app::Config::default()
+        });
+        let routes = routes(vault.app.clone());
+
+        let res = warp::test::request()
+            .path("/api/importers")
+            .reply(&routes);
+        assert_eq!(res.status(), 403);
+
+        let res = warp::test::request()
+            .path("/api/importers")
+            .header("authorization", "Bearer secret")
+            .reply(&routes);
+        assert_eq!(res.status(), 200);
+    }
+
+    #[test]
+    fn client_config_has_the_home_query() {
+        let home_query = |vault: &crate::app::testing::TestApp| {
+            let res = warp::test::request()
+                .path("/api/config")
+                .reply(&routes(vault.app.clone()));
+            assert_eq!(res.status(), 200);
+            let config: t::ClientConfig = serde_json::from_slice(res.body()).unwrap();
+            config.home_query
+        };
+
+        let vault = crate::app::testing::TestApp::with_config(crate::app::Config {
+            home_query: Some(t::FileQuery {
+                page_size: 12,
+                ..t::FileQuery::default()
+            }),
+            ..crate::app::Config::default()
+        });
+        assert_eq!(home_query(&vault).page_size, 12);
+
+        // Without a configured query, the frontend gets the default.
+        let vault = crate::app::testing::TestApp::new();
+        assert_eq!(home_query(&vault).page_size, t::FileQuery::default().page_size);
+    }
+
+    #[test]
+    fn files_query_with_and_without_envelope() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+        let files = |path: &str| -> serde_json::Value {
+            let res = warp::test::request()
+                .method("POST")
+                .path(path)
+                .body(serde_json::to_vec(&t::FileQuery::default()).unwrap())
+                .reply(&routes);
+            assert_eq!(res.status(), 200);
+            serde_json::from_slice(res.body()).unwrap()
+        };
+
+        let flat = files("/api/files");
+        assert_eq!(flat["items"].as_array().unwrap().len(), 1);
+        assert_eq!(flat["total"], 1);
+        assert!(flat.get("data").is_none());
+
+        let wrapped = files("/api/files?envelope=1");
+        assert_eq!(wrapped["data"].as_array().unwrap().len(), 1);
+        assert_eq!(wrapped["meta"]["total"], 1);
+        assert_eq!(wrapped["meta"]["total_pages"], 1);
+        assert!(wrapped.get("items").is_none());
+    }
+
+    #[test]
+    fn files_count_endpoint() {
+        let vault = crate::app::testing::TestApp::new();
+        vault.write("a.txt", b"a");
+        vault.write("b.txt", b"b");
+        vault.app.index().unwrap();
+        let routes = routes(vault.app.clone());
+        let count = |filter: Option<t::FileFilter>| -> serde_json::Value {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/api/files/count")
+                .body(serde_json::to_vec(&filter).unwrap())
+                .reply(&routes);
+            assert_eq!(res.status(), 200);
+            serde_json::from_slice(res.body()).unwrap()
+        };
+
+        assert_eq!(count(None), 2);
+        assert_eq!(count(Some(t::FileFilter::Untagged)), 2);
+        assert_eq!(count(Some(t::FileFilter::Tag("missing".to_string()))), 0);
+    }
+}
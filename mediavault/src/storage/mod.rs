@@ -1,8 +1,10 @@
 use crate::prelude::*;
 use failure::format_err;
 use std::{
-    fs, io,
-    path::{Path, PathBuf},
+    collections::HashSet,
+    fs,
+    io::{self, BufRead},
+    path::{Component, Path, PathBuf},
 };
 
 use mediavault_common::types::*;
@@ -15,11 +17,33 @@ pub struct GalleryItem {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Gallery {
-    #[serde(skip)]
+    /// Path of the `.gallery.yaml` file. Not stored in the file itself.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub path: String,
     pub title: String,
     pub description: Option<String>,
-    pub items: GalleryItem,
+    #[serde(deserialize_with = "gallery_items")]
+    pub items: Vec<GalleryItem>,
+}
+
+/// Deserialize gallery items from a sequence, or from a single mapping as
+/// written by older versions.
+fn gallery_items<'de, D>(deserializer: D) -> Result<Vec<GalleryItem>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Items {
+        One(GalleryItem),
+        Many(Vec<GalleryItem>),
+    }
+
+    let items = match serde::Deserialize::deserialize(deserializer)? {
+        Items::One(item) => vec![item],
+        Items::Many(items) => items,
+    };
+    Ok(items)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -28,6 +52,39 @@ pub struct Importer {
     pub content: String,
 }
 
+impl Importer {
+    /// Parse the declarations in the leading comment block of the script.
+    ///
+    /// ```js
+    /// // @name Example
+    /// // @match https://example.com/*
+    /// ```
+    pub fn info(&self) -> ImporterInfo {
+        let mut info = ImporterInfo {
+            path: self.path.clone(),
+            name: None,
+            matches: Vec::new(),
+        };
+
+        for line in self.content.lines().map(|l| l.trim()) {
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with("//") {
+                break;
+            }
+            let line = line.trim_start_matches('/').trim();
+            if line.starts_with("@name ") {
+                info.name = Some(line["@name ".len()..].trim().to_string());
+            } else if line.starts_with("@match ") {
+                info.matches.push(line["@match ".len()..].trim().to_string());
+            }
+        }
+
+        info
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum StorageItem {
     File(File),
@@ -35,29 +92,197 @@ pub enum StorageItem {
     Importer(Importer),
 }
 
+/// Content of a media file.
+#[derive(Debug)]
+pub enum MediaContent {
+    /// Content generated in memory, e.g. converted subtitles.
+    Data(Vec<u8>),
+    /// An open file with its length, read in chunks when it is served.
+    File(fs::File, u64),
+}
+
+impl MediaContent {
+    pub fn len(&self) -> u64 {
+        match self {
+            MediaContent::Data(ref data) => data.len() as u64,
+            MediaContent::File(_, len) => *len,
+        }
+    }
+
+    /// Open a file for serving.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(MediaContent::File(file, len))
+    }
+}
+
+/// A media file, ready to be served.
+#[derive(Debug)]
+pub struct Media {
+    pub mime: Option<String>,
+    pub content: MediaContent,
+    /// Verified content hash of the data, sent to the client if set.
+    pub hash: Option<(HashAlgo, String)>,
+    /// Entity tag for conditional requests.
+    pub etag: Option<String>,
+}
+
+/// A directory with media.
+///
+/// Paths of files in a root are prefixed with its id, e.g. `photos/a.jpg` for
+/// `a.jpg` in the root `photos`. A single root may have an empty id, which
+/// makes its files available without a prefix. Mounted roots shadow top-level
+/// directories of the same name in that root.
+#[derive(Deserialize, Clone, Debug)]
+pub struct StorageRoot {
+    #[serde(default)]
+    pub id: String,
+    pub path: String,
+}
+
+#[derive(Clone)]
+struct Root {
+    id: String,
+    path: PathBuf,
+}
+
+/// Directory in each root that holds deleted files. Never indexed.
+pub const TRASH_DIR: &str = ".trash";
+
+/// Default read size for hashing files: 64 KB.
+pub const DEFAULT_HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Digest used for content hashes, which identify files in the index.
+///
+/// Changing it changes every hash, so the index has to be rebuilt.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    Md5,
+    Sha256,
+}
+
+impl Default for HashAlgo {
+    fn default() -> Self {
+        HashAlgo::Md5
+    }
+}
+
+impl HashAlgo {
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Md5 => "md5",
+            HashAlgo::Sha256 => "sha256",
+        }
+    }
+}
+
+enum Hasher {
+    Md5(md5::Context),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Md5 => Hasher::Md5(md5::Context::new()),
+            HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::default()),
+        }
+    }
+
+    fn consume(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(ctx) => ctx.consume(data),
+            Hasher::Sha256(ctx) => sha2::Digest::input(ctx, data),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Hasher::Md5(ctx) => format!("{:x}", ctx.compute()),
+            Hasher::Sha256(ctx) => format!("{:x}", sha2::Digest::result(ctx)),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Storage {
-    root: PathBuf,
+    roots: Vec<Root>,
+    /// Number of metadata backups to keep per file.
+    meta_backups: usize,
+    /// Serve files whose real location is outside of their root.
+    follow_symlinks: bool,
+    /// Read size for hashing files.
+    hash_buffer_size: usize,
+    hash_algo: HashAlgo,
+    /// `ffprobe` binary used to read the duration of videos and audio.
+    ffprobe_binary: String,
 }
 
 impl Storage {
-    fn compute_hash<I: io::Read>(mut input: I) -> Result<String, Error> {
-        let mut ctx = md5::Context::new();
+    /// Compute the content hash with the configured algorithm.
+    pub fn compute_hash<I: io::Read>(&self, input: I) -> Result<String, Error> {
+        Self::compute_hash_buffered(input, self.hash_buffer_size, self.hash_algo)
+    }
 
-        let mut buffer = [0u8; 4096];
+    /// Compute the hash, reading `buffer_size` bytes at a time.
+    pub fn compute_hash_buffered<I: io::Read>(
+        input: I,
+        buffer_size: usize,
+        algo: HashAlgo,
+    ) -> Result<String, Error> {
+        let mut reader = io::BufReader::with_capacity(buffer_size.max(1), input);
+        let mut hasher = Hasher::new(algo);
         loop {
-            let len = input.read(&mut buffer)?;
-            if len > 0 {
-                ctx.consume(&buffer[0..len]);
-            } else {
+            let len = {
+                let buffer = reader.fill_buf()?;
+                hasher.consume(buffer);
+                buffer.len()
+            };
+            if len == 0 {
                 break;
             }
+            reader.consume(len);
+        }
+        Ok(hasher.finish())
+    }
+
+    /// Tags derived from the name of a file.
+    ///
+    /// The name without extension is split into words at anything that is not
+    /// alphanumeric. Words with less than three characters and plain numbers
+    /// are skipped, e.g. `holiday_2018-beach 01.jpg` gives `holiday` and `beach`.
+    pub fn filename_tags(path: &str) -> Vec<String> {
+        let stem = Path::new(path)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("");
+        let mut tags: Vec<String> = Vec::new();
+        for word in stem.split(|c: char| !c.is_alphanumeric()) {
+            let word = word.to_lowercase();
+            if word.chars().count() < 3 || word.chars().all(|c| c.is_numeric()) {
+                continue;
+            }
+            if !tags.contains(&word) {
+                tags.push(word);
+            }
         }
-        let digest = ctx.compute();
-        Ok(format!("{:x}", digest))
+        tags
     }
 
     fn file_mime(path: &Path) -> Result<Option<String>, Error> {
+        // `file` reports SVGs as text/xml or text/plain depending on the
+        // presence of an XML declaration.
+        let is_svg = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("svg"))
+            .unwrap_or(false);
+        if is_svg {
+            return Ok(Some("image/svg+xml".to_string()));
+        }
+
         let output = std::process::Command::new("file")
             .arg("--mime-type")
             .arg("--brief")
@@ -67,24 +292,245 @@ impl Storage {
         Ok(Some(mime.to_string()))
     }
 
-    pub fn new(root: &str) -> Result<Self, Error> {
-        fs::create_dir_all(&root)?;
-        let s = Storage {
-            root: PathBuf::from(root),
+    pub fn new(roots: &[StorageRoot]) -> Result<Self, Error> {
+        if roots.is_empty() {
+            return Err(format_err!("at least one storage root is required"));
+        }
+        let mut ids = HashSet::new();
+        for root in roots {
+            if root.id.contains('/') || !ids.insert(root.id.as_str()) {
+                return Err(format_err!("invalid or duplicate storage root id: '{}'", root.id));
+            }
+        }
+
+        let mut s = Storage {
+            roots: Vec::new(),
+            meta_backups: 0,
+            follow_symlinks: false,
+            hash_buffer_size: DEFAULT_HASH_BUFFER_SIZE,
+            hash_algo: HashAlgo::default(),
+            ffprobe_binary: "ffprobe".to_string(),
         };
+        for root in roots {
+            fs::create_dir_all(&root.path)?;
+            s.roots.push(Root {
+                id: root.id.clone(),
+                path: PathBuf::from(&root.path),
+            });
+        }
         Ok(s)
     }
 
-    fn file_path(&self, path: &str) -> PathBuf {
-        self.root.join(path)
+    /// Keep up to `keep` timestamped copies of a metadata sidecar before
+    /// overwriting it, named `{file}.meta.yaml.{timestamp}.bak`.
+    /// 0 disables backups.
+    pub fn set_meta_backups(&mut self, keep: usize) {
+        self.meta_backups = keep;
+    }
+
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.follow_symlinks = follow;
     }
 
-    fn meta_path(&self, path: &str) -> PathBuf {
-        self.root.join(format!("{}.meta.yaml", path))
+    pub fn set_hash_buffer_size(&mut self, size: usize) {
+        self.hash_buffer_size = size;
+    }
+
+    pub fn set_hash_algo(&mut self, algo: HashAlgo) {
+        self.hash_algo = algo;
+    }
+
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.hash_algo
+    }
+
+    pub fn set_ffprobe_binary(&mut self, binary: &str) {
+        self.ffprobe_binary = binary.to_string();
+    }
+
+    /// Metadata sidecars and their backups. Never indexed or served.
+    fn is_meta(path: &str) -> bool {
+        path.ends_with(".meta.yaml") || (path.contains(".meta.yaml.") && path.ends_with(".bak"))
+    }
+
+    /// Back up the current metadata sidecar of a file, if there is one, and
+    /// remove backups beyond the configured number.
+    fn meta_backup(&self, path: &str) -> Result<(), Error> {
+        if self.meta_backups == 0 {
+            return Ok(());
+        }
+        let meta_path = self.meta_path(path)?;
+        if !meta_path.is_file() {
+            return Ok(());
+        }
+
+        let meta_name = match meta_path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => return Ok(()),
+        };
+        let backup_name = format!("{}.{}.bak", meta_name, now().format("%Y%m%dT%H%M%S%.3f"));
+        fs::copy(&meta_path, meta_path.with_file_name(backup_name))?;
+
+        // Timestamps sort chronologically, so the oldest backups come first.
+        let dir = meta_path.parent().unwrap_or(Path::new(""));
+        let prefix = format!("{}.", meta_name);
+        let mut backups = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().map(|n| n.to_string()))
+            .filter(|name| name.starts_with(&prefix) && name.ends_with(".bak"))
+            .collect::<Vec<_>>();
+        backups.sort();
+        if backups.len() > self.meta_backups {
+            let remove = backups.len() - self.meta_backups;
+            for name in &backups[..remove] {
+                fs::remove_file(dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Metadata and subtitle files, which belong to another file.
+    pub fn is_sidecar(path: &str) -> bool {
+        Self::is_meta(path) || Self::is_subtitle(path)
+    }
+
+    /// Directories of all roots.
+    pub fn root_dirs(&self) -> Vec<PathBuf> {
+        self.roots.iter().map(|root| root.path.clone()).collect()
+    }
+
+    /// Storage path of a location on disk, the reverse of `file_path`.
+    ///
+    /// Returns `None` for locations outside of the roots and for those
+    /// shadowed by a mounted root.
+    pub fn storage_path(&self, full_path: &Path) -> Option<String> {
+        for root in &self.roots {
+            let rel = match full_path.strip_prefix(&root.path) {
+                Ok(rel) => rel.to_path_buf(),
+                // Watchers may report canonical paths.
+                Err(_) => match fs::canonicalize(&root.path) {
+                    Ok(real) => match full_path.strip_prefix(real) {
+                        Ok(rel) => rel.to_path_buf(),
+                        Err(_) => continue,
+                    },
+                    Err(_) => continue,
+                },
+            };
+            let rel = rel.to_str()?;
+            if root.id.is_empty() {
+                let first = rel.split('/').next().unwrap_or("");
+                if self.mounted(first).is_some() {
+                    continue;
+                }
+                return Some(rel.to_string());
+            }
+            if rel.is_empty() {
+                return Some(root.id.clone());
+            }
+            return Some(format!("{}/{}", root.id, rel));
+        }
+        None
+    }
+
+    fn mounted(&self, id: &str) -> Option<&Root> {
+        self.roots.iter().find(|r| !r.id.is_empty() && r.id == id)
+    }
+
+    fn default_root(&self) -> Option<&Root> {
+        self.roots.iter().find(|r| r.id.is_empty())
+    }
+
+    /// Find the root of a path and the path relative to it.
+    fn resolve<'a>(&self, path: &'a str) -> Option<(&Root, &'a str)> {
+        let first = path.split('/').next().unwrap_or("");
+        match self.mounted(first) {
+            Some(root) => Some((root, path[first.len()..].trim_start_matches('/'))),
+            None => self.default_root().map(|root| (root, path)),
+        }
+    }
+
+    /// Location on disk of a storage path. Every method that takes a path
+    /// goes through here.
+    ///
+    /// Only plain relative paths are accepted. Unless symlinks may be
+    /// followed, the directory of the path must also resolve to a location
+    /// inside the root, so symlinked directories can't be used to escape it.
+    /// Symlinked files are checked when they are served, see `served_path`.
+    fn file_path(&self, path: &str) -> Result<PathBuf, Error> {
+        if !Self::is_relative(path) {
+            return Err(AppError::BadRequest(format!("invalid_path: {}", path)).into());
+        }
+        let (root, rel) = self.resolve(path).ok_or_else(AppError::not_found)?;
+        let fpath = root.path.join(rel);
+        if !self.follow_symlinks {
+            if let Some(dir) = fpath.parent() {
+                if dir.starts_with(&root.path) && !Self::is_within(&root.path, dir)? {
+                    log::warn!("Refusing to access {}, which resolves outside of its root", path);
+                    return Err(AppError::BadRequest(format!("invalid_path: {}", path)).into());
+                }
+            }
+        }
+        Ok(fpath)
+    }
+
+    /// Whether `path` is inside `root` once symlinks are resolved.
+    ///
+    /// The path does not have to exist, its deepest existing ancestor is
+    /// checked instead.
+    fn is_within(root: &Path, path: &Path) -> Result<bool, Error> {
+        let mut existing = path;
+        let real = loop {
+            match fs::canonicalize(existing) {
+                Ok(real) => break real,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => match existing.parent() {
+                    Some(parent) => existing = parent,
+                    None => return Ok(false),
+                },
+                Err(e) => return Err(e.into()),
+            }
+        };
+        Ok(real.starts_with(fs::canonicalize(root)?))
+    }
+
+    /// Path of a file to serve.
+    ///
+    /// Returns `None` if the file does not exist or, unless symlinks may be
+    /// followed, if it resolves to a location outside of its root.
+    fn served_path(&self, path: &str) -> Result<Option<PathBuf>, Error> {
+        let (root, rel) = match self.resolve(path) {
+            Some(resolved) => resolved,
+            None => return Ok(None),
+        };
+        let fpath = root.path.join(rel);
+        if self.follow_symlinks {
+            return Ok(Some(fpath));
+        }
+
+        let real = match fs::canonicalize(&fpath) {
+            Ok(real) => real,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if real.starts_with(fs::canonicalize(&root.path)?) {
+            Ok(Some(fpath))
+        } else {
+            log::warn!("Refusing to serve {}, which resolves outside of its root", path);
+            Ok(None)
+        }
+    }
+
+    pub fn exists(&self, path: &str) -> bool {
+        self.file_path(path)
+            .map(|p| fs::metadata(p).is_ok())
+            .unwrap_or(false)
+    }
+
+    fn meta_path(&self, path: &str) -> Result<PathBuf, Error> {
+        self.file_path(&format!("{}.meta.yaml", path))
     }
 
     pub fn file_meta(&self, path: &str) -> Result<FileMeta, Error> {
-        let meta_path = self.meta_path(path);
+        let meta_path = self.meta_path(path)?;
         match fs::File::open(meta_path) {
             Ok(f) => Ok(serde_yaml::from_reader(f)?),
             Err(e) => {
@@ -97,49 +543,147 @@ impl Storage {
         }
     }
 
+    /// Write the metadata sidecar of a file. All writes go through here, so
+    /// the format always matches what `file_meta` reads.
     fn file_meta_write(&self, path: &str, meta: &FileMeta) -> Result<(), Error> {
-        let mut f = fs::File::create(self.meta_path(path))?;
-        serde_json::to_writer(&mut f, meta)?;
+        self.meta_backup(path)?;
+        let mut f = fs::File::create(self.meta_path(path)?)?;
+        serde_yaml::to_writer(&mut f, meta)?;
         Ok(())
     }
 
     pub fn file_info(&self, path: &str) -> Result<FileInfo, Error> {
-        let fpath = self.file_path(path);
+        let fpath = self.file_path(path)?;
         let mut f = fs::File::open(&fpath)?;
 
         // Build file info.
         let fsmeta = f.metadata()?;
 
         let size = fsmeta.len() as i64;
-        let hash = Self::compute_hash(&mut f)?;
+        let hash = self.compute_hash(&mut f)?;
         let mime = Self::file_mime(&fpath)?;
         let kind = match mime.as_ref() {
             Some(mime) => FileKind::from_mime(mime),
             None => FileKind::Other,
         };
-        // TODO: add created_at and updated_at.
+        let updated_at = fsmeta.modified().ok().map(DateTime::from);
+        // Not every platform or file system records the creation time.
+        let created_at = fsmeta.created().map(DateTime::from).unwrap_or_else(|_| now());
 
-        let info = FileInfo {
+        let mut info = FileInfo {
             hash,
             size,
             mime,
             kind,
             media: None,
-            created_at: None,
-            updated_at: None,
+            created_at: Some(created_at),
+            updated_at,
+            subtitles: Vec::new(),
+            location: None,
         };
+        if info.is_image() && !info.is_svg() {
+            info.location = Self::exif_location(&fpath);
+        }
+        if info.kind == FileKind::Video || info.kind == FileKind::Audio {
+            info.media = self.probe_media(&fpath, info.kind);
+        }
         Ok(info)
     }
 
+    /// Read the duration, and for videos the dimensions, with `ffprobe`.
+    ///
+    /// Returns `None` if ffprobe is not installed or can't read the file.
+    fn probe_media(&self, path: &Path, kind: FileKind) -> Option<MediaInfo> {
+        let output = std::process::Command::new(&self.ffprobe_binary)
+            .args(&["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+            .arg(path)
+            .output();
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                log::debug!("Could not run {}: {}", self.ffprobe_binary, e);
+                return None;
+            }
+        };
+        if !output.status.success() {
+            log::warn!("ffprobe failed for {}: {}", path.display(), String::from_utf8_lossy(&output.stderr));
+            return None;
+        }
+
+        let probe: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+        // Durations are reported in seconds as a decimal string.
+        let length = probe["format"]["duration"]
+            .as_str()?
+            .parse::<f64>()
+            .ok()?
+            .round() as u32;
+
+        match kind {
+            FileKind::Video => {
+                let stream = probe["streams"]
+                    .as_array()?
+                    .iter()
+                    .find(|stream| stream["codec_type"] == "video")?;
+                Some(MediaInfo::Video(VideoInfo {
+                    width: stream["width"].as_u64()? as u32,
+                    height: stream["height"].as_u64()? as u32,
+                    length,
+                }))
+            }
+            FileKind::Audio => Some(MediaInfo::Audio(AudioInfo { length })),
+            _ => None,
+        }
+    }
+
+    /// Read GPS coordinates from the EXIF data of an image.
+    ///
+    /// Most files have no EXIF data, so failures are not errors.
+    fn exif_location(path: &Path) -> Option<GeoLocation> {
+        let file = fs::File::open(path).ok()?;
+        let reader = exif::Reader::new(&mut io::BufReader::new(&file)).ok()?;
+        let lat = Self::exif_coordinate(&reader, exif::Tag::GPSLatitude, exif::Tag::GPSLatitudeRef, b'S')?;
+        let lon = Self::exif_coordinate(&reader, exif::Tag::GPSLongitude, exif::Tag::GPSLongitudeRef, b'W')?;
+        Some(GeoLocation { lat, lon })
+    }
+
+    /// Convert a degrees/minutes/seconds EXIF coordinate to decimal degrees.
+    /// The reference tag holds N/S or E/W; `negative_ref` flips the sign.
+    fn exif_coordinate(
+        reader: &exif::Reader,
+        tag: exif::Tag,
+        ref_tag: exif::Tag,
+        negative_ref: u8,
+    ) -> Option<f64> {
+        let degrees = match reader.get_field(tag, false)?.value {
+            exif::Value::Rational(ref parts) if parts.len() == 3 => {
+                parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+        let negative = match reader.get_field(ref_tag, false).map(|f| &f.value) {
+            Some(exif::Value::Ascii(ref values)) => {
+                values.first().and_then(|v| v.first()) == Some(&negative_ref)
+            }
+            _ => false,
+        };
+        if !degrees.is_finite() {
+            return None;
+        }
+        Some(if negative { -degrees } else { degrees })
+    }
+
     pub fn file(&self, path: &str) -> Result<File, Error> {
         if path.ends_with(".gallery.yaml") {
-            return Err(format_err!("path is a gallery, not a file"));
+            return Err(AppError::BadRequest("path is a gallery, not a file".to_string()).into());
         }
         if path.ends_with(".importer.js") {
-            return Err(format_err!("path is a importer, not a file"));
+            return Err(AppError::BadRequest("path is an importer, not a file".to_string()).into());
         }
 
-        let info = self.file_info(path)?;
+        let mut info = self.file_info(path)?;
+        if info.is_video() {
+            info.subtitles = self.subtitles(path)?;
+        }
         let meta = self.file_meta(path)?;
 
         Ok(File {
@@ -155,33 +699,31 @@ impl Storage {
         meta: FileMeta,
         mut input: I,
     ) -> Result<File, Error> {
-        let full_path = self.root.join(path);
+        let full_path = self.file_path(path)?;
         if fs::metadata(&full_path).is_ok() {
-            return Err(format_err!("path_already_exists"));
+            return Err(AppError::Conflict("path_already_exists".to_string()).into());
         }
 
-        // TODO: prevent parent directory escape attacks by resolving relative paths like ../.
-        let parent_dir = full_path.parent().unwrap();
-        if parent_dir != self.root {
+        if let Some(parent_dir) = full_path.parent() {
             fs::create_dir_all(&parent_dir)?;
         }
 
         let mut f = fs::File::create(full_path)?;
         io::copy(&mut input, &mut f)?;
 
-
-        let mut f = fs::File::create(self.meta_path(path))?;
-        serde_yaml::to_writer(&mut f, &meta)?;
+        self.file_meta_write(path, &meta)?;
 
         self.file(path)
     }
 
     pub fn file_meta_update(&self, path: &str, meta: FileMeta) -> Result<File, Error> {
         // Load file info to make sure it exists.
-        let info = self.file_info(path)?;
+        let mut info = self.file_info(path)?;
+        if info.is_video() {
+            info.subtitles = self.subtitles(path)?;
+        }
 
-        let mut f = fs::File::create(self.meta_path(path))?;
-        serde_yaml::to_writer(&mut f, &meta)?;
+        self.file_meta_write(path, &meta)?;
 
         Ok(File{
             path: path.to_string(),
@@ -192,7 +734,7 @@ impl Storage {
 
     pub fn file_delete(&self, path: &str) -> Result<(), Error> {
         // Remove metadata if it exists.
-        match fs::remove_file(self.meta_path(path)) {
+        match fs::remove_file(self.meta_path(path)?) {
             Ok(_) => {},
             Err(e) => {
                 if e.kind() != io::ErrorKind::NotFound {
@@ -201,26 +743,295 @@ impl Storage {
             }
         }
 
-        fs::remove_file(self.file_path(path))?;
+        fs::remove_file(self.file_path(path)?)?;
 
         Ok(())
     }
 
+    /// Location on disk of a media file to serve, with the same checks as
+    /// `media`.
+    pub fn media_source(&self, path: &str) -> Result<Option<PathBuf>, Error> {
+        if Self::is_meta(path) || !Self::is_relative(path) {
+            return Ok(None);
+        }
+        self.served_path(path)
+    }
+
+    /// Storage path of a file once it is moved to the trash: the same path
+    /// below the trash directory of its root.
+    fn trash_path(&self, path: &str) -> Result<String, Error> {
+        match self.resolve(path) {
+            Some((root, rel)) if root.id.is_empty() => Ok(format!("{}/{}", TRASH_DIR, rel)),
+            Some((root, rel)) => Ok(format!("{}/{}/{}", root.id, TRASH_DIR, rel)),
+            None => Err(AppError::not_found().into()),
+        }
+    }
+
+    /// Move a file and its metadata sidecar to the trash.
+    pub fn file_trash(&self, path: &str) -> Result<(), Error> {
+        let trash_path = self.trash_path(path)?;
+        self.rename(path, &trash_path)
+    }
+
+    /// Remove the trashed copy of a file and its metadata sidecar for good.
+    /// Succeeds if it is already gone.
+    pub fn file_purge(&self, path: &str) -> Result<(), Error> {
+        let trash_path = self.trash_path(path)?;
+        if !self.file_path(&trash_path)?.exists() {
+            return Ok(());
+        }
+        self.file_delete(&trash_path)
+    }
+
+    /// Move a file and its metadata sidecar back from the trash.
+    pub fn file_restore(&self, path: &str) -> Result<File, Error> {
+        if self.exists(path) {
+            return Err(AppError::Conflict("path_already_exists".to_string()).into());
+        }
+        let trash_path = self.trash_path(path)?;
+        self.rename(&trash_path, path)?;
+        self.file(path)
+    }
+
+    /// Move a file, its metadata sidecar and the given subtitle sidecars to a
+    /// different path. Subtitles are renamed to match the new file name.
+    /// Returns the subtitles with their new paths.
+    ///
+    /// Never overwrites: fails with a conflict if `to` already exists.
+    /// Roots may be on different file systems, which can't be renamed
+    /// across, so moves between roots are rejected.
+    pub fn file_move(&self, from: &str, to: &str, subtitles: &[Subtitle]) -> Result<Vec<Subtitle>, Error> {
+        if Self::is_meta(from) || Self::is_meta(to) {
+            return Err(AppError::BadRequest("invalid_path: metadata files can not be moved".to_string()).into());
+        }
+        let same_root = match (self.resolve(from), self.resolve(to)) {
+            (Some((a, _)), Some((b, _))) => a.id == b.id,
+            _ => false,
+        };
+        if !same_root {
+            return Err(AppError::BadRequest("invalid_path: files can not be moved between storage roots".to_string()).into());
+        }
+        if !self.exists(from) {
+            return Err(AppError::not_found().into());
+        }
+        if self.exists(to) {
+            return Err(AppError::Conflict("path_already_exists".to_string()).into());
+        }
+
+        let moved = subtitles
+            .iter()
+            .map(|subtitle| Subtitle {
+                lang: subtitle.lang.clone(),
+                path: Self::subtitle_moved_path(&subtitle.path, from, to),
+            })
+            .collect::<Vec<_>>();
+        if let Some(subtitle) = moved.iter().find(|subtitle| self.exists(&subtitle.path)) {
+            return Err(AppError::Conflict(format!("path_already_exists: {}", subtitle.path)).into());
+        }
+
+        self.rename(from, to)?;
+        for (old, new) in subtitles.iter().zip(&moved) {
+            fs::rename(self.file_path(&old.path)?, self.file_path(&new.path)?)?;
+        }
+        Ok(moved)
+    }
+
+    /// Path of a subtitle of the file at `from` once the file is at `to`.
+    /// `movie.en.vtt` of `movie.mp4` becomes `film.en.vtt` for `film.mp4`.
+    fn subtitle_moved_path(subtitle: &str, from: &str, to: &str) -> String {
+        let stem = |path: &str| -> String {
+            Path::new(path)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        let name = subtitle.rsplit('/').next().unwrap_or(subtitle);
+        let rest = name.get(stem(from).len()..).unwrap_or(name);
+        match to.rfind('/') {
+            Some(index) => format!("{}/{}{}", &to[..index], stem(to), rest),
+            None => format!("{}{}", stem(to), rest),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let target = self.file_path(to)?;
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(self.file_path(from)?, target)?;
+        match fs::rename(self.meta_path(from)?, self.meta_path(to)?) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load a media file for serving.
+    ///
+    /// Returns `None` if the file does not exist. Metadata sidecars are never
+    /// served, since they might contain details of private files.
+    pub fn media(&self, path: &str) -> Result<Option<Media>, Error> {
+        let fpath = match self.media_source(path)? {
+            Some(fpath) => fpath,
+            None => return Ok(None),
+        };
+        let content = match MediaContent::open(&fpath) {
+            Ok(content) => content,
+            Err(e) => {
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(None);
+                } else {
+                    return Err(e.into());
+                }
+            }
+        };
+        let mime = Self::file_mime(&fpath)?;
+
+        Ok(Some(Media { mime, content, hash: None, etag: None }))
+    }
+
+    /// Only plain relative paths are allowed, so requests can't escape the root.
+    pub fn is_relative(path: &str) -> bool {
+        Path::new(path)
+            .components()
+            .all(|c| match c {
+                Component::Normal(_) => true,
+                _ => false,
+            })
+    }
+
+    fn is_subtitle(path: &str) -> bool {
+        path.ends_with(".vtt") || path.ends_with(".srt")
+    }
+
+    /// Find the subtitle sidecars of a video.
+    ///
+    /// Sidecars are named like the video without its extension, followed by an
+    /// optional language and the subtitle extension, e.g. `movie.en.vtt` for
+    /// `movie.mp4`. Subtitles without a language get `und`.
+    pub fn subtitles(&self, path: &str) -> Result<Vec<Subtitle>, Error> {
+        let rel_path = Path::new(path);
+        let stem = match rel_path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => format!("{}.", stem),
+            None => return Ok(Vec::new()),
+        };
+        let rel_dir = rel_path.parent().and_then(|p| p.to_str()).unwrap_or("");
+
+        let mut subtitles = Vec::new();
+        for entry in fs::read_dir(self.file_path(rel_dir)?)? {
+            // Subtitles must be addressable by a path, like all other files.
+            let name = match entry?.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if !Self::is_subtitle(&name) || !name.starts_with(&stem) {
+                continue;
+            }
+
+            let rest = &name[stem.len()..];
+            let lang = match rest.rfind('.') {
+                Some(index) if index > 0 => &rest[..index],
+                _ => "und",
+            };
+            let path = if rel_dir.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", rel_dir, name)
+            };
+            subtitles.push(Subtitle {
+                lang: lang.to_string(),
+                path,
+            });
+        }
+        subtitles.sort_by(|a, b| a.lang.cmp(&b.lang));
+        Ok(subtitles)
+    }
+
+    /// Load a subtitle of a video as WebVTT.
+    ///
+    /// SRT subtitles are converted, since browsers only support WebVTT tracks.
+    pub fn subtitle(&self, path: &str, lang: &str) -> Result<Option<Media>, Error> {
+        if !Self::is_relative(path) || !self.exists(path) || !self.file_path(path)?.is_file() {
+            return Ok(None);
+        }
+        let subtitle = match self.subtitles(path)?.into_iter().find(|s| s.lang == lang) {
+            Some(subtitle) => subtitle,
+            None => return Ok(None),
+        };
+
+        let subtitle_path = match self.served_path(&subtitle.path)? {
+            Some(subtitle_path) => subtitle_path,
+            None => return Ok(None),
+        };
+        let content = fs::read_to_string(subtitle_path)?;
+        let content = content.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+        let content = if subtitle.path.ends_with(".srt") {
+            let cues = content
+                .lines()
+                .map(|line| {
+                    if line.contains("-->") {
+                        line.replace(',', ".")
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("WEBVTT\n\n{}", cues)
+        } else {
+            content
+        };
+
+        Ok(Some(Media {
+            mime: Some("text/vtt".to_string()),
+            content: MediaContent::Data(content.into_bytes()),
+            hash: None,
+            etag: None,
+        }))
+    }
+
     pub fn gallery(&self, path: &str) -> Result<Gallery, Error> {
         if !path.ends_with(".gallery.yaml") {
-            return Err(format_err!("gallery path must end with .gallery.yaml"));
+            return Err(AppError::BadRequest("gallery path must end with .gallery.yaml".to_string()).into());
         }
-        let mut f = fs::File::open(self.root.join(path))?;
+        let mut f = fs::File::open(self.file_path(path)?)?;
         let mut gallery: Gallery = serde_yaml::from_reader(&mut f)?;
         gallery.path = path.to_string();
         Ok(gallery)
     }
 
+    /// Write a gallery to its `.gallery.yaml` file.
+    pub fn gallery_write(&self, gallery: &Gallery) -> Result<(), Error> {
+        if !gallery.path.ends_with(".gallery.yaml") {
+            return Err(AppError::BadRequest("gallery path must end with .gallery.yaml".to_string()).into());
+        }
+        if !Self::is_relative(&gallery.path) {
+            return Err(AppError::not_found().into());
+        }
+        // The path is implied by the file name.
+        let data = Gallery {
+            path: String::new(),
+            ..gallery.clone()
+        };
+        let mut f = fs::File::create(self.file_path(&gallery.path)?)?;
+        serde_yaml::to_writer(&mut f, &data)?;
+        Ok(())
+    }
+
+    pub fn gallery_delete(&self, path: &str) -> Result<(), Error> {
+        if !path.ends_with(".gallery.yaml") {
+            return Err(AppError::BadRequest("gallery path must end with .gallery.yaml".to_string()).into());
+        }
+        fs::remove_file(self.file_path(path)?)?;
+        Ok(())
+    }
+
     pub fn importer(&self, path: &str) -> Result<Importer, Error> {
         if !path.ends_with(".importer.js") {
-            return Err(format_err!("importer path must end with .importer.js"));
+            return Err(AppError::BadRequest("importer path must end with .importer.js".to_string()).into());
         }
-        let content = fs::read_to_string(self.root.join(path))?;
+        let content = fs::read_to_string(self.file_path(path)?)?;
         Ok(Importer {
             path: path.to_string(),
             content,
@@ -237,18 +1048,123 @@ impl Storage {
         }
     }
 
-    pub fn items(
+    /// Build the directory hierarchy below a path.
+    ///
+    /// Metadata sidecars are omitted. Directories are listed before files.
+    /// Entries with names that are not valid UTF-8 are skipped, and
+    /// directories that link back to one of their ancestors are listed
+    /// without children.
+    pub fn tree(&self, path: Option<&str>) -> Result<TreeEntry, Error> {
+        let path = path.unwrap_or("").trim_matches('/');
+        if path.split('/').any(|part| part == "..") {
+            return Err(AppError::BadRequest("invalid_path".to_string()).into());
+        }
+        self.tree_entry(path, &mut Vec::new())
+    }
+
+    /// `ancestors` holds the resolved paths of the directories above `path`.
+    fn tree_entry(&self, path: &str, ancestors: &mut Vec<PathBuf>) -> Result<TreeEntry, Error> {
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("")
+            .to_string();
+
+        // Without a default root, the top level only holds the mounted roots.
+        let full_path = match (path.is_empty(), self.default_root()) {
+            (true, None) => None,
+            _ => Some(self.file_path(path)?),
+        };
+        let meta = match full_path.as_ref() {
+            Some(full_path) => Some(fs::metadata(full_path)?),
+            None => None,
+        };
+
+        let kind = if meta.as_ref().map(|m| m.is_dir()).unwrap_or(true) {
+            let real_path = match full_path.as_ref() {
+                Some(full_path) => Some(fs::canonicalize(full_path)?),
+                None => None,
+            };
+            if let Some(real_path) = real_path.as_ref() {
+                if ancestors.contains(real_path) {
+                    log::warn!("Not listing {}, which links back to one of its parent directories", path);
+                    return Ok(TreeEntry {
+                        name,
+                        path: path.to_string(),
+                        kind: TreeEntryKind::Directory(Vec::new()),
+                    });
+                }
+            }
+
+            let mut child_names = Vec::new();
+            if let Some(full_path) = full_path.as_ref() {
+                for entry in fs::read_dir(full_path)? {
+                    match entry?.file_name().into_string() {
+                        Ok(name) => child_names.push(name),
+                        Err(name) => log::warn!("Skipping {:?} in {}: name is not valid UTF-8", name, path),
+                    }
+                }
+            }
+            if path.is_empty() {
+                child_names.retain(|name| self.mounted(name).is_none());
+                child_names.extend(self.roots.iter().filter(|r| !r.id.is_empty()).map(|r| r.id.clone()));
+            }
+
+            ancestors.extend(real_path.clone());
+            let mut children = Vec::new();
+            for child_name in child_names {
+                if Self::is_meta(&child_name) || child_name == TRASH_DIR {
+                    continue;
+                }
+                let child_path = if path.is_empty() {
+                    child_name
+                } else {
+                    format!("{}/{}", path, child_name)
+                };
+                children.push(self.tree_entry(&child_path, ancestors)?);
+            }
+            if real_path.is_some() {
+                ancestors.pop();
+            }
+            children.sort_by(|a, b| (!a.is_dir(), &a.name).cmp(&(!b.is_dir(), &b.name)));
+            TreeEntryKind::Directory(children)
+        } else {
+            TreeEntryKind::File {
+                size: meta.map(|m| m.len()).unwrap_or(0),
+                indexed: false,
+            }
+        };
+
+        Ok(TreeEntry {
+            name,
+            path: path.to_string(),
+            kind,
+        })
+    }
+
+    /// Walk the storage and return the relative paths of all items.
+    pub fn paths(
         &self,
         path: Option<&str>,
-    ) -> impl Iterator<Item = Result<StorageItem, Error>> + '_ {
-        let path = match path {
-            Some(p) => self.root.join(p),
-            None => self.root.clone(),
+    ) -> impl Iterator<Item = Result<String, Error>> + '_ {
+        let walks = match path {
+            Some(p) => self.resolve(p)
+                .filter(|_| Self::is_relative(p))
+                .map(|(root, rel)| vec![(root, root.path.join(rel))])
+                .unwrap_or_default(),
+            None => self.roots.iter().map(|root| (root, root.path.clone())).collect(),
         };
+        walks.into_iter().flat_map(move |(root, start)| self.walk(root, start))
+    }
 
-        let storage = self.clone();
-        walkdir::WalkDir::new(path)
+    fn walk<'a>(
+        &'a self,
+        root: &'a Root,
+        start: PathBuf,
+    ) -> impl Iterator<Item = Result<String, Error>> + 'a {
+        walkdir::WalkDir::new(start)
             .into_iter()
+            .filter_entry(|entry| entry.file_name() != TRASH_DIR)
             .filter_map(move |entry| {
                 let entry = match entry {
                     Ok(entry) => entry,
@@ -258,18 +1174,360 @@ impl Storage {
                 };
                 match entry.metadata() {
                     Ok(meta) => {
-                        let full_path = entry.path().to_str().unwrap();
-                        if meta.file_type().is_dir() || full_path.ends_with(".meta.yaml") {
+                        if meta.file_type().is_dir() {
+                            return None;
+                        }
+                        let rel_path = match entry.path().strip_prefix(&root.path).map(|path| path.to_str()) {
+                            Ok(Some(rel_path)) => rel_path,
+                            Ok(None) => {
+                                log::warn!("Skipping {:?}: path is not valid UTF-8", entry.path());
+                                return None;
+                            }
+                            Err(_) => {
+                                log::warn!("Skipping {:?}: not inside {:?}", entry.path(), root.path);
+                                return None;
+                            }
+                        };
+                        if Self::is_sidecar(rel_path) {
                             None
+                        } else if root.id.is_empty() {
+                            // Shadowed by a mounted root.
+                            let first = rel_path.split('/').next().unwrap_or("");
+                            if self.mounted(first).is_some() {
+                                return None;
+                            }
+                            Some(Ok(rel_path.to_string()))
                         } else {
-                            // TODO: handle non-utf8 file name error.
-                            // TODO: handle full to relative path fixup better.
-                            let rel_path = &full_path[self.root.to_str().unwrap().len() + 1..];
-                            Some(storage.item(rel_path))
+                            Some(Ok(format!("{}/{}", root.id, rel_path)))
                         }
                     }
                     Err(e) => Some(Err(e.into())),
                 }
             })
     }
+
+    pub fn items(
+        &self,
+        path: Option<&str>,
+    ) -> impl Iterator<Item = Result<StorageItem, Error>> + '_ {
+        self.paths(path)
+            .map(move |path| path.and_then(|p| self.item(&p)))
+    }
+
+    pub fn importers(&self) -> impl Iterator<Item = Result<Importer, Error>> + '_ {
+        self.paths(None)
+            .filter_map(move |path| match path {
+                Ok(ref p) if p.ends_with(".importer.js") => Some(self.importer(p)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entry: &TreeEntry) -> Vec<&str> {
+        match entry.kind {
+            TreeEntryKind::Directory(ref children) => children.iter().map(|c| c.name.as_str()).collect(),
+            TreeEntryKind::File { .. } => Vec::new(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tree_skips_symlink_loops_and_non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::{ffi::OsStrExt, fs::symlink}};
+
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        fs::create_dir_all(dir.join("a")).unwrap();
+        fs::write(dir.join("a/x.txt"), b"x").unwrap();
+        symlink(dir.join("a"), dir.join("a/loop")).unwrap();
+        fs::write(dir.join(OsStr::from_bytes(b"\xff.txt")), b"y").unwrap();
+
+        let storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: dir.to_string_lossy().into_owned(),
+        }]).unwrap();
+        let tree = storage.tree(None);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let tree = tree.unwrap();
+        assert_eq!(names(&tree), vec!["a"]);
+        let a = match tree.kind {
+            TreeEntryKind::Directory(ref children) => &children[0],
+            TreeEntryKind::File { .. } => unreachable!(),
+        };
+        assert_eq!(names(a), vec!["loop", "x.txt"]);
+        let looped = match a.kind {
+            TreeEntryKind::Directory(ref children) => &children[0],
+            TreeEntryKind::File { .. } => unreachable!(),
+        };
+        assert!(looped.is_dir());
+        assert!(names(looped).is_empty());
+    }
+
+    #[test]
+    fn subtitle_moved_path() {
+        assert_eq!(Storage::subtitle_moved_path("movie.en.vtt", "movie.mp4", "film.mp4"), "film.en.vtt");
+        assert_eq!(Storage::subtitle_moved_path("a/movie.srt", "a/movie.mkv", "b/c/film.mkv"), "b/c/film.srt");
+        assert_eq!(Storage::subtitle_moved_path("a/movie.de.vtt", "a/movie.mkv", "movie.mkv"), "movie.de.vtt");
+    }
+
+    #[test]
+    fn file_move_between_roots_is_rejected() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let root = |id: &str| StorageRoot {
+            id: id.to_string(),
+            path: dir.join(if id.is_empty() { "default" } else { id }).to_string_lossy().into_owned(),
+        };
+        let storage = Storage::new(&[root(""), root("usb")]).unwrap();
+        fs::write(dir.join("default/a.txt"), b"a").unwrap();
+
+        let res = storage.file_move("a.txt", "usb/a.txt", &[]);
+        let same_root = storage.file_move("a.txt", "b.txt", &[]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        match res.unwrap_err().downcast_ref::<AppError>() {
+            Some(AppError::BadRequest(_)) => {}
+            other => panic!("expected a bad request, got {:?}", other),
+        }
+        assert!(same_root.unwrap().is_empty());
+    }
+
+    #[test]
+    fn paths_of_a_root_with_a_trailing_slash() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: format!("{}/", dir.to_string_lossy()),
+        }]).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("sub/b.txt"), b"b").unwrap();
+
+        let paths = storage.paths(None).collect::<Result<Vec<_>, _>>();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut paths = paths.unwrap();
+        paths.sort();
+        assert_eq!(paths, vec!["a.txt".to_string(), "sub/b.txt".to_string()]);
+    }
+
+    #[test]
+    fn file_meta_round_trips_through_every_write() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: dir.to_string_lossy().into_owned(),
+        }]).unwrap();
+
+        let created = FileMeta {
+            title: Some("Created".to_string()),
+            tags: vec!["cat".to_string()],
+            ..FileMeta::default()
+        };
+        let res = storage.file_create("a.txt", created, &b"a"[..]).and_then(|_| storage.file_meta("a.txt"));
+        let updated = FileMeta {
+            title: Some("Updated".to_string()),
+            notes: Some("a: b".to_string()),
+            ..FileMeta::default()
+        };
+        let res = res.and_then(|created| {
+            storage.file_meta_update("a.txt", updated)?;
+            Ok((created, storage.file_meta("a.txt")?))
+        });
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (created, updated) = res.unwrap();
+        assert_eq!(created.title, Some("Created".to_string()));
+        assert_eq!(created.tags, vec!["cat".to_string()]);
+        assert_eq!(updated.title, Some("Updated".to_string()));
+        assert_eq!(updated.notes, Some("a: b".to_string()));
+        assert!(updated.tags.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_not_served() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let root = dir.join("root");
+        let mut storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: root.to_string_lossy().into_owned(),
+        }]).unwrap();
+        fs::write(dir.join("outside.txt"), b"secret").unwrap();
+        fs::write(root.join("a.txt"), b"a").unwrap();
+        symlink(dir.join("outside.txt"), root.join("outside.txt")).unwrap();
+        symlink(root.join("a.txt"), root.join("inside.txt")).unwrap();
+
+        let outside = storage.media("outside.txt").unwrap().is_some();
+        let inside = storage.media("inside.txt").unwrap().is_some();
+        storage.set_follow_symlinks(true);
+        let followed = storage.media("outside.txt").unwrap().is_some();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!outside);
+        assert!(inside);
+        assert!(followed);
+    }
+
+    #[test]
+    fn hash_is_independent_of_the_buffer_size() {
+        let data = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for algo in &[HashAlgo::Md5, HashAlgo::Sha256] {
+            let expected = Storage::compute_hash_buffered(&data[..], DEFAULT_HASH_BUFFER_SIZE, *algo).unwrap();
+            for size in &[0, 1, 7, 4096, 1 << 20] {
+                assert_eq!(Storage::compute_hash_buffered(&data[..], *size, *algo).unwrap(), expected);
+            }
+        }
+        assert_eq!(
+            Storage::compute_hash_buffered(&b""[..], 4096, HashAlgo::Md5).unwrap(),
+            "d41d8cd98f00b204e9800998ecf8427e",
+        );
+    }
+
+    #[test]
+    fn gallery_items_single_and_list() {
+        let legacy: Gallery = serde_yaml::from_str("title: Old\ndescription: ~\nitems:\n  path: a.jpg\n  _hash: ~\n").unwrap();
+        assert_eq!(legacy.items.len(), 1);
+        assert_eq!(legacy.items[0].path, "a.jpg");
+
+        let list: Gallery = serde_yaml::from_str("title: New\ndescription: ~\nitems:\n  - path: a.jpg\n    _hash: ~\n  - path: b.jpg\n    _hash: ~\n").unwrap();
+        let paths = list.items.iter().map(|item| item.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["a.jpg", "b.jpg"]);
+
+        // Written as a list, which reads back the same.
+        let written = serde_yaml::to_string(&list).unwrap();
+        let read: Gallery = serde_yaml::from_str(&written).unwrap();
+        assert_eq!(read.items.len(), 2);
+    }
+
+    /// One second of silent 8 kHz mono audio.
+    fn wav() -> Vec<u8> {
+        let samples = 8000u32;
+        let mut data = Vec::new();
+        data.extend_from_slice(b"RIFF");
+        data.extend_from_slice(&(36 + samples).to_le_bytes());
+        data.extend_from_slice(b"WAVEfmt ");
+        data.extend_from_slice(&16u32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&samples.to_le_bytes());
+        data.extend_from_slice(&samples.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&8u16.to_le_bytes());
+        data.extend_from_slice(b"data");
+        data.extend_from_slice(&samples.to_le_bytes());
+        data.extend(std::iter::repeat(128u8).take(samples as usize));
+        data
+    }
+
+    #[test]
+    fn probe_media_reads_the_duration() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let mut storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: dir.to_string_lossy().into_owned(),
+        }]).unwrap();
+        let path = dir.join("a.wav");
+        fs::write(&path, wav()).unwrap();
+
+        let available = std::process::Command::new("ffprobe").arg("-version").output().is_ok();
+        let probed = storage.probe_media(&path, FileKind::Audio);
+        storage.set_ffprobe_binary("mediavault-missing-ffprobe");
+        let missing = storage.probe_media(&path, FileKind::Audio);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(missing.is_none());
+        if !available {
+            eprintln!("ffprobe is not installed, skipping the duration check");
+            return;
+        }
+        match probed {
+            Some(MediaInfo::Audio(AudioInfo { length })) => assert_eq!(length, 1),
+            other => panic!("expected audio info, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn hash_algorithms_give_known_digests() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let mut storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: dir.to_string_lossy().into_owned(),
+        }]).unwrap();
+        let md5 = storage.compute_hash(&b"hello world"[..]).unwrap();
+        storage.set_hash_algo(HashAlgo::Sha256);
+        let sha256 = storage.compute_hash(&b"hello world"[..]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(md5, "5eb63bbbe01eeed093cb22bb8f5acdc3");
+        assert_eq!(sha256, "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+    }
+
+    #[test]
+    fn paths_escaping_the_root_are_rejected() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: dir.join("root").to_string_lossy().into_owned(),
+        }]).unwrap();
+
+        let escapes = ["../../etc/passwd", "/etc/passwd", "a/../../b.txt"]
+            .iter()
+            .map(|path| {
+                let created = storage.file_create(path, FileMeta::default(), &b"x"[..]);
+                (storage.exists(path), created, storage.file_meta(path))
+            })
+            .collect::<Vec<_>>();
+        let escaped = fs::metadata(dir.join("b.txt")).is_ok();
+        let nested = storage.file_create("a/b/c.txt", FileMeta::default(), &b"c"[..]);
+        let nested_on_disk = fs::read(dir.join("root/a/b/c.txt"));
+        let nested_exists = storage.exists("a/b/c.txt");
+        fs::remove_dir_all(&dir).unwrap();
+
+        for (exists, created, meta) in escapes {
+            assert!(!exists);
+            for err in &[created.map(|_| ()).unwrap_err(), meta.map(|_| ()).unwrap_err()] {
+                match err.downcast_ref::<AppError>() {
+                    Some(AppError::BadRequest(_)) => {}
+                    other => panic!("expected a bad request, got {:?}", other),
+                }
+            }
+        }
+        assert!(!escaped);
+        assert_eq!(nested.unwrap().path, "a/b/c.txt");
+        assert_eq!(nested_on_disk.unwrap(), b"c");
+        assert!(nested_exists);
+    }
+
+    #[test]
+    fn file_info_has_filesystem_timestamps() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let storage = Storage::new(&[StorageRoot {
+            id: String::new(),
+            path: dir.to_string_lossy().into_owned(),
+        }]).unwrap();
+        // File systems may use a coarser clock than `now`.
+        let before = now() - chrono::Duration::seconds(1);
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        let first = storage.file_info("a.txt");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(dir.join("a.txt"), b"ab").unwrap();
+        let second = storage.file_info("a.txt");
+        let after = now() + chrono::Duration::seconds(1);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (first, second) = (first.unwrap(), second.unwrap());
+        let created = first.created_at.unwrap();
+        let updated = first.updated_at.unwrap();
+        assert!(before <= created && created <= after);
+        assert!(before <= updated && updated <= after);
+        assert!(second.updated_at.unwrap() >= updated);
+        assert!(second.updated_at.unwrap() <= after);
+        assert_eq!(second.size, 2);
+    }
 }
@@ -0,0 +1,252 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use failure::format_err;
+use mediavault_common::types::FileKind;
+
+use crate::prelude::*;
+
+/// Bound of thumbnails in pixels for requests that don't ask for a size.
+pub const DEFAULT_SIZE: u32 = 256;
+
+/// Default for the largest thumbnail bound that may be requested.
+pub const DEFAULT_MAX_SIZE: u32 = 1024;
+
+/// Encodings thumbnails are served in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ThumbFormat {
+    Jpeg,
+    Webp,
+}
+
+impl ThumbFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "jpg",
+            ThumbFormat::Webp => "webp",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "image/jpeg",
+            ThumbFormat::Webp => "image/webp",
+        }
+    }
+
+    /// WebP if the `Accept` header of a request explicitly lists it, JPEG
+    /// otherwise. Wildcards don't count, since every browser sends them.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let accepts_webp = accept
+            .unwrap_or("")
+            .split(',')
+            .any(|range| {
+                let mut parts = range.split(';').map(|part| part.trim());
+                if !parts.next().map_or(false, |mime| mime.eq_ignore_ascii_case("image/webp")) {
+                    return false;
+                }
+                // `q=0` means not acceptable.
+                parts
+                    .filter_map(|param| param.splitn(2, '=').nth(1).filter(|_| param.starts_with("q=")))
+                    .all(|q| q.parse::<f32>().map_or(false, |q| q > 0.0))
+            });
+        if accepts_webp {
+            ThumbFormat::Webp
+        } else {
+            ThumbFormat::Jpeg
+        }
+    }
+}
+
+/// Generates image and video thumbnails and caches them on disk.
+///
+/// Thumbnails are stored as `{hash}-{size}.jpg`, plus `{hash}-{size}.webp`
+/// once they were requested as WebP. A cached thumbnail is regenerated if
+/// its source was modified after it was written.
+pub struct Thumbnailer {
+    dir: PathBuf,
+    max_size: u32,
+    /// `ffmpeg` binary used to extract the first frame of videos and to
+    /// encode WebP, which the image crate can't write.
+    ffmpeg_binary: String,
+}
+
+impl Thumbnailer {
+    pub fn new(dir: PathBuf, max_size: u32) -> Result<Self, Error> {
+        fs::create_dir_all(&dir)?;
+        Ok(Thumbnailer {
+            dir,
+            max_size,
+            ffmpeg_binary: "ffmpeg".to_string(),
+        })
+    }
+
+    pub fn set_ffmpeg_binary(&mut self, binary: &str) {
+        self.ffmpeg_binary = binary.to_string();
+    }
+
+    /// Whether thumbnails can be generated for files of a kind.
+    pub fn supports(kind: FileKind) -> bool {
+        kind == FileKind::Image || kind == FileKind::Video
+    }
+
+    /// Path and format of a thumbnail that fits into `size` x `size` pixels,
+    /// generated if there is no fresh one yet.
+    ///
+    /// The size is clamped to the configured maximum. Images are never
+    /// scaled up. Animated GIFs and videos are shown by their first frame.
+    /// Falls back to JPEG if WebP can't be encoded.
+    pub fn thumbnail(
+        &self,
+        source: &Path,
+        kind: FileKind,
+        hash: &str,
+        size: u32,
+        format: ThumbFormat,
+    ) -> Result<(PathBuf, ThumbFormat), Error> {
+        let size = size.max(1).min(self.max_size);
+        let jpeg = self.jpeg(source, kind, hash, size)?;
+        if format == ThumbFormat::Jpeg {
+            return Ok((jpeg, ThumbFormat::Jpeg));
+        }
+
+        let path = self.dir.join(format!("{}-{}.{}", hash, size, format.extension()));
+        if Self::is_fresh(&path, source)? {
+            return Ok((path, format));
+        }
+        match self.webp_encode(&jpeg, &path) {
+            Ok(()) => Ok((path, format)),
+            Err(e) => {
+                log::debug!("Serving JPEG thumbnail instead of WebP: {}", e);
+                Ok((jpeg, ThumbFormat::Jpeg))
+            }
+        }
+    }
+
+    /// Whether a fresh JPEG thumbnail of `size` is cached.
+    pub fn is_cached(&self, source: &Path, hash: &str, size: u32) -> Result<bool, Error> {
+        let size = size.max(1).min(self.max_size);
+        Self::is_fresh(&self.dir.join(format!("{}-{}.jpg", hash, size)), source)
+    }
+
+    fn jpeg(&self, source: &Path, kind: FileKind, hash: &str, size: u32) -> Result<PathBuf, Error> {
+        let path = self.dir.join(format!("{}-{}.jpg", hash, size));
+        if Self::is_fresh(&path, source)? {
+            return Ok(path);
+        }
+
+        let image = self.first_frame(source, kind)?;
+        let thumb = if image.width() > size || image.height() > size {
+            image.thumbnail(size, size)
+        } else {
+            image
+        };
+        // Concurrent requests must never see a partially written thumbnail.
+        let tmp_path = self.dir.join(format!("{}-{}.{}.jpg", hash, size, uuid()));
+        thumb.save(&tmp_path)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(path)
+    }
+
+    /// Convert a JPEG thumbnail to WebP with `ffmpeg`.
+    fn webp_encode(&self, jpeg: &Path, path: &Path) -> Result<(), Error> {
+        let tmp_path = path.with_extension(format!("{}.webp", uuid()));
+        let output = Command::new(&self.ffmpeg_binary)
+            .args(&["-v", "error", "-i"])
+            .arg(jpeg)
+            .args(&["-f", "webp"])
+            .arg(&tmp_path)
+            .output()
+            .map_err(|e| format_err!("Could not run {}: {}", self.ffmpeg_binary, e))?;
+        if !output.status.success() {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(format_err!("ffmpeg failed to encode WebP: {}", String::from_utf8_lossy(&output.stderr)));
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Decode the image to thumbnail. The GIF decoder only reads the first
+    /// frame, so animations don't need special handling.
+    fn first_frame(&self, source: &Path, kind: FileKind) -> Result<image::DynamicImage, Error> {
+        match kind {
+            FileKind::Image => Ok(image::open(source)?),
+            FileKind::Video => self.video_frame(source),
+            _ => Err(format_err!("Can't create thumbnails for {:?} files", kind)),
+        }
+    }
+
+    /// Extract the first frame of a video as PNG with `ffmpeg`.
+    fn video_frame(&self, source: &Path) -> Result<image::DynamicImage, Error> {
+        let output = Command::new(&self.ffmpeg_binary)
+            .args(&["-v", "error", "-i"])
+            .arg(source)
+            .args(&["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+            .output()
+            .map_err(|e| format_err!("Could not run {}: {}", self.ffmpeg_binary, e))?;
+        if !output.status.success() || output.stdout.is_empty() {
+            return Err(format_err!(
+                "ffmpeg failed for {}: {}",
+                source.display(),
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        Ok(image::load_from_memory_with_format(&output.stdout, image::ImageFormat::PNG)?)
+    }
+
+    fn is_fresh(thumb: &Path, source: &Path) -> Result<bool, Error> {
+        let thumb_modified = match fs::metadata(thumb) {
+            Ok(meta) => meta.modified()?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(fs::metadata(source)?.modified()? <= thumb_modified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_thumb_format() {
+        assert_eq!(ThumbFormat::negotiate(None), ThumbFormat::Jpeg);
+        assert_eq!(ThumbFormat::negotiate(Some("*/*")), ThumbFormat::Jpeg);
+        assert_eq!(ThumbFormat::negotiate(Some("image/*,*/*;q=0.8")), ThumbFormat::Jpeg);
+        assert_eq!(
+            ThumbFormat::negotiate(Some("image/avif,image/webp,image/apng,image/*,*/*;q=0.8")),
+            ThumbFormat::Webp
+        );
+        assert_eq!(ThumbFormat::negotiate(Some("image/webp;q=0.5")), ThumbFormat::Webp);
+        assert_eq!(ThumbFormat::negotiate(Some("IMAGE/WEBP")), ThumbFormat::Webp);
+        assert_eq!(ThumbFormat::negotiate(Some("image/webp;q=0, image/jpeg")), ThumbFormat::Jpeg);
+    }
+
+    #[test]
+    fn thumbnail_is_bounded_and_reused() {
+        let dir = std::env::temp_dir().join(format!("mediavault-test-{}", uuid()));
+        let thumbs = Thumbnailer::new(dir.join(".thumbs"), 64).unwrap();
+        let source = dir.join("a.png");
+        image::RgbImage::new(200, 100).save(&source).unwrap();
+
+        let (path, format) = thumbs.thumbnail(&source, FileKind::Image, "a", 50, ThumbFormat::Jpeg).unwrap();
+        let thumb = image::open(&path).unwrap().to_rgb();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let (again, _) = thumbs.thumbnail(&source, FileKind::Image, "a", 50, ThumbFormat::Jpeg).unwrap();
+        let again_modified = fs::metadata(&again).unwrap().modified().unwrap();
+        let (clamped, _) = thumbs.thumbnail(&source, FileKind::Image, "a", 500, ThumbFormat::Jpeg).unwrap();
+        let clamped_thumb = image::open(&clamped).unwrap().to_rgb();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(format, ThumbFormat::Jpeg);
+        assert_eq!((thumb.width(), thumb.height()), (50, 25));
+        assert_eq!(again, path);
+        assert_eq!(again_modified, modified);
+        assert_eq!(clamped.file_name().unwrap(), "a-64.jpg");
+        assert_eq!((clamped_thumb.width(), clamped_thumb.height()), (64, 32));
+    }
+}
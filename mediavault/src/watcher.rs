@@ -0,0 +1,39 @@
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use std::{path::PathBuf, sync::mpsc, thread, time::Duration};
+
+use crate::{app::App, prelude::*};
+
+/// Events on the same path within this delay are reported once.
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(2);
+
+/// Watch directories and keep the index up to date from a background thread.
+pub fn spawn(app: App, dirs: Vec<PathBuf>) -> Result<(), Error> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::watcher(sender, DEBOUNCE_DELAY)?;
+    for dir in &dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+
+    thread::spawn(move || {
+        // Dropping the watcher stops it, so it has to live as long as the thread.
+        let _watcher = watcher;
+        for event in receiver {
+            let res = match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => app.path_changed(&path),
+                DebouncedEvent::Remove(path) => app.path_removed(&path),
+                DebouncedEvent::Rename(from, to) => {
+                    app.path_removed(&from).and_then(|_| app.path_changed(&to))
+                }
+                DebouncedEvent::Error(e, path) => {
+                    log::warn!("Watch error for {:?}: {}", path, e);
+                    Ok(())
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = res {
+                log::warn!("Could not index change: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
@@ -15,6 +15,8 @@ impl FileKind {
     pub fn from_mime(value: &str) -> Self {
         match value {
             value if value.starts_with("image/") => FileKind::Image,
+            value if value.starts_with("video/") => FileKind::Video,
+            value if value.starts_with("audio/") => FileKind::Audio,
             _ => FileKind::Other,
         }
     }
@@ -89,6 +91,20 @@ impl MediaInfo {
     }
 }
 
+/// GPS coordinates in decimal degrees.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct GeoLocation {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// A subtitle sidecar of a video.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Subtitle {
+    pub lang: String,
+    pub path: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FileInfo {
     pub hash: String,
@@ -98,6 +114,11 @@ pub struct FileInfo {
     pub media: Option<MediaInfo>,
     pub created_at: Option<DateTime>,
     pub updated_at: Option<DateTime>,
+    #[serde(default)]
+    pub subtitles: Vec<Subtitle>,
+    /// Where the file was taken, from EXIF data.
+    #[serde(default)]
+    pub location: Option<GeoLocation>,
 }
 
 impl FileInfo {
@@ -106,6 +127,20 @@ impl FileInfo {
             .map(|m| m.starts_with("image/"))
             .unwrap_or(false)
     }
+
+    /// SVGs are images, but text based and can not be decoded like raster
+    /// images.
+    pub fn is_svg(&self) -> bool {
+        self.mime.as_ref()
+            .map(|m| m == "image/svg+xml")
+            .unwrap_or(false)
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.mime.as_ref()
+            .map(|m| m.starts_with("video/"))
+            .unwrap_or(false)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -121,6 +156,34 @@ pub struct FileSource {
     pub extra: Option<serde_json::Value>,
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Visibility {
+    Public,
+    Private,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+impl Visibility {
+    pub fn from_str(value: &str) -> Self {
+        match value {
+            "private" => Visibility::Private,
+            _ => Visibility::Public,
+        }
+    }
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Private => "private",
+        }
+    }
+}
+
 #[derive(Default, Serialize, Deserialize, Clone, Debug)]
 pub struct FileMeta {
     pub title: Option<String>,
@@ -130,6 +193,15 @@ pub struct FileMeta {
     #[serde(default)]
     pub sources: Vec<FileSource>,
     pub hash: Option<String>,
+    /// Private files are hidden from unauthenticated requests.
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Featured files are shown on the home page, lower weights first.
+    #[serde(default)]
+    pub featured_weight: Option<i64>,
+    /// Private notes. Only returned to authenticated requests.
+    #[serde(default)]
+    pub notes: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -146,29 +218,195 @@ pub struct FileUpdate {
     pub description: Option<String>,
     #[serde(default)]
     pub tags: Option<Vec<String>>,
+    #[serde(default)]
+    pub visibility: Option<Visibility>,
+    #[serde(default)]
+    pub featured_weight: Option<i64>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Replaces all sources of the file.
+    #[serde(default)]
+    pub sources: Option<Vec<FileSource>>,
+    /// Applied in order after `tags`.
+    #[serde(default)]
+    pub tag_ops: Vec<TagOp>,
+}
+
+/// A conditional change to the tags of a file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum TagOp {
+    /// Add `tag` if the file has the tag `condition`.
+    AddIfPresent { tag: String, condition: String },
+    /// Remove all tags that are not in `keep`.
+    RemoveAllBut { keep: Vec<String> },
+}
+
+impl TagOp {
+    pub fn apply(&self, tags: &mut Vec<String>) {
+        match self {
+            TagOp::AddIfPresent { tag, condition } => {
+                if tags.contains(condition) && !tags.contains(tag) {
+                    tags.push(tag.clone());
+                }
+            }
+            TagOp::RemoveAllBut { keep } => {
+                tags.retain(|tag| keep.contains(tag));
+            }
+        }
+    }
+}
+
+/// Deserialize a field that distinguishes absent (`None`) from `null`
+/// (`Some(None)`). Must be combined with `#[serde(default)]`.
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: serde::Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+/// Partial metadata update.
+///
+/// Absent fields are left unchanged. `null` clears title, description,
+/// featured weight and notes, an empty list clears the tags.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilePatch {
+    pub hash: String,
+    #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+    pub title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+    pub description: Option<Option<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Visibility>,
+    #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+    pub featured_weight: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "double_option", skip_serializing_if = "Option::is_none")]
+    pub notes: Option<Option<String>>,
+}
+
+/// Move a file to a different storage path, keeping its hash and metadata.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FileMove {
+    pub from: String,
+    pub to: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum FileFilter {
     Tag(String),
+    /// Files without any tags.
+    Untagged,
     Kind(FileKind),
+    /// Files of any of the kinds. An empty list matches nothing.
+    Kinds(Vec<FileKind>),
+    /// Files with the mime type. A trailing `*` matches any suffix, e.g.
+    /// `video/*`.
+    Mime(String),
+    Visibility(Visibility),
+    /// Images, videos and audio files without extracted media info.
+    MissingMediaInfo,
+    /// Full text search in title and description.
+    Text(String),
+    /// Files with a location inside the box. Bounds are inclusive.
+    BoundingBox {
+        min_lat: f64,
+        max_lat: f64,
+        min_lon: f64,
+        max_lon: f64,
+    },
+    /// Files with a size in bytes between the bounds. Bounds are inclusive,
+    /// a missing bound is unrestricted.
+    SizeRange {
+        min: Option<i64>,
+        max: Option<i64>,
+    },
+    /// Files with dimensions in pixels between the bounds. Bounds are
+    /// inclusive, a missing bound is unrestricted. Files without dimensions
+    /// only match if no bound is set.
+    Dimension {
+        min_width: Option<u32>,
+        max_width: Option<u32>,
+        min_height: Option<u32>,
+        max_height: Option<u32>,
+    },
     And(Box<FileFilter>, Box<FileFilter>),
     Or(Box<FileFilter>, Box<FileFilter>),
+    /// Files not matching the inner filter.
+    Not(Box<FileFilter>),
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl FileFilter {
+    /// Nesting depth of the filter tree. A single condition has depth 1.
+    pub fn depth(&self) -> usize {
+        match self {
+            FileFilter::And(ref left, ref right) | FileFilter::Or(ref left, ref right) => {
+                1 + left.depth().max(right.depth())
+            }
+            FileFilter::Not(ref inner) => 1 + inner.depth(),
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum FileSort {
     Updated,
     Created,
     Type,
     Size,
     Length,
+    /// Case insensitive. Files without a title come last.
+    Title,
+    /// Different order for every query. The direction is ignored, and so are
+    /// sort items after it.
+    Random,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl FileSort {
+    /// Direction used if neither the query nor the server config specify one.
+    pub fn default_ascending(&self) -> bool {
+        match self {
+            FileSort::Type | FileSort::Title | FileSort::Random => true,
+            FileSort::Updated | FileSort::Created | FileSort::Size | FileSort::Length => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct FileSortItem {
     pub sort: FileSort,
-    pub ascending: bool,
+    /// Sort direction. The server picks a default for the field if unset.
+    #[serde(default)]
+    pub ascending: Option<bool>,
+}
+
+impl FileSortItem {
+    pub fn is_ascending(&self) -> bool {
+        self.ascending.unwrap_or_else(|| self.sort.default_ascending())
+    }
+}
+
+/// Value of the sort column at a cursor position.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub enum CursorValue {
+    Null,
+    Integer(i64),
+    Text(String),
+}
+
+/// Position after the last item of a page, used for keyset pagination.
+///
+/// Rows are ordered by the sort item of the query and then by hash, so a
+/// cursor is only valid for queries with the same sort item. Queries with
+/// more than one sort item can't use cursors.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct FileCursor {
+    pub sort: FileSortItem,
+    pub value: CursorValue,
+    pub hash: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -177,6 +415,20 @@ pub struct FileQuery {
     pub page_size: u32,
     pub filter: Option<FileFilter>,
     pub sort: Vec<FileSortItem>,
+    /// Continue after this position instead of using `page`.
+    #[serde(default)]
+    pub cursor: Option<FileCursor>,
+    /// Only return files with one of these hashes.
+    #[serde(default)]
+    pub include_hashes: Option<Vec<String>>,
+    /// Never return files with one of these hashes.
+    #[serde(default)]
+    pub exclude_hashes: Option<Vec<String>>,
+}
+
+impl FileQuery {
+    /// Largest page size the server returns.
+    pub const MAX_PAGE_SIZE: u32 = 100;
 }
 
 impl Default for FileQuery {
@@ -185,7 +437,10 @@ impl Default for FileQuery {
             page: 1,
             page_size: 30,
             filter: None,
-            sort: vec![FileSortItem{sort: FileSort::Updated, ascending: false}],
+            sort: vec![FileSortItem{sort: FileSort::Updated, ascending: Some(false)}],
+            cursor: None,
+            include_hashes: None,
+            exclude_hashes: None,
         }
     }
 }
@@ -196,16 +451,322 @@ pub struct FilesPage {
     pub total: u32,
     pub page: u32,
     pub page_size: u32,
+    /// Number of pages needed for all matching files.
+    #[serde(default)]
+    pub total_pages: u32,
+    /// Cursor for the next page, if there might be more items.
+    pub next_cursor: Option<FileCursor>,
+    /// Relevance of each item for text searches. Lower is better.
+    #[serde(default)]
+    pub scores: Option<Vec<f64>>,
+    /// Combined size of all matching files, if requested.
+    #[serde(default)]
+    pub total_size: Option<i64>,
 }
 
 impl FilesPage {
+    /// Pages needed for `total` items: `ceil(total / page_size)`.
+    pub fn page_count(total: u32, page_size: u32) -> u32 {
+        if page_size == 0 || total == 0 {
+            0
+        } else {
+            (total - 1) / page_size + 1
+        }
+    }
+
+    /// Whether there are pages after this one. Pages start at 1.
     pub fn has_more(&self) -> bool {
-        self.items.len() as u64 == self.page_size as u64
+        u64::from(self.page.max(1)) * u64::from(self.page_size) < u64::from(self.total)
     }
 }
 
+/// Query string parameters of the files query.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilesParams {
+    /// Wrap the response in a `FilesEnvelope`.
+    #[serde(default)]
+    pub envelope: Option<String>,
+    /// Compute `FilesPage::total_size`.
+    #[serde(default)]
+    pub totals: Option<String>,
+}
+
+/// Boolean query string parameter, set with `1` or `true`.
+fn param_flag(value: &Option<String>) -> bool {
+    match value.as_ref().map(|s| s.as_str()) {
+        Some("1") | Some("true") => true,
+        _ => false,
+    }
+}
+
+impl FilesParams {
+    pub fn envelope(&self) -> bool {
+        param_flag(&self.envelope)
+    }
+
+    pub fn totals(&self) -> bool {
+        param_flag(&self.totals)
+    }
+}
+
+/// Query string parameters of media requests.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MediaParams {
+    /// Check the content against the indexed hash and send it along as
+    /// `x-content-md5` or `x-content-sha256`, depending on the hash algorithm.
+    #[serde(default)]
+    pub verify: Option<String>,
+}
+
+impl MediaParams {
+    pub fn verify(&self) -> bool {
+        param_flag(&self.verify)
+    }
+}
+
+/// Query string parameters of thumbnail requests.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ThumbParams {
+    /// Bound for width and height in pixels.
+    pub size: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PageMeta {
+    pub total: u32,
+    #[serde(default)]
+    pub total_size: Option<i64>,
+    pub page: u32,
+    pub page_size: u32,
+    pub total_pages: u32,
+}
+
+/// A page of files in the `{ data, meta }` shape.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilesEnvelope {
+    pub data: Vec<File>,
+    pub meta: PageMeta,
+}
+
+impl From<FilesPage> for FilesEnvelope {
+    fn from(page: FilesPage) -> Self {
+        FilesEnvelope {
+            data: page.items,
+            meta: PageMeta {
+                total: page.total,
+                total_size: page.total_size,
+                page: page.page,
+                page_size: page.page_size,
+                total_pages: page.total_pages,
+            },
+        }
+    }
+}
+
+/// Request for specific files in a custom order.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilesByHashes {
+    pub hashes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct FilesByHashesResult {
+    /// Files in the requested order. Repeated hashes are only returned once.
+    pub items: Vec<File>,
+    /// Requested hashes without a visible file, in the requested order.
+    pub missing: Vec<String>,
+}
+
+/// Identical content stored at several paths.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Duplicate {
+    pub hash: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KindCount {
+    pub kind: FileKind,
+    pub count: u32,
+}
+
+/// Library statistics.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Stats {
+    pub total: u32,
+    /// Number of files per kind. Kinds without files are omitted.
+    pub kinds: Vec<KindCount>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TopTagsQuery {
+    /// Number of tags to return. Defaults to `TopTagsQuery::DEFAULT_LIMIT`.
+    pub limit: Option<u32>,
+}
+
+impl TopTagsQuery {
+    pub const DEFAULT_LIMIT: u32 = 10;
+    pub const MAX_LIMIT: u32 = 100;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TagRename {
+    pub from: String,
+    /// New name. Files that have both tags end up with this one only.
+    pub to: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TagMerge {
+    pub sources: Vec<String>,
+    pub target: String,
+}
+
+/// Result of renaming or merging tags.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TagRenameReport {
+    /// Hashes of the files that had any of the tags.
+    pub updated: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TagSuggestQuery {
+    /// Prefix of the tags.
+    pub q: String,
+    /// Number of tags to return. Defaults to `TagSuggestQuery::DEFAULT_LIMIT`.
+    pub limit: Option<u32>,
+}
+
+impl TagSuggestQuery {
+    pub const DEFAULT_LIMIT: u32 = 10;
+    pub const MAX_LIMIT: u32 = 100;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FolderStatsQuery {
+    /// Folder to list the subfolders of. The top level if unset or empty.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+/// Totals of all files in a folder, including nested folders.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FolderStats {
+    pub path: String,
+    pub count: u32,
+    /// Total size in bytes.
+    pub size: i64,
+}
+
+/// Configuration exposed to the frontend.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientConfig {
+    /// Query for the landing page.
+    pub home_query: FileQuery,
+}
+
+// Storage tree.
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum TreeEntryKind {
+    Directory(Vec<TreeEntry>),
+    File {
+        size: u64,
+        /// Whether the file is present in the database.
+        indexed: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TreeEntry {
+    pub name: String,
+    pub path: String,
+    pub kind: TreeEntryKind,
+}
+
+impl TreeEntry {
+    pub fn is_dir(&self) -> bool {
+        match self.kind {
+            TreeEntryKind::Directory(_) => true,
+            TreeEntryKind::File { .. } => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PathQuery {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TreeQuery {
+    pub path: Option<String>,
+}
+
 // Importer related types.
 
+/// An importer script discovered in storage.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImporterInfo {
+    pub path: String,
+    /// Name declared with a `// @name` header comment.
+    pub name: Option<String>,
+    /// URL patterns declared with `// @match` header comments.
+    #[serde(default)]
+    pub matches: Vec<String>,
+}
+
+impl ImporterInfo {
+    /// Check if the url matches one of the declared patterns.
+    /// `*` matches any sequence of characters.
+    /// Importers without patterns match every url.
+    pub fn matches_url(&self, url: &str) -> bool {
+        if self.matches.is_empty() {
+            return true;
+        }
+        self.matches.iter().any(|pattern| {
+            let parts = pattern.split('*').collect::<Vec<_>>();
+            if parts.len() == 1 {
+                return url == pattern;
+            }
+            let (first, last) = (parts[0], parts[parts.len() - 1]);
+            if !url.starts_with(first) || url.len() < first.len() + last.len() {
+                return false;
+            }
+
+            let mut rest = &url[first.len()..url.len() - last.len()];
+            for part in &parts[1..parts.len() - 1] {
+                match rest.find(part) {
+                    Some(index) => rest = &rest[index + part.len()..],
+                    None => return false,
+                }
+            }
+            url.ends_with(last)
+        })
+    }
+}
+
+/// Run an importer against a url or an html document.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Import {
+    pub importer_path: String,
+    /// An `http(s)://` url to fetch, or html to pass to the importer as is.
+    pub input: String,
+}
+
+/// Run an importer against a url.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImportRun {
+    pub importer_path: String,
+    pub url: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ImporterItem {
     File(FileSource),
@@ -217,3 +778,161 @@ pub enum ImporterOutput {
     NoMatch,
     Err(String),
 }
+
+/// Download and store the files of importer sources.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImportFetch {
+    /// Storage directory for the downloaded files.
+    /// Defaults to `imports`.
+    pub path: Option<String>,
+    pub sources: Vec<FileSource>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ImportFetchStatus {
+    Stored(File),
+    /// A file with the same content hash already exists.
+    Duplicate(String),
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImportFetchItem {
+    pub url: String,
+    pub status: ImportFetchStatus,
+}
+
+/// Sources for an already indexed file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImportBackfillItem {
+    /// Content hash of the file.
+    pub hash: String,
+    pub sources: Vec<FileSource>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImportBackfill {
+    pub items: Vec<ImportBackfillItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ImportBackfillReport {
+    /// Hashes of files that got new sources.
+    pub updated: Vec<String>,
+    /// Hashes without an indexed file.
+    pub missing: Vec<String>,
+}
+
+/// Query string parameters of `POST /api/maintenance/tags-from-filename`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TagsFromFilenameParams {
+    /// Only report the proposed tags. Set to `0` or `false` to apply them.
+    #[serde(default)]
+    pub dry_run: Option<String>,
+}
+
+impl TagsFromFilenameParams {
+    /// Dry runs are the default, so tags are only applied on request.
+    pub fn dry_run(&self) -> bool {
+        match self.dry_run.as_ref().map(|s| s.as_str()) {
+            Some("0") | Some("false") => false,
+            _ => true,
+        }
+    }
+}
+
+/// Tags proposed for a file based on its name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TagProposal {
+    pub path: String,
+    /// Tags the file does not have yet.
+    pub tags: Vec<String>,
+}
+
+/// Result of removing expired files from the trash.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TrashPurgeReport {
+    /// Hashes of the removed files.
+    pub purged: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TagsFromFilenameReport {
+    /// Whether the tags were added, or only proposed.
+    pub applied: bool,
+    pub files: Vec<TagProposal>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(total: u32, page: u32, page_size: u32) -> FilesPage {
+        FilesPage {
+            items: Vec::new(),
+            total,
+            page,
+            page_size,
+            total_pages: FilesPage::page_count(total, page_size),
+            next_cursor: None,
+            scores: None,
+            total_size: None,
+        }
+    }
+
+    #[test]
+    fn page_count_boundaries() {
+        assert_eq!(FilesPage::page_count(0, 30), 0);
+        assert_eq!(FilesPage::page_count(1, 30), 1);
+        assert_eq!(FilesPage::page_count(30, 30), 1);
+        assert_eq!(FilesPage::page_count(31, 30), 2);
+        assert_eq!(FilesPage::page_count(60, 30), 2);
+        assert_eq!(FilesPage::page_count(10, 0), 0);
+    }
+
+    #[test]
+    fn has_more_boundaries() {
+        assert!(!page(0, 1, 30).has_more());
+        assert!(!page(30, 1, 30).has_more());
+        assert!(page(31, 1, 30).has_more());
+        assert!(page(60, 1, 30).has_more());
+        assert!(!page(60, 2, 30).has_more());
+        // Page 0 is the first page.
+        assert!(page(60, 0, 30).has_more());
+    }
+
+    #[test]
+    fn file_kind_from_mime() {
+        assert_eq!(FileKind::from_mime("image/jpeg"), FileKind::Image);
+        assert_eq!(FileKind::from_mime("video/mp4"), FileKind::Video);
+        assert_eq!(FileKind::from_mime("audio/mpeg"), FileKind::Audio);
+        assert_eq!(FileKind::from_mime("audio/ogg"), FileKind::Audio);
+        assert_eq!(FileKind::from_mime("application/pdf"), FileKind::Other);
+        assert_eq!(FileKind::from_mime(""), FileKind::Other);
+    }
+
+    #[test]
+    fn file_patch_distinguishes_null_from_absent() {
+        let patch: FilePatch = serde_json::from_str(r#"{"hash": "a", "title": null, "tags": []}"#).unwrap();
+        assert_eq!(patch.title, Some(None));
+        assert_eq!(patch.description, None);
+        assert_eq!(patch.tags, Some(Vec::new()));
+    }
+
+    #[test]
+    fn importer_url_patterns() {
+        let info = |matches: &[&str]| ImporterInfo {
+            path: "a.importer.js".to_string(),
+            name: None,
+            matches: matches.iter().map(|m| m.to_string()).collect(),
+        };
+
+        assert!(info(&[]).matches_url("https://any.com/"));
+        assert!(info(&["https://example.com/"]).matches_url("https://example.com/"));
+        assert!(!info(&["https://example.com/"]).matches_url("https://example.com/a"));
+        let wildcard = info(&["https://*.example.com/*/view"]);
+        assert!(wildcard.matches_url("https://www.example.com/123/view"));
+        assert!(!wildcard.matches_url("https://www.example.com/123/edit"));
+        assert!(!wildcard.matches_url("http://www.example.com/123/view"));
+    }
+}
@@ -75,6 +75,10 @@ where
         .and_then(|raw_body| serde_json::from_str::<O>(&raw_body).map_err(|e| e.to_string()))
 }
 
+pub fn config() -> impl Future<Item = t::ClientConfig, Error = String> {
+    fetch_json::<(), _>("/api/config", Method::Get, None)
+}
+
 pub fn file(hash: &str) -> impl Future<Item = t::File, Error = String> {
     fetch_json::<(), _>(&format!("/api/file/{}", hash), Method::Get, None)
 }
@@ -83,7 +87,37 @@ pub fn files(q: t::FileQuery) -> impl Future<Item = t::FilesPage, Error = String
     fetch_json("/api/files", Method::Post, Some(q))
 }
 
+/// Number of files matching the filter.
+pub fn files_count(filter: Option<t::FileFilter>) -> impl Future<Item = u32, Error = String> {
+    fetch_json("/api/files/count", Method::Post, Some(filter))
+}
+
+pub fn featured() -> impl Future<Item = Vec<t::File>, Error = String> {
+    fetch_json::<(), _>("/api/featured", Method::Get, None)
+}
+
+pub fn stats() -> impl Future<Item = t::Stats, Error = String> {
+    fetch_json::<(), _>("/api/stats", Method::Get, None)
+}
+
 pub fn file_update(data: &t::FileUpdate) -> impl Future<Item = t::File, Error = String> {
     // TODO: propagate json encode error?
     fetch_json("/api/file", Method::Put, Some(data.clone()))
 }
+
+pub fn file_delete(hash: &str) -> impl Future<Item = (), Error = String> {
+    fetch_json::<(), _>(&format!("/api/file/{}", hash), Method::Delete, None)
+}
+
+pub fn tags() -> impl Future<Item = Vec<t::TagCount>, Error = String> {
+    fetch_json::<(), _>("/api/tags", Method::Get, None)
+}
+
+pub fn tags_top(limit: u32) -> impl Future<Item = Vec<t::TagCount>, Error = String> {
+    fetch_json::<(), _>(&format!("/api/tags/top?limit={}", limit), Method::Get, None)
+}
+
+pub fn tag_suggest(prefix: &str, limit: u32) -> impl Future<Item = Vec<String>, Error = String> {
+    let q = url::form_urlencoded::byte_serialize(prefix.as_bytes()).collect::<String>();
+    fetch_json::<(), _>(&format!("/api/tags/suggest?q={}&limit={}", q, limit), Method::Get, None)
+}
@@ -36,9 +36,23 @@ pub fn start() {
     //        views::Message::Files(views::files::Message::Test)
     //    });
     mb.send(views::Message::Start);
-    mb.send(views::Message::Files(views::files::Message::Query(
-        Default::default(),
-    )));
+
+    // A query in the url is applied by the router instead.
+    if !views::location_params().is_empty() {
+        return;
+    }
+    mb.spawn(api::config(), |res| {
+        let query = match res {
+            Ok(config) => config.home_query,
+            Err(e) => {
+                error!("Could not load config: {}", e);
+                Default::default()
+            }
+        };
+        views::Message::Files(views::files::Message::Query(
+            views::files::initial_query(query),
+        ))
+    });
 }
 
 pub fn main() {}
@@ -0,0 +1,79 @@
+use draco::html as h;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Open,
+    Confirm,
+    Cancel,
+}
+
+/// A modal dialog that asks for confirmation before a destructive action.
+///
+/// The parent forwards messages to `handle` and performs the action once it
+/// returns `true`.
+#[derive(Debug, Clone)]
+pub struct Confirm {
+    question: String,
+    confirm_label: String,
+    open: bool,
+}
+
+impl Confirm {
+    pub fn new(question: &str, confirm_label: &str) -> Self {
+        Confirm {
+            question: question.to_string(),
+            confirm_label: confirm_label.to_string(),
+            open: false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Apply a message. Returns true if the action was confirmed.
+    pub fn handle(&mut self, message: Message) -> bool {
+        match message {
+            Message::Open => {
+                self.open = true;
+                false
+            }
+            Message::Confirm => {
+                // Ignore stray confirmations for a closed dialog.
+                let confirmed = self.open;
+                self.open = false;
+                confirmed
+            }
+            Message::Cancel => {
+                self.open = false;
+                false
+            }
+        }
+    }
+
+    pub fn render(&self) -> draco::Node<Message> {
+        if !self.open {
+            return h::div().into();
+        }
+
+        let dialog = h::div()
+            .class("m-Confirm-Dialog")
+            .push(h::p().push(self.question.clone()))
+            .push(
+                h::div()
+                    .class("m-Confirm-Actions")
+                    .push(h::button().push("Cancel").on("click", |_| Message::Cancel))
+                    .push(
+                        h::button()
+                            .class("m-Confirm-Confirm")
+                            .push(self.confirm_label.clone())
+                            .on("click", |_| Message::Confirm),
+                    ),
+            );
+
+        h::div()
+            .class("m-Confirm")
+            .push(dialog)
+            .into()
+    }
+}
@@ -1,8 +1,12 @@
 use js_sys::Date;
 use mediavault_common::types as t;
 
+use super::confirm;
+
 type Error = String;
 
+const TAG_SUGGEST_LIMIT: u32 = 10;
+
 #[derive(Clone, Debug)]
 pub enum ContainerMessage {
     Load { hash: String },
@@ -62,6 +66,7 @@ impl draco::App for FileContainer {
 pub enum Edit {
     Title(String),
     Description(String),
+    Notes(String),
     TagRemove(String),
     TagAdd(String),
 }
@@ -72,7 +77,14 @@ pub enum Message {
     Edit(Edit),
     Save,
     Saved(t::File),
+    SaveFailed(String),
+    /// Send the last failed update again.
+    Retry,
     Error(String),
+    Delete(confirm::Message),
+    Deleted,
+    /// Existing tags starting with the given tag input.
+    TagSuggestions(String, Vec<String>),
 }
 
 #[derive(Debug, Clone)]
@@ -82,13 +94,19 @@ pub struct FileView {
     // Edit fields.
     title: Option<String>,
     description: Option<String>,
+    notes: Option<String>,
     tag_input: String,
+    tag_suggestions: Vec<String>,
     tags: Option<Vec<String>>,
     last_edit: Option<f64>,
 
     // Saving state.
     saving: bool,
+    save_error: Option<String>,
     error: Option<String>,
+
+    delete_confirm: confirm::Confirm,
+    deleting: bool,
 }
 
 impl FileView {
@@ -97,11 +115,16 @@ impl FileView {
             file,
             title: None,
             description: None,
+            notes: None,
             tag_input: String::new(),
+            tag_suggestions: Vec::new(),
             tags: None,
             last_edit: None,
             saving: false,
+            save_error: None,
             error: None,
+            delete_confirm: confirm::Confirm::new("Delete this file permanently?", "Delete"),
+            deleting: false,
         }
     }
 
@@ -121,9 +144,42 @@ impl FileView {
             .unwrap_or(String::new())
     }
 
+    fn notes(&self) -> String {
+        self.notes
+            .as_ref()
+            .or(self.file.meta.notes.as_ref())
+            .map(|s| s.clone())
+            .unwrap_or(String::new())
+    }
+
     fn tags(&self) -> &Vec<String> {
         self.tags.as_ref().unwrap_or(&self.file.meta.tags)
     }
+
+    fn save(&mut self, mailbox: &draco::Mailbox<Message>) {
+        self.saving = true;
+        self.save_error = None;
+        mailbox.spawn(
+            crate::api::file_update(&t::FileUpdate {
+                hash: self.file.info.hash.clone(),
+                title: self.title.clone(),
+                description: self.description.clone(),
+                tags: self.tags.clone(),
+                visibility: None,
+                featured_weight: None,
+                notes: self.notes.clone(),
+                sources: None,
+                tag_ops: Vec::new(),
+            }),
+            |res| match res {
+                Ok(d) => Message::Saved(d),
+                Err(e) => {
+                    log!("fetch error: {}", e);
+                    Message::SaveFailed(e)
+                }
+            },
+        );
+    }
 }
 
 impl draco::App for FileView {
@@ -141,6 +197,9 @@ impl draco::App for FileView {
                     self::Edit::Description(description) => {
                         self.description = Some(description);
                     }
+                    self::Edit::Notes(notes) => {
+                        self.notes = Some(notes);
+                    }
                     self::Edit::TagRemove(tag) => {
                         let mut tags = self.tags().clone();
                         tags.retain(|t| t != &tag);
@@ -162,6 +221,18 @@ impl draco::App for FileView {
                             }
                         } else {
                             self.tag_input = tag.trim().to_string();
+                            if self.tag_input.is_empty() {
+                                self.tag_suggestions.clear();
+                            } else {
+                                let prefix = self.tag_input.clone();
+                                mailbox.spawn(crate::api::tag_suggest(&prefix, TAG_SUGGEST_LIMIT), move |res| {
+                                    let tags = res.unwrap_or_else(|e| {
+                                        error!("Could not load tag suggestions: {}", e);
+                                        Vec::new()
+                                    });
+                                    Message::TagSuggestions(prefix.clone(), tags)
+                                });
+                            }
                             // NOTE: early return in case of non-complete tag.
                             return;
                         }
@@ -174,23 +245,8 @@ impl draco::App for FileView {
                 if let Some(last_edit) = self.last_edit {
                     let time_passed = Date::now() - last_edit;
                     let should_save = time_passed > 5000.0;
-                    if should_save {
-                        self.saving = true;
-                        mailbox.spawn(
-                            crate::api::file_update(&t::FileUpdate {
-                                hash: self.file.info.hash.clone(),
-                                title: self.title.clone(),
-                                description: self.description.clone(),
-                                tags: self.tags.clone(),
-                            }),
-                            |res| match res {
-                                Ok(d) => Message::Saved(d),
-                                Err(e) => {
-                                    log!("fetch error: {}", e);
-                                    Message::Error(e)
-                                }
-                            },
-                        );
+                    if should_save && !self.saving {
+                        self.save(mailbox);
                     }
                 }
             }
@@ -198,12 +254,47 @@ impl draco::App for FileView {
                 self.file = f;
                 self.title = None;
                 self.description = None;
+                self.notes = None;
                 self.last_edit = None;
                 self.saving = false;
+                self.save_error = None;
+            }
+            SaveFailed(e) => {
+                // Keep the edits, so they can be sent again.
+                self.saving = false;
+                self.save_error = Some(e);
+            }
+            Retry => {
+                if !self.saving {
+                    self.save(mailbox);
+                }
             }
             Error(e) => {
+                self.deleting = false;
                 self.error = Some(e);
             }
+            Delete(msg) => {
+                if self.delete_confirm.handle(msg) && !self.deleting {
+                    self.deleting = true;
+                    mailbox.spawn(crate::api::file_delete(&self.file.info.hash), |res| match res {
+                        Ok(()) => Message::Deleted,
+                        Err(e) => {
+                            log!("delete error: {}", e);
+                            Message::Error(e)
+                        }
+                    });
+                }
+            }
+            TagSuggestions(prefix, tags) => {
+                // Ignore responses for outdated input.
+                if prefix == self.tag_input {
+                    self.tag_suggestions = tags;
+                }
+            }
+            Deleted => {
+                self.deleting = false;
+                super::Route::goto(&super::Route::home());
+            }
         }
     }
 
@@ -232,6 +323,18 @@ impl draco::App for FileView {
         };
         let description = h::div().push(description_textarea);
 
+        let notes_textarea = h::textarea()
+            .class("m-FileView-Notes")
+            .attr("placeholder", "Private notes...")
+            .push(self.notes())
+            .on_input(|value| Message::Edit(Edit::Notes(value)));
+        let notes_textarea = if self.saving {
+            notes_textarea.attr("disabled", "disabled")
+        } else {
+            notes_textarea
+        };
+        let notes = h::div().push(notes_textarea);
+
         let tags = h::div()
             .class("m-TagEditor-Tags")
             .append(self.tags().iter().map(|tag| {
@@ -248,7 +351,16 @@ impl draco::App for FileView {
                             }),
                     )
             }));
+        let suggestions = h::datalist()
+            .attr("id", "m-TagEditor-Suggestions")
+            .append(
+                self.tag_suggestions
+                    .iter()
+                    .filter(|tag| !self.tags().contains(tag))
+                    .map(|tag| h::option().attr("value", tag.clone())),
+            );
         let tag_input = h::input()
+            .attr("list", "m-TagEditor-Suggestions")
             .attr("value", self.tag_input.clone())
             .attr("placeholder", "Add tags...")
             .on_input(|value| Message::Edit(Edit::TagAdd(value)));
@@ -262,13 +374,33 @@ impl draco::App for FileView {
         let tag_editor = h::div()
             .class("m-TagEditor")
             .push(tags)
-            .push(h::div().push(tag_input));
+            .push(h::div().push(tag_input).push(suggestions));
+
+        let delete_button = h::button()
+            .class("m-FileView-Delete")
+            .push("Delete")
+            .on("click", |_| Message::Delete(confirm::Message::Open));
+        let delete_button = if self.deleting {
+            delete_button.attr("disabled", "disabled")
+        } else {
+            delete_button
+        };
 
-        let sidebar = h::div()
-            .class("m-FileView-SideBar")
+        let mut sidebar = h::div().class("m-FileView-SideBar");
+        if let Some(e) = self.save_error.as_ref() {
+            sidebar = sidebar.push(
+                h::div()
+                    .class("m-FileView-SaveError")
+                    .push(h::span().push(format!("Saving failed: {}", e)))
+                    .push(h::button().push("Retry").on("click", |_| Message::Retry)),
+            );
+        }
+        let sidebar = sidebar
             .push(title)
             .push(description)
-            .push(tag_editor);
+            .push(notes)
+            .push(tag_editor)
+            .push(h::div().push(delete_button));
 
         let viewer = h::div()
             .class("m-FileView-Viewer")
@@ -278,6 +410,7 @@ impl draco::App for FileView {
             .class("m-FileView")
             .push(sidebar)
             .push(viewer)
+            .push(self.delete_confirm.render().map(Message::Delete))
             //.push(h::button().push("Reset").on("click", |_| Message::Reset))
             .into()
     }
@@ -4,9 +4,24 @@ use mediavault_common::types as t;
 #[derive(Debug, Clone)]
 pub enum Message {
     Query(t::FileQuery),
-    Data(t::FilesPage),
-    Error(String),
+    /// Response to the query with the given generation.
+    Data(u32, t::FilesPage),
+    Error(u32, String),
     ShowFile(t::File),
+    PageSize(u32),
+    TopTags(Vec<t::TagCount>),
+    ShowTag(String),
+    Stats(t::Stats),
+    /// Restrict the query to a kind, or lift the restriction if it is active.
+    KindToggle(t::FileKind),
+    ClearFilter,
+    /// Append a sort item for a field that is not sorted on yet.
+    SortAdd,
+    /// Change the field of the sort item at the given position.
+    SortField(usize, t::FileSort),
+    /// Flip the direction of the sort item at the given position.
+    SortDirection(usize),
+    SortRemove(usize),
 }
 
 #[derive(Debug, Clone)]
@@ -14,6 +29,12 @@ pub struct Files {
     query: t::FileQuery,
     data: Option<t::FilesPage>,
     error: Option<String>,
+    /// Most used tags, shown as quick filters. Loaded with the first query.
+    top_tags: Option<Vec<t::TagCount>>,
+    stats: Option<t::Stats>,
+    /// Incremented for every query, so responses to outdated queries that
+    /// arrive late can be ignored.
+    generation: u32,
 }
 
 impl Default for Files {
@@ -22,10 +43,337 @@ impl Default for Files {
             query: t::FileQuery::default(),
             data: None,
             error: None,
+            top_tags: None,
+            stats: None,
+            generation: 0,
         }
     }
 }
 
+const PAGE_SIZES: &[u32] = &[20, 50, 100];
+const TOP_TAGS_LIMIT: u32 = 10;
+/// Bound of grid thumbnails in pixels.
+const THUMB_SIZE: u32 = 256;
+const KINDS: &[t::FileKind] = &[
+    t::FileKind::Image,
+    t::FileKind::Video,
+    t::FileKind::Audio,
+    t::FileKind::Other,
+];
+
+/// The kind the filter is restricted to: either the whole filter or the
+/// right operand of an `And`, as built by `filter_kind_set`.
+fn filter_kind(filter: Option<&t::FileFilter>) -> Option<t::FileKind> {
+    match filter? {
+        t::FileFilter::Kind(kind) => Some(*kind),
+        t::FileFilter::And(_, right) => match **right {
+            t::FileFilter::Kind(kind) => Some(kind),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Replace the kind restriction of a filter, keeping everything else.
+fn filter_kind_set(filter: Option<t::FileFilter>, kind: Option<t::FileKind>) -> Option<t::FileFilter> {
+    let rest = match filter {
+        Some(t::FileFilter::Kind(_)) => None,
+        Some(t::FileFilter::And(left, right)) => match *right {
+            t::FileFilter::Kind(_) => Some(*left),
+            right => Some(t::FileFilter::And(left, Box::new(right))),
+        },
+        other => other,
+    };
+    match (rest, kind) {
+        (Some(rest), Some(kind)) => Some(t::FileFilter::And(
+            Box::new(rest),
+            Box::new(t::FileFilter::Kind(kind)),
+        )),
+        (None, Some(kind)) => Some(t::FileFilter::Kind(kind)),
+        (rest, None) => rest,
+    }
+}
+
+const SORTS: &[(t::FileSort, &str)] = &[
+    (t::FileSort::Updated, "Updated"),
+    (t::FileSort::Created, "Created"),
+    (t::FileSort::Type, "Type"),
+    (t::FileSort::Size, "Size"),
+    (t::FileSort::Length, "Length"),
+    (t::FileSort::Title, "Title"),
+    (t::FileSort::Random, "Random"),
+];
+
+fn sort_label(sort: t::FileSort) -> &'static str {
+    SORTS
+        .iter()
+        .find(|(s, _)| *s == sort)
+        .map(|(_, label)| *label)
+        .unwrap_or("")
+}
+
+/// Append the first field that is not sorted on yet, if any.
+fn sort_add(mut items: Vec<t::FileSortItem>) -> Vec<t::FileSortItem> {
+    let unused = SORTS
+        .iter()
+        .map(|(sort, _)| *sort)
+        .find(|sort| !items.iter().any(|item| item.sort == *sort));
+    if let Some(sort) = unused {
+        items.push(t::FileSortItem { sort, ascending: None });
+    }
+    items
+}
+
+/// Change the field at `index`, using the default direction of the new field.
+///
+/// Sorting on a field twice has no effect, so other items with the same field
+/// are dropped.
+fn sort_field_set(items: Vec<t::FileSortItem>, index: usize, sort: t::FileSort) -> Vec<t::FileSortItem> {
+    items
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, item)| {
+            if i == index {
+                Some(t::FileSortItem { sort, ascending: None })
+            } else if item.sort == sort {
+                None
+            } else {
+                Some(item)
+            }
+        })
+        .collect()
+}
+
+fn sort_direction_toggle(mut items: Vec<t::FileSortItem>, index: usize) -> Vec<t::FileSortItem> {
+    if let Some(item) = items.get_mut(index) {
+        item.ascending = Some(!item.is_ascending());
+    }
+    items
+}
+
+/// Remove the item at `index`. The last remaining item is kept.
+fn sort_remove(mut items: Vec<t::FileSortItem>, index: usize) -> Vec<t::FileSortItem> {
+    if items.len() > 1 && index < items.len() {
+        items.remove(index);
+    }
+    items
+}
+
+/// Encode a query as url query parameters, e.g. `tag=beach&sort=size:desc`.
+///
+/// Tag and kind filters built by the UI get their own parameters, other
+/// filters are sent as JSON in `filter`.
+pub fn query_to_params(q: &t::FileQuery) -> String {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+
+    let kind = filter_kind(q.filter.as_ref());
+    match filter_kind_set(q.filter.clone(), None) {
+        None => {}
+        Some(t::FileFilter::Tag(tag)) => {
+            params.append_pair("tag", &tag);
+        }
+        Some(filter) => {
+            if let Ok(json) = serde_json::to_string(&filter) {
+                params.append_pair("filter", &json);
+            }
+        }
+    }
+    if let Some(kind) = kind {
+        params.append_pair("kind", kind.to_str());
+    }
+
+    let sort = q
+        .sort
+        .iter()
+        .map(|item| {
+            let name = format!("{:?}", item.sort).to_lowercase();
+            match item.ascending {
+                Some(true) => format!("{}:asc", name),
+                Some(false) => format!("{}:desc", name),
+                None => name,
+            }
+        })
+        .collect::<Vec<_>>();
+    if !sort.is_empty() {
+        params.append_pair("sort", &sort.join(","));
+    }
+    if q.page > 1 {
+        params.append_pair("page", &q.page.to_string());
+    }
+    if q.page_size != t::FileQuery::default().page_size {
+        params.append_pair("page_size", &q.page_size.to_string());
+    }
+    params.finish()
+}
+
+fn sort_item_from_param(value: &str) -> Option<t::FileSortItem> {
+    let mut parts = value.splitn(2, ':');
+    let name = parts.next()?;
+    let sort = SORTS
+        .iter()
+        .map(|(sort, _)| *sort)
+        .find(|sort| format!("{:?}", sort).to_lowercase() == name)?;
+    let ascending = match parts.next() {
+        Some("asc") => Some(true),
+        Some("desc") => Some(false),
+        _ => None,
+    };
+    Some(t::FileSortItem { sort, ascending })
+}
+
+/// Apply url query parameters built by `query_to_params` to a query.
+///
+/// Invalid values are ignored.
+pub fn query_from_params(params: &str, mut q: t::FileQuery) -> t::FileQuery {
+    let mut filter = None;
+    let mut kind = None;
+    for (key, value) in url::form_urlencoded::parse(params.trim_start_matches('?').as_bytes()) {
+        match key.as_ref() {
+            "tag" => filter = Some(t::FileFilter::Tag(value.to_string())),
+            "filter" => filter = serde_json::from_str(&value).ok().or(filter),
+            "kind" => kind = Some(t::FileKind::from_str(&value)),
+            "sort" => {
+                let sort = value
+                    .split(',')
+                    .filter_map(sort_item_from_param)
+                    .collect::<Vec<_>>();
+                if !sort.is_empty() {
+                    q.sort = sort;
+                }
+            }
+            "page" => q.page = value.parse().unwrap_or(q.page).max(1),
+            "page_size" => {
+                q.page_size = value
+                    .parse()
+                    .unwrap_or(q.page_size)
+                    .min(t::FileQuery::MAX_PAGE_SIZE)
+                    .max(1)
+            }
+            _ => {}
+        }
+    }
+    if filter.is_some() || kind.is_some() {
+        q.filter = filter_kind_set(filter, kind);
+    }
+    q.cursor = None;
+    q
+}
+
+const PAGE_SIZE_KEY: &str = "mediavault.page_size";
+
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
+
+fn page_size_load() -> Option<u32> {
+    local_storage()?.get_item(PAGE_SIZE_KEY).ok()??.parse().ok()
+}
+
+fn page_size_store(page_size: u32) {
+    if let Some(storage) = local_storage() {
+        if storage.set_item(PAGE_SIZE_KEY, &page_size.to_string()).is_err() {
+            error!("Could not persist page size");
+        }
+    }
+}
+
+/// The query to start with, using the last selected page size.
+pub fn initial_query(mut q: t::FileQuery) -> t::FileQuery {
+    if let Some(page_size) = page_size_load() {
+        q.page_size = page_size.min(t::FileQuery::MAX_PAGE_SIZE).max(1);
+    }
+    q
+}
+
+fn view_page_size(q: &t::FileQuery) -> Elem<Message> {
+    let options = PAGE_SIZES
+        .iter()
+        .filter(|size| **size <= t::FileQuery::MAX_PAGE_SIZE)
+        .map(|size| {
+            let option = h::option()
+                .attr("value", size.to_string())
+                .push(size.to_string());
+            if *size == q.page_size {
+                option.attr("selected", "selected")
+            } else {
+                option
+            }
+        });
+
+    h::div()
+        .class("m-Files-PageSize")
+        .push(h::label().push("Per page"))
+        .push(
+            h::select()
+                .append(options)
+                .on_input(|value| Message::PageSize(value.parse().unwrap_or(30))),
+        )
+}
+
+fn view_sort_item(index: usize, item: &t::FileSortItem, removable: bool) -> Elem<Message> {
+    let options = SORTS.iter().map(|(sort, label)| {
+        let option = h::option()
+            .attr("value", format!("{:?}", sort))
+            .push(*label);
+        if *sort == item.sort {
+            option.attr("selected", "selected")
+        } else {
+            option
+        }
+    });
+    let direction = if item.is_ascending() { "Asc" } else { "Desc" };
+
+    let mut row = h::div()
+        .class("m-Files-SortItem")
+        .push(
+            h::select()
+                .append(options)
+                .on_input(move |value| {
+                    let sort = SORTS
+                        .iter()
+                        .map(|(sort, _)| *sort)
+                        .find(|sort| format!("{:?}", sort) == value)
+                        .unwrap_or(t::FileSort::Updated);
+                    Message::SortField(index, sort)
+                }),
+        )
+        .push(
+            h::button()
+                .attr("title", format!("Toggle direction of {}", sort_label(item.sort)))
+                .push(direction)
+                .on("click", move |_| Message::SortDirection(index)),
+        );
+    if removable {
+        row = row.push(
+            h::button()
+                .class("m-Files-SortRemove")
+                .push("x")
+                .on("click", move |_| Message::SortRemove(index)),
+        );
+    }
+    row
+}
+
+/// Sort items in the order they are applied: later items break ties of
+/// earlier ones.
+fn view_sort(q: &t::FileQuery) -> Elem<Message> {
+    let removable = q.sort.len() > 1;
+    let sort = h::div()
+        .class("m-Files-Sort")
+        .push(h::label().push("Sort by"))
+        .append(
+            q.sort
+                .iter()
+                .enumerate()
+                .map(|(index, item)| view_sort_item(index, item, removable)),
+        );
+    if q.sort.len() < SORTS.len() {
+        sort.push(h::button().push("Then by...").on("click", |_| Message::SortAdd))
+    } else {
+        sort
+    }
+}
+
 fn view_pager(f: &Files) -> Elem<Message> {
     let mut p = h::div().class("m-Files-pager");
 
@@ -43,6 +391,14 @@ fn view_pager(f: &Files) -> Elem<Message> {
             );
         }
 
+        if data.total_pages > 1 {
+            p = p.push(
+                h::span()
+                    .class("m-Files-pager-info")
+                    .push(format!("{} / {}", page.max(1), data.total_pages)),
+            );
+        }
+
         if data.has_more() {
             let mut q = f.query.clone();
             q.page += 1;
@@ -59,30 +415,110 @@ fn view_pager(f: &Files) -> Elem<Message> {
     p
 }
 
-fn view_filter(q: &t::FileQuery) -> Elem<Message> {
+fn view_top_tags(tags: &[t::TagCount]) -> Elem<Message> {
+    h::div()
+        .class("m-Files-TopTags")
+        .append(tags.iter().map(|tag| {
+            let name = tag.tag.clone();
+            h::span()
+                .class("m-Files-TagChip")
+                .attr("title", format!("{} files", tag.count))
+                .push(&tag.tag)
+                .on("click", move |_| Message::ShowTag(name.clone()))
+        }))
+}
+
+fn view_kinds(q: &t::FileQuery, stats: Option<&t::Stats>) -> Elem<Message> {
+    let active = filter_kind(q.filter.as_ref());
+    h::div()
+        .class("m-Files-Kinds")
+        .append(KINDS.iter().map(|kind| {
+            let kind = *kind;
+            let count = stats
+                .and_then(|s| s.kinds.iter().find(|k| k.kind == kind))
+                .map(|k| k.count)
+                .unwrap_or(0);
+            let label = match stats {
+                Some(_) => format!("{:?} ({})", kind, count),
+                None => format!("{:?}", kind),
+            };
+            let class = if active == Some(kind) {
+                "m-Files-Kind m-Files-Kind--active"
+            } else {
+                "m-Files-Kind"
+            };
+            h::button()
+                .class(class)
+                .push(label)
+                .on("click", move |_| Message::KindToggle(kind))
+        }))
+}
+
+fn view_filter(
+    q: &t::FileQuery,
+    top_tags: Option<&Vec<t::TagCount>>,
+    stats: Option<&t::Stats>,
+) -> Elem<Message> {
     let tags = h::div().push(h::label().push("Tags")).push(
         h::input()
             .attr("type", "text")
             .attr("placeholder", "Tags..."),
     );
 
-    h::div().class("m-Files-Filter").push(tags)
+    let mut filter = h::div()
+        .class("m-Files-Filter")
+        .push(tags);
+    if let Some(top_tags) = top_tags {
+        filter = filter.push(view_top_tags(top_tags));
+    }
+    filter
+        .push(view_kinds(q, stats))
+        .push(view_sort(q))
+        .push(view_page_size(q))
+}
+
+/// What the files area shows.
+#[derive(PartialEq, Eq, Debug)]
+enum Content {
+    Loading,
+    /// No matching files. With a filter, it can be cleared.
+    Empty { filtered: bool },
+    Files,
+}
+
+impl Content {
+    fn new(p: Option<&t::FilesPage>, q: &t::FileQuery) -> Self {
+        match p {
+            None => Content::Loading,
+            Some(p) if p.items.is_empty() => Content::Empty { filtered: q.filter.is_some() },
+            Some(_) => Content::Files,
+        }
+    }
 }
 
-fn view_files(p: Option<&t::FilesPage>) -> Elem<Message> {
-    match p {
-        Some(p) => h::div()
+fn view_empty(filtered: bool) -> Elem<Message> {
+    let empty = h::div().class("m-Files-Empty");
+    if filtered {
+        empty
+            .push(h::p().push("No files match this filter"))
+            .push(h::button().push("Clear filter").on("click", |_| Message::ClearFilter))
+    } else {
+        empty.push(h::p().push("No files yet"))
+    }
+}
+
+fn view_files(p: Option<&t::FilesPage>, q: &t::FileQuery) -> Elem<Message> {
+    match (Content::new(p, q), p) {
+        (Content::Empty { filtered }, _) => view_empty(filtered),
+        (Content::Files, Some(p)) => h::div()
             .class("m-Files-Viewer")
             .append(p.items.iter().map(|f| {
 
                 let content = match f.info.kind {
-                    t::FileKind::Image => {
+                    t::FileKind::Image | t::FileKind::Video => {
                         h::img()
                             .class("m-Files-Image")
-                            .attr("src", format!("/media/{}", f.path))
-                    }
-                    t::FileKind::Video => {
-                        h::span().push(&f.path)
+                            .attr("src", format!("/media/thumb/{}?size={}", f.info.hash, THUMB_SIZE))
                     }
                     t::FileKind::Audio => {
                         h::span().push(&f.path)
@@ -98,7 +534,13 @@ fn view_files(p: Option<&t::FilesPage>) -> Elem<Message> {
                     .push(content)
                     .on("click", move |_| Message::ShowFile(file_clone.clone()))
             })),
-        None => h::div().push("loading"),
+        _ => h::div().push("loading"),
+    }
+}
+
+impl Files {
+    pub fn query(&self) -> &t::FileQuery {
+        &self.query
     }
 }
 
@@ -109,40 +551,213 @@ impl draco::App for Files {
         use self::Message::*;
         match message {
             Query(q) => {
+                if self.top_tags.is_none() {
+                    // Avoid loading twice while the request is pending.
+                    self.top_tags = Some(Vec::new());
+                    mailbox.spawn(crate::api::tags_top(TOP_TAGS_LIMIT), |res| match res {
+                        Ok(tags) => Message::TopTags(tags),
+                        Err(e) => {
+                            error!("Could not load top tags: {}", e);
+                            Message::TopTags(Vec::new())
+                        }
+                    });
+                }
+                if self.stats.is_none() {
+                    mailbox.spawn(crate::api::stats(), |res| match res {
+                        Ok(stats) => Message::Stats(stats),
+                        Err(e) => {
+                            error!("Could not load stats: {}", e);
+                            Message::Stats(t::Stats { total: 0, kinds: Vec::new() })
+                        }
+                    });
+                }
+
                 self.query = q.clone();
+                self.generation = self.generation.wrapping_add(1);
+                let generation = self.generation;
 
-                mailbox.spawn(crate::api::files(q), |res| match res {
-                    Ok(d) => Message::Data(d),
+                mailbox.spawn(crate::api::files(q), move |res| match res {
+                    Ok(d) => Message::Data(generation, d),
                     Err(e) => {
                         log!("fetch error: {}", e);
-                        Message::Error(e)
+                        Message::Error(generation, e)
                     }
                 });
             }
-            Data(data) => {
-                self.data = Some(data);
+            Data(generation, data) => {
+                if generation == self.generation {
+                    self.data = Some(data);
+                    self.error = None;
+                }
             }
-            Error(e) => {
+            Error(generation, e) => {
                 // TODO: show error msg.
-                self.error = Some(e);
+                if generation == self.generation {
+                    self.error = Some(e);
+                }
             }
             ShowFile(f) => {
                 super::Route::goto(&super::Route::File { hash: f.info.hash });
             }
+            TopTags(tags) => {
+                self.top_tags = Some(tags);
+            }
+            ShowTag(tag) => {
+                super::Route::goto(&super::Route::Tag { tag });
+            }
+            Stats(stats) => {
+                self.stats = Some(stats);
+            }
+            ClearFilter => {
+                let mut q = self.query.clone();
+                q.filter = None;
+                q.page = 1;
+                q.cursor = None;
+                self.update(mailbox, Query(q));
+            }
+            KindToggle(kind) => {
+                let mut q = self.query.clone();
+                let kind = if filter_kind(q.filter.as_ref()) == Some(kind) {
+                    None
+                } else {
+                    Some(kind)
+                };
+                q.filter = filter_kind_set(q.filter.take(), kind);
+                q.page = 1;
+                q.cursor = None;
+                self.update(mailbox, Query(q));
+            }
+            SortAdd | SortField(..) | SortDirection(_) | SortRemove(_) => {
+                let mut q = self.query.clone();
+                let sort = std::mem::replace(&mut q.sort, Vec::new());
+                q.sort = match message {
+                    SortAdd => sort_add(sort),
+                    SortField(index, field) => sort_field_set(sort, index, field),
+                    SortDirection(index) => sort_direction_toggle(sort, index),
+                    SortRemove(index) => sort_remove(sort, index),
+                    _ => sort,
+                };
+                // Cursors are tied to the first sort item.
+                q.page = 1;
+                q.cursor = None;
+                self.update(mailbox, Query(q));
+            }
+            PageSize(page_size) => {
+                page_size_store(page_size);
+
+                // Page offsets change with the size, so start over.
+                let mut q = self.query.clone();
+                q.page_size = page_size;
+                q.page = 1;
+                q.cursor = None;
+                self.update(mailbox, Query(q));
+            }
         }
     }
 
     fn render(&self) -> draco::Node<Self::Message> {
         h::div()
             .class("m-Files")
-            .push(view_filter(&self.query))
+            .push(view_filter(&self.query, self.top_tags.as_ref(), self.stats.as_ref()))
             .push(
                 h::div()
                     .class("m-Files-Browser")
-                    .push(view_files(self.data.as_ref()))
+                    .push(view_files(self.data.as_ref(), &self.query))
                     .push(view_pager(self)),
             )
             //.push(h::button().push("Reset").on("click", |_| Message::Reset))
             .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(items: Vec<t::File>) -> t::FilesPage {
+        t::FilesPage {
+            total: items.len() as u32,
+            items,
+            page: 1,
+            page_size: 30,
+            total_pages: 1,
+            next_cursor: None,
+            scores: None,
+            total_size: None,
+        }
+    }
+
+    fn file() -> t::File {
+        t::File {
+            path: "a.jpg".to_string(),
+            info: t::FileInfo {
+                hash: "a".to_string(),
+                size: 1,
+                mime: Some("image/jpeg".to_string()),
+                kind: t::FileKind::Image,
+                media: None,
+                created_at: None,
+                updated_at: None,
+                subtitles: Vec::new(),
+                location: None,
+            },
+            meta: t::FileMeta::default(),
+        }
+    }
+
+    #[test]
+    fn content_states() {
+        let q = t::FileQuery::default();
+        let filtered = t::FileQuery {
+            filter: Some(t::FileFilter::Tag("cat".to_string())),
+            ..t::FileQuery::default()
+        };
+
+        assert_eq!(Content::new(None, &q), Content::Loading);
+        assert_eq!(Content::new(Some(&page(Vec::new())), &q), Content::Empty { filtered: false });
+        assert_eq!(Content::new(Some(&page(Vec::new())), &filtered), Content::Empty { filtered: true });
+        assert_eq!(Content::new(Some(&page(vec![file()])), &filtered), Content::Files);
+    }
+
+    #[test]
+    fn query_params_round_trip() {
+        let q = t::FileQuery {
+            filter: Some(t::FileFilter::And(
+                Box::new(t::FileFilter::Tag("beach".to_string())),
+                Box::new(t::FileFilter::Kind(t::FileKind::Image)),
+            )),
+            sort: vec![t::FileSortItem { sort: t::FileSort::Size, ascending: Some(false) }],
+            page: 2,
+            ..t::FileQuery::default()
+        };
+        let params = query_to_params(&q);
+        assert_eq!(params, "tag=beach&kind=image&sort=size%3Adesc&page=2");
+
+        let decoded = query_from_params(&params, t::FileQuery::default());
+        assert_eq!(query_to_params(&decoded), params);
+        assert_eq!(filter_kind(decoded.filter.as_ref()), Some(t::FileKind::Image));
+        assert_eq!(decoded.sort, q.sort);
+        assert_eq!(decoded.page, 2);
+
+        // Other filters are encoded as JSON.
+        let q = t::FileQuery {
+            filter: Some(t::FileFilter::Untagged),
+            ..t::FileQuery::default()
+        };
+        let decoded = query_from_params(&query_to_params(&q), t::FileQuery::default());
+        match decoded.filter {
+            Some(t::FileFilter::Untagged) => {}
+            other => panic!("expected the untagged filter, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn query_params_ignore_invalid_values() {
+        let defaults = t::FileQuery::default();
+        let q = query_from_params("?page=x&page_size=100000&sort=bogus&kind=image", defaults.clone());
+        assert_eq!(q.page, defaults.page);
+        assert_eq!(q.page_size, t::FileQuery::MAX_PAGE_SIZE);
+        assert_eq!(q.sort, defaults.sort);
+        assert_eq!(filter_kind(q.filter.as_ref()), Some(t::FileKind::Image));
+    }
+}
@@ -1,23 +1,52 @@
+use mediavault_common::types as t;
+use wasm_bindgen::JsCast;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+pub mod confirm;
 pub mod file;
 pub mod files;
+pub mod tags;
 
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub enum Route {
-    Home,
+    /// Files, with the query encoded as url query parameters.
+    Home {
+        params: String,
+    },
     NotFound,
     File {
         hash: String,
     },
+    Tags,
+    Tag {
+        tag: String,
+    },
+}
+
+/// Query parameters of the current location, without the leading `?`.
+pub fn location_params() -> String {
+    web_sys::window()
+        .and_then(|w| w.location().search().ok())
+        .map(|search| search.trim_start_matches('?').to_string())
+        .unwrap_or_default()
 }
 
 impl Route {
+    pub fn home() -> Route {
+        Route::Home { params: String::new() }
+    }
+
     fn from_url(url: &draco::router::Url) -> Route {
         use draco::router::{parse, param};
         parse(url)
-            .alt((), |()| Route::Home)
+            .alt((), |()| Route::Home { params: location_params() })
             .alt(("file", param()), |((), hash)| Route::File{
                 hash,
             })
+            .alt("tags", |()| Route::Tags)
+            .alt(("tag", param()), |((), tag): ((), String)| Route::Tag{
+                tag: percent_decode(tag.as_bytes()).decode_utf8_lossy().to_string(),
+            })
             .value()
             .unwrap_or(Route::NotFound)
     }
@@ -25,9 +54,12 @@ impl Route {
     fn to_path(&self) -> String {
         use self::Route::*;
         match self {
-            Home => "/".to_string(),
+            Home { params } if params.is_empty() => "/".to_string(),
+            Home { params } => format!("/?{}", params),
             NotFound => "/not-found".to_string(),
             File{ hash } => format!("/file/{}", hash),
+            Tags => "/tags".to_string(),
+            Tag{ tag } => format!("/tag/{}", utf8_percent_encode(tag, PATH_SEGMENT_ENCODE_SET)),
         }
     }
 
@@ -40,6 +72,23 @@ impl Route {
 pub enum View {
     Files(files::Files),
     File(file::FileContainer),
+    Tags(tags::Tags),
+}
+
+/// Whether the input looks like a file hash: 32 (md5) or 64 (sha256) hex
+/// digits.
+fn is_hash(value: &str) -> bool {
+    (value.len() == 32 || value.len() == 64) && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Route to open for the input of the "go to file" box, if it is a hash.
+fn goto_route(input: &str) -> Option<Route> {
+    let hash = input.trim().to_lowercase();
+    if is_hash(&hash) {
+        Some(Route::File { hash })
+    } else {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -48,9 +97,16 @@ pub enum Message {
     UrlChange(draco::router::Url),
 
     Show(View),
+    Navigate(Route),
+
+    /// Input of the "go to file" box.
+    GotoInput(String),
+    /// Key pressed in the "go to file" box.
+    GotoKey(String),
 
     Files(files::Message),
     File(file::ContainerMessage),
+    Tags(tags::Message),
 }
 
 #[derive(Debug)]
@@ -60,6 +116,9 @@ pub struct Root {
     history_subscription: Option<draco::Unsubscribe>,
     current_route: Route,
 
+    goto_input: String,
+    goto_invalid: bool,
+
     // Caches.
     cache_files: Option<files::Files>,
 }
@@ -69,7 +128,9 @@ impl Default for Root {
         Root {
             view: View::Files(files::Files::default()),
             history_subscription: None,
-            current_route: Route::Home,
+            current_route: Route::home(),
+            goto_input: String::new(),
+            goto_invalid: false,
             cache_files: None,
         }
     }
@@ -97,7 +158,17 @@ impl draco::App for Root {
                     self.current_route = route.clone();
 
                     let view = match &route {
-                        &Route::Home | &Route::NotFound => {
+                        &Route::Home { ref params } => {
+                            let files = self.cache_files.take().unwrap_or(files::Files::default());
+                            // Skip the query if the view already shows it, e.g. when
+                            // coming back from a file.
+                            if !params.is_empty() && &files::query_to_params(files.query()) != params {
+                                let q = files::query_from_params(params, files::initial_query(t::FileQuery::default()));
+                                mailbox.send(Message::Files(files::Message::Query(q)));
+                            }
+                            View::Files(files)
+                        },
+                        &Route::NotFound => {
                             View::Files(self.cache_files.take().unwrap_or(files::Files::default()))
                         },
                         &Route::File { ref hash } => {
@@ -105,6 +176,16 @@ impl draco::App for Root {
                             mailbox.send(msg);
                             View::File(file::FileContainer::default())
                         },
+                        &Route::Tags => {
+                            mailbox.send(Message::Tags(tags::Message::Load));
+                            View::Tags(tags::Tags::default())
+                        },
+                        &Route::Tag { ref tag } => {
+                            let mut q = t::FileQuery::default();
+                            q.filter = Some(t::FileFilter::Tag(tag.to_string()));
+                            mailbox.send(Message::Files(files::Message::Query(q)));
+                            View::Files(files::Files::default())
+                        },
                     };
                     self.update(mailbox, Message::Show(view));
                 }
@@ -119,9 +200,41 @@ impl draco::App for Root {
                     _ => {},
                 }
             },
+            Navigate(route) => {
+                Route::goto(&route);
+            },
+            GotoInput(value) => {
+                self.goto_input = value;
+                self.goto_invalid = false;
+            },
+            GotoKey(key) => {
+                if key == "Enter" {
+                    match goto_route(&self.goto_input) {
+                        Some(route) => {
+                            self.goto_input = String::new();
+                            self.goto_invalid = false;
+                            Route::goto(&route);
+                        }
+                        None => {
+                            self.goto_invalid = true;
+                        }
+                    }
+                }
+            },
             Files(msg) => match &mut self.view {
                 View::Files(ref mut v) => {
                     v.update(&mailbox.clone().map(|m| Message::Files(m)), msg);
+
+                    // Keep the url in sync with the query, so the view can be
+                    // bookmarked. The route is updated first, so the resulting
+                    // url change doesn't trigger another query.
+                    if let Route::Home { .. } = self.current_route {
+                        let route = Route::Home { params: files::query_to_params(v.query()) };
+                        if route != self.current_route {
+                            self.current_route = route.clone();
+                            Route::goto(&route);
+                        }
+                    }
                 }
                 _ => {}
             },
@@ -131,6 +244,12 @@ impl draco::App for Root {
                 }
                 _ => {}
             },
+            Tags(msg) => match &mut self.view {
+                View::Tags(ref mut v) => {
+                    v.update(&mailbox.clone().map(|m| Message::Tags(m)), msg);
+                }
+                _ => {}
+            },
         }
     }
 
@@ -141,12 +260,52 @@ impl draco::App for Root {
         let view = match &self.view {
             Files(v) => v.render().map(Message::Files),
             File(v) => v.render().map(Message::File),
+            Tags(v) => v.render().map(Message::Tags),
         };
 
+        let nav = h::div()
+            .class("m-Root-Nav")
+            .push(h::span().push("Files").on("click", |_| Message::Navigate(Route::home())))
+            .push(h::span().push("Tags").on("click", |_| Message::Navigate(Route::Tags)))
+            .push(
+                h::input()
+                    .class(if self.goto_invalid { "m-Root-Goto m-Root-Goto--invalid" } else { "m-Root-Goto" })
+                    .attr("placeholder", "Go to hash...")
+                    .attr("value", self.goto_input.clone())
+                    .on_input(Message::GotoInput)
+                    .on("keydown", |event| {
+                        let key = event
+                            .dyn_ref::<web_sys::KeyboardEvent>()
+                            .map(|e| e.key())
+                            .unwrap_or_default();
+                        Message::GotoKey(key)
+                    }),
+            );
+
         h::div()
             .class("m-Root")
+            .push(nav)
             .push(view)
             //.push(h::button().push("Reset").on("click", |_| Message::Reset))
             .into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goto_route_accepts_hashes() {
+        let md5 = "d41d8cd98f00b204e9800998ecf8427e";
+        let file = |hash: &str| Some(Route::File { hash: hash.to_string() });
+        assert_eq!(goto_route(md5), file(md5));
+        assert_eq!(goto_route(&format!("  {}\n", md5.to_uppercase())), file(md5));
+        assert_eq!(goto_route(&"a".repeat(64)), file(&"a".repeat(64)));
+
+        assert_eq!(goto_route(""), None);
+        assert_eq!(goto_route(&md5[1..]), None);
+        assert_eq!(goto_route(&"g".repeat(32)), None);
+        assert_eq!(goto_route(&"a".repeat(40)), None);
+    }
+}
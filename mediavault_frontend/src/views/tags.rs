@@ -0,0 +1,95 @@
+use draco::{element::NonKeyedElement as Elem, html as h};
+use mediavault_common::types as t;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Load,
+    Data(Vec<t::TagCount>),
+    Error(String),
+    ShowTag(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    tags: Option<Vec<t::TagCount>>,
+    error: Option<String>,
+}
+
+const MIN_FONT_SIZE: f64 = 0.8;
+const MAX_FONT_SIZE: f64 = 2.4;
+
+/// Font size in `em`, scaled linearly between the least and most used tag.
+fn font_size(count: u32, min: u32, max: u32) -> f64 {
+    if max <= min {
+        return (MIN_FONT_SIZE + MAX_FONT_SIZE) / 2.0;
+    }
+    let ratio = (count - min) as f64 / (max - min) as f64;
+    MIN_FONT_SIZE + ratio * (MAX_FONT_SIZE - MIN_FONT_SIZE)
+}
+
+fn view_cloud(tags: &[t::TagCount]) -> Elem<Message> {
+    if tags.is_empty() {
+        return h::div().class("m-Tags-Empty").push("No tags yet");
+    }
+
+    let min = tags.iter().map(|t| t.count).min().unwrap_or(0);
+    let max = tags.iter().map(|t| t.count).max().unwrap_or(0);
+
+    // Alphabetical order makes the cloud easier to scan.
+    let mut sorted = tags.to_vec();
+    sorted.sort_by(|a, b| a.tag.cmp(&b.tag));
+
+    h::div()
+        .class("m-Tags-Cloud")
+        .append(sorted.into_iter().map(|tag| {
+            let size = font_size(tag.count, min, max);
+            let name = tag.tag.clone();
+            h::span()
+                .class("m-Tags-Tag")
+                .attr("style", format!("font-size: {:.2}em", size))
+                .attr("title", format!("{} files", tag.count))
+                .push(tag.tag)
+                .on("click", move |_| Message::ShowTag(name.clone()))
+        }))
+}
+
+impl draco::App for Tags {
+    type Message = Message;
+
+    fn update(&mut self, mailbox: &draco::Mailbox<Self::Message>, message: Self::Message) {
+        use self::Message::*;
+        match message {
+            Load => {
+                mailbox.spawn(crate::api::tags(), |res| match res {
+                    Ok(tags) => Message::Data(tags),
+                    Err(e) => {
+                        log!("fetch error: {}", e);
+                        Message::Error(e)
+                    }
+                });
+            }
+            Data(tags) => {
+                self.tags = Some(tags);
+            }
+            Error(e) => {
+                self.error = Some(e);
+            }
+            ShowTag(tag) => {
+                super::Route::goto(&super::Route::Tag { tag });
+            }
+        }
+    }
+
+    fn render(&self) -> draco::Node<Self::Message> {
+        let content = match (self.error.as_ref(), self.tags.as_ref()) {
+            (Some(e), _) => h::div().push(format!("Error: {}", e)),
+            (None, Some(tags)) => view_cloud(tags),
+            (None, None) => h::div().push("loading"),
+        };
+
+        h::div()
+            .class("m-Tags")
+            .push(content)
+            .into()
+    }
+}